@@ -1 +1,3 @@
 curl -X POST -H 'Accept: application/json' -F "load_profile_data=@testdata/load_profile_data.zip" -F model_id=1 -F simulation_type="Powerflow" -F allowed_file_types=application/zip -w "\nHTTP CODE: %{http_code}\n" -i http://localhost:8000/simulation
+curl -X POST -H 'Content-Type: application/zip' --data-binary "@testdata/load_profile_data.zip" -w "\nHTTP CODE: %{http_code}\n" -i "http://localhost:8000/load_profile/upload?name=load_profile_data"
+curl -X POST -H 'Content-Type: application/json' -d '{ "simulation_type": "Powerflow", "model_id": "1", "load_profile": 1 }' -w "\nHTTP CODE: %{http_code}\n" -i http://localhost:8000/simulation
@@ -1,25 +1,62 @@
 #[cfg(not(test))]
 use lapin::{
-    options::*, publisher_confirm::Confirmation, types::FieldTable, BasicProperties, Connection,
+    options::*, types::{AMQPValue, FieldTable}, BasicProperties, Connection,
     ConnectionProperties, Result,
 };
 #[cfg(not(test))]
 use log::info;
+#[cfg(not(test))]
+use crate::logging;
+#[cfg(not(test))]
+use crate::telemetry;
 #[cfg(test)]
 use lapin::{
     Result,
 };
 use crate::routes::{Simulation, SimulationType, DomainType, SolverType};
-use rocket::serde::json::{json, Json};
+use crate::contingency::{Contingency, ContingencyJob};
+use crate::events::Event;
+use crate::realtime;
+use crate::metrics;
+use crate::realtime::RealTimeConfig;
+use crate::simulation_time::SimulationTime;
+use rocket::serde::json::{json, Value};
 use serde::{ Serialize, Deserialize };
+use std::collections::BTreeMap;
 use schemars::JsonSchema;
 #[cfg(test)]
-pub async fn publish(bytes: Vec<u8>) -> Result<()> {
-    println!("AMQPSimulation: {:?}", bytes);
+pub async fn publish(messages: Vec<Vec<u8>>) -> Result<()> {
+    for bytes in messages {
+        println!("AMQPSimulation: {:?}", bytes);
+    }
+    Ok(())
+}
+#[cfg(test)]
+pub async fn check_connection() -> Result<()> {
     Ok(())
 }
 #[cfg(not(test))]
-pub async fn publish(bytes: Vec<u8>) -> Result<()> {
+#[doc = "Check that a connection can be made to the AMQP broker"]
+pub async fn check_connection() -> Result<()> {
+    let addr = std::env::var("AMQP_ADDR").unwrap_or_else(|_| "amqp://rabbitmq:5672/%2f".into());
+    let conn = Connection::connect(&addr, ConnectionProperties::default().with_default_executor(1)).await?;
+    conn.close(0, "").await
+}
+#[cfg(not(test))]
+#[doc = "Headers of a job message, carrying the id and the trace of the request that published it"]
+fn message_headers() -> FieldTable {
+    let mut headers = FieldTable::default();
+    if let Some(request_id) = logging::request_id() {
+        headers.insert("x-request-id".into(), AMQPValue::LongString(request_id.into()));
+    }
+    for (name, value) in telemetry::trace_headers() {
+        headers.insert(name.into(), AMQPValue::LongString(value.into()));
+    }
+    headers
+}
+#[cfg(not(test))]
+#[doc = "Publish messages to the worker queue in one transaction, so that either all of them are queued or none is"]
+pub async fn publish(messages: Vec<Vec<u8>>) -> Result<()> {
     let addr = std::env::var("AMQP_ADDR").unwrap_or_else(|_| "amqp://rabbitmq:5672/%2f".into());
 
     let conn = Connection::connect(
@@ -41,87 +78,133 @@ pub async fn publish(bytes: Vec<u8>) -> Result<()> {
 
     info!("Declared queue {:?}", queue);
 
-    let confirm = channel_a
-        .basic_publish(
-            "",
-            "dpsim-worker-queue",
-            BasicPublishOptions::default(),
-            bytes,
-            BasicProperties::default(),
-        )
-        .await?
-        .await?;
-    assert_eq!(confirm, Confirmation::NotRequested);
-    Ok(())
+    channel_a.tx_select().await?;
+    let published = async {
+        for bytes in messages {
+            channel_a
+                .basic_publish(
+                    "",
+                    "dpsim-worker-queue",
+                    BasicPublishOptions::default(),
+                    bytes,
+                    BasicProperties::default().with_headers(message_headers()),
+                )
+                .await?;
+        }
+        Ok::<(), lapin::Error>(())
+    }.await;
+    match published {
+        Ok(()) => channel_a.tx_commit().await,
+        Err(e) => {
+            info!("Rolling back the jobs published so far: {}", e);
+            if let Err(rollback) = channel_a.tx_rollback().await {
+                info!("Could not roll back: {}", rollback);
+            }
+            Err(e)
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[doc = "Struct for encapsulation Simulation details"]
 pub struct AMQPSimulation {
     error: String,
-    load_profile_url:  String,
-    model_url:         String,
+    load_profile_url:  Option<String>,
+    model_urls:        Vec<String>,
     simulation_id:     u64,
     simulation_type:   SimulationType,
     results_file:      String,
     domain:            DomainType,
     solver:            SolverType,
-    timestep:          u64,
-    finaltime:         u64
+    timestep:          SimulationTime,
+    finaltime:         SimulationTime,
+    contingency:       Option<Contingency>,
+    all_n_minus_1:     bool,
+    events:            Vec<Event>,
+    realtime:          Option<RealTimeConfig>,
+    worker_parameters: BTreeMap<String, String>
 }
 
 impl AMQPSimulation {
-    pub fn from_simulation(sim: &Json<Simulation>, model_url: String, load_profile_url: String) -> AMQPSimulation {
+    pub fn from_simulation(sim: &Simulation, model_urls: Vec<String>, load_profile_url: Option<String>) -> AMQPSimulation {
         AMQPSimulation {
             error:            "".into(),
-            load_profile_url: load_profile_url,
-            model_url:        model_url,
+            load_profile_url,
+            model_urls,
             simulation_id:    sim.simulation_id,
             simulation_type:  sim.simulation_type,
             results_file:     sim.results_id.clone(),
             domain:           sim.domain,
             solver:           sim.solver,
             timestep:         sim.timestep,
-            finaltime:        sim.finaltime
+            finaltime:        sim.finaltime,
+            contingency:      None,
+            all_n_minus_1:    sim.all_n_minus_1,
+            events:           sim.events.clone(),
+            realtime:         sim.realtime.clone(),
+            worker_parameters: sim.worker_parameters.clone()
         }
     }
-}
-
-pub async fn request_simulation(_simulation: &AMQPSimulation) -> Result<()> {
-    if std::env::var("RUST_LOG").is_err() {
-        std::env::set_var("RUST_LOG", "info");
-    }
-
-    let mut load_profile = json!("");
 
-    if _simulation.load_profile_url != "" {
-        load_profile = json!({
-            "type" : "url-list",
-            "url" : [ _simulation.load_profile_url ]
-        });
+    #[doc = "The job for a single contingency of an Outage simulation"]
+    pub fn for_contingency(&self, job: &ContingencyJob) -> AMQPSimulation {
+        AMQPSimulation {
+            results_file:     job.results_id.clone(),
+            contingency:      Some(job.contingency.clone()),
+            all_n_minus_1:    false,
+            ..self.clone()
+        }
     }
+}
 
-    let message_as_jsonvalue = json!({
+#[doc = "The message sent to the worker for a job. It has no load_profile if the simulation has none"]
+pub fn worker_message(_simulation: &AMQPSimulation) -> Value {
+    let mut message_as_jsonvalue = json!({
       "model" : {
         "type" : "url-list",
-        "url" : [ _simulation.model_url ]
+        "url" : _simulation.model_urls
       },
-      "load_profile" : load_profile,
       "parameters": {
         "domain":          _simulation.domain,
         "solver":          _simulation.solver,
         "timestep":        _simulation.timestep,
         "finaltime":       _simulation.finaltime,
         "results_file":    _simulation.results_file,
+        "events":          _simulation.events,
         "executable": "SLEW_Shmem_CIGRE_MV_PowerFlow",
         "name": "SLEW_Shmem_CIGRE_MV_PowerFlow",
-        "timestep": 0.1,
-        "duration": 20
+        "duration":        _simulation.finaltime
       }
     });
-    let message = serde_json::to_vec(&message_as_jsonvalue).unwrap();
 
-    publish(message).await?;
+    if let SimulationType::Outage = _simulation.simulation_type {
+        message_as_jsonvalue["parameters"]["contingency"] = json!(_simulation.contingency);
+        message_as_jsonvalue["parameters"]["all_n_minus_1"] = json!(_simulation.all_n_minus_1);
+    }
+
+    if let Some(config) = &_simulation.realtime {
+        message_as_jsonvalue["parameters"]["realtime"] = realtime::to_worker_parameters(config);
+    }
+
+    if let Some(load_profile_url) = &_simulation.load_profile_url {
+        message_as_jsonvalue["load_profile"] = json!({
+            "type" : "url-list",
+            "url" : [ load_profile_url ]
+        });
+    }
+
+    for (key, value) in &_simulation.worker_parameters {
+        message_as_jsonvalue["parameters"][key] = json!(value);
+    }
+
+    message_as_jsonvalue
+}
+
+#[doc = "Send the worker messages of a simulation, as made by worker_message, to the worker queue, all or none of them"]
+pub async fn request_simulations(messages: &[Value]) -> Result<()> {
+    let messages = messages.iter().map(|message| serde_json::to_vec(message).unwrap()).collect();
+
+    metrics::observe_amqp_publish(publish(messages)).await?;
 
     Ok(())
 }
@@ -0,0 +1,194 @@
+//!
+//! # Audit log
+//!
+//! Every change to a simulation is recorded in an append-only audit log:
+//! what was done, to which simulation, by whom, from where and when,
+//! with the simulation record as it was before and after.
+//!
+//! | Operation    | Recorded by                                        |
+//! |--------------|----------------------------------------------------|
+//! | Create       | `POST /simulation`                                 |
+//! | Rerun        | `POST /simulation/<id>/rerun`, for the new simulation |
+//! | Patch        | `PATCH /simulation/<id>`                           |
+//! | StatusUpdate | `PUT /simulation/<id>/status`                      |
+//! | Cancel       | `PUT /simulation/<id>/status` to Cancelled         |
+//!
+//! A simulation that could not be started is recorded as created or rerun,
+//! with the record as it was marked Failed.
+//!
+//! The actor and the source IP can only be taken from headers set by a
+//! trusted, authenticating proxy in front of the API. Their names are
+//! configured with the environment variables:
+//!
+//! | Variable            | Header, e.g.       | Default without it          |
+//! |---------------------|--------------------|-----------------------------|
+//! | `AUDIT_USER_HEADER` | `X-Forwarded-User` | the actor is "anonymous"    |
+//! | `AUDIT_IP_HEADER`   | `X-Real-IP`        | the address of the client   |
+//!
+//! Without the variables, the headers are ignored, as any client could set
+//! them.
+//!
+//! `GET /audit` returns the entries, oldest first, optionally only those
+//! for one simulation, by one actor, or within a time range.
+//!
+
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{ Serialize, Deserialize };
+use schemars::JsonSchema;
+use rocket::request::{self, FromRequest, Request};
+use rocket_okapi::gen::OpenApiGenerator;
+use rocket_okapi::request::{OpenApiFromRequest, RequestHeaderInput};
+use okapi::openapi3::{Parameter, ParameterValue};
+use log::warn;
+use crate::db;
+use crate::logging::RequestId;
+use crate::routes::Simulation;
+use crate::status::SimulationStatus;
+
+const USER_HEADER_VARIABLE: &str = "AUDIT_USER_HEADER";
+const IP_HEADER_VARIABLE: &str = "AUDIT_IP_HEADER";
+const ANONYMOUS: &str = "anonymous";
+
+#[doc = "The kinds of change to a simulation that are audited"]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum AuditOperation {
+    Create,
+    Rerun,
+    Patch,
+    StatusUpdate,
+    Cancel
+}
+
+#[doc = "Who made a request, and from where"]
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditContext {
+    pub actor:      String,
+    pub source_ip:  Option<String>,
+    pub request_id: String
+}
+
+#[doc = "An entry in the audit log"]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct AuditEntry {
+    pub audit_id:      u64,
+    #[doc = "Seconds since the Unix epoch"]
+    pub timestamp:     u64,
+    pub operation:     AuditOperation,
+    pub simulation_id: u64,
+    pub actor:         String,
+    pub source_ip:     Option<String>,
+    pub request_id:    String,
+    #[doc = "The simulation before the change, None if it was created"]
+    pub before:        Option<Simulation>,
+    pub after:         Simulation
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[doc = "Struct for encapsulation a list of AuditEntries"]
+pub struct AuditArray {
+    pub entries: Vec<AuditEntry>
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuditContext {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        let actor = trusted_header(request, USER_HEADER_VARIABLE)
+            .unwrap_or_else(|| ANONYMOUS.into());
+        let source_ip = trusted_header(request, IP_HEADER_VARIABLE)
+            .and_then(|ip| ip.parse::<IpAddr>().ok())
+            .or_else(|| request.remote().map(|remote| remote.ip()));
+        request::Outcome::Success(AuditContext {
+            actor,
+            source_ip:  source_ip.map(|ip| ip.to_string()),
+            request_id: RequestId::of(request).0
+        })
+    }
+}
+
+impl<'r> OpenApiFromRequest<'r> for AuditContext {
+    fn from_request_input(gen: &mut OpenApiGenerator, _name: String, _required: bool)
+        -> rocket_okapi::Result<RequestHeaderInput> {
+        let header = match header_name(USER_HEADER_VARIABLE) {
+            Some(header) => header,
+            None => return Ok(RequestHeaderInput::None)
+        };
+        Ok(RequestHeaderInput::Parameter(Parameter {
+            name:              header,
+            location:          "header".into(),
+            description:       Some("The user making the change, as recorded in the audit log".into()),
+            required:          false,
+            deprecated:        false,
+            allow_empty_value: false,
+            value:             ParameterValue::Schema {
+                style:          None,
+                explode:        None,
+                allow_reserved: false,
+                schema:         gen.json_schema::<String>(),
+                example:        None,
+                examples:       None
+            },
+            extensions:        Default::default()
+        }))
+    }
+}
+
+#[doc = "The name of the header configured in the given environment variable, if any"]
+fn header_name(variable: &str) -> Option<String> {
+    std::env::var(variable).ok()
+        .map(|header| header.trim().to_string())
+        .filter(|header| !header.is_empty())
+}
+
+// Only headers set by the trusted proxy are read, never those of the client
+fn trusted_header(request: &Request<'_>, variable: &str) -> Option<String> {
+    let header = header_name(variable)?;
+    request.headers().get_one(&header)
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(String::from)
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[doc = "The operation that a status update is"]
+pub fn status_operation(after: &Simulation) -> AuditOperation {
+    match after.status {
+        SimulationStatus::Cancelled => AuditOperation::Cancel,
+        _ => AuditOperation::StatusUpdate
+    }
+}
+
+#[doc = "Record a change to a simulation. The change has been made, so a failure is only logged"]
+pub fn record(context: &AuditContext, operation: AuditOperation, before: Option<&Simulation>, after: &Simulation) {
+    let result = db::get_new_audit_id().and_then(|audit_id| db::append_audit_entry(&AuditEntry {
+        audit_id,
+        timestamp:     now(),
+        operation,
+        simulation_id: after.simulation_id,
+        actor:         context.actor.clone(),
+        source_ip:     context.source_ip.clone(),
+        request_id:    context.request_id.clone(),
+        before:        before.cloned(),
+        after:         after.clone()
+    }));
+    if let Err(e) = result {
+        warn!("Could not record {:?} of simulation {} by {} in the audit log: {}",
+              operation, after.simulation_id, context.actor, e);
+    }
+}
+
+#[doc = "The entries of the audit log that match all of the given filters"]
+pub fn query(simulation_id: Option<u64>, actor: Option<&str>, from: Option<u64>, to: Option<u64>)
+    -> redis::RedisResult<Vec<AuditEntry>> {
+    let entries = db::read_audit_entries(simulation_id)?;
+    Ok(entries.into_iter()
+        .filter(|entry| actor.is_none_or(|actor| entry.actor == actor))
+        .filter(|entry| from.is_none_or(|from| entry.timestamp >= from))
+        .filter(|entry| to.is_none_or(|to| entry.timestamp <= to))
+        .collect())
+}
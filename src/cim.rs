@@ -0,0 +1,359 @@
+//!
+//! # CIM model validation
+//!
+//! The CIM/XML files of a model are checked before any simulation is run
+//! with them, so that a malformed upload is rejected by the API rather than
+//! failing late in a worker. Each file must parse as RDF/XML; the profile of
+//! a file is taken from the `md:Model.profile` of its header, or guessed
+//! from its contents if it has none.
+//!
+//! | Problem                                            | Severity |
+//! |----------------------------------------------------|----------|
+//! | A file that is not UTF-8, not XML or not RDF       | Error    |
+//! | No EQ or no TP profile in the model                | Error    |
+//! | No topological nodes in the model                  | Error    |
+//! | A terminal connected to an unknown topological node | Error   |
+//! | No SV or no SSH profile in the model               | Warning  |
+//! | A file with no known profile                       | Warning  |
+//! | A profile in more than one file                    | Warning  |
+//!
+//! A model is valid if it has no errors.
+//!
+
+use std::collections::BTreeSet;
+use serde::{ Serialize, Deserialize };
+use schemars::JsonSchema;
+use roxmltree::{Document, Node};
+use crate::contingency::{Contingency, ElementType};
+
+const RDF_NS: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#";
+
+const LINE_CLASSES: [&str; 1] = ["ACLineSegment"];
+const TRANSFORMER_CLASSES: [&str; 1] = ["PowerTransformer"];
+const GENERATOR_CLASSES: [&str; 1] = ["SynchronousMachine"];
+const LOAD_CLASSES: [&str; 3] = ["EnergyConsumer", "ConformLoad", "NonConformLoad"];
+
+#[doc = "The CGMES profiles a CIM model is split into"]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, JsonSchema)]
+pub enum CimProfile {
+    #[doc = "Equipment"]
+    EQ,
+    #[doc = "Topology"]
+    TP,
+    #[doc = "State variables"]
+    SV,
+    #[doc = "Steady state hypothesis"]
+    #[serde(rename = "SSH")]
+    Ssh
+}
+
+impl CimProfile {
+    #[doc = "The profile of a md:Model.profile URI"]
+    fn from_uri(uri: &str) -> Option<CimProfile> {
+        if uri.contains("Equipment") {
+            Some(CimProfile::EQ)
+        } else if uri.contains("Topology") {
+            Some(CimProfile::TP)
+        } else if uri.contains("StateVariables") {
+            Some(CimProfile::SV)
+        } else if uri.contains("SteadyStateHypothesis") {
+            Some(CimProfile::Ssh)
+        } else {
+            None
+        }
+    }
+
+    #[doc = "The short name of the profile, as in the JSON"]
+    pub fn name(&self) -> &'static str {
+        match self {
+            CimProfile::EQ  => "EQ",
+            CimProfile::TP  => "TP",
+            CimProfile::SV  => "SV",
+            CimProfile::Ssh => "SSH"
+        }
+    }
+}
+
+#[doc = "How bad a problem with a model is"]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum Severity {
+    Error,
+    Warning
+}
+
+#[doc = "A problem found in a model"]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ModelProblem {
+    pub severity: Severity,
+    #[doc = "The file the problem is in, None if it is in the model as a whole"]
+    pub file_id:  Option<String>,
+    pub message:  String
+}
+
+#[doc = "The contents of a CIM file, or of a whole model"]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct CimCounts {
+    pub topological_nodes: usize,
+    pub lines:             usize,
+    pub transformers:      usize,
+    pub loads:             usize
+}
+
+#[doc = "What was found in one file of a model"]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct CimFileSummary {
+    pub file_id:  String,
+    pub profiles: Vec<CimProfile>,
+    pub counts:   CimCounts
+}
+
+/// # The result of validating a CIM model
+///
+/// ## Parameters:
+/// * valid
+///   - true if no problem is an error
+/// * profiles
+///   - the profiles found in any of the files
+/// * counts
+///   - the topological nodes, lines, transformers and loads of the whole model
+/// * files
+///   - what was found in each file, in order
+/// * problems
+///   - errors and warnings, errors first
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ModelValidation {
+    pub valid:    bool,
+    pub profiles: Vec<CimProfile>,
+    pub counts:   CimCounts,
+    pub files:    Vec<CimFileSummary>,
+    pub problems: Vec<ModelProblem>
+}
+
+impl ModelValidation {
+    #[doc = "The errors, one per line, for an error response"]
+    pub fn errors(&self) -> String {
+        self.problems.iter()
+            .filter(|problem| problem.severity == Severity::Error)
+            .map(|problem| match &problem.file_id {
+                Some(file_id) => format!("{}: {}", file_id, problem.message),
+                None => problem.message.clone()
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+#[doc = "A load of a model, as identified in a load profile"]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct CimLoad {
+    pub mrid: String,
+    pub name: Option<String>
+}
+
+impl CimLoad {
+    #[doc = "Whether a load id of a load profile refers to this load, by name or by mRID"]
+    pub fn is(&self, load_id: &str) -> bool {
+        self.name.as_deref() == Some(load_id) || self.mrid == load_id || self.mrid.trim_start_matches('_') == load_id
+    }
+}
+
+#[doc = "The files of a model, for validation"]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ValidationForm {
+    pub file_ids: Vec<String>
+}
+
+#[doc = "What one file defines and refers to"]
+struct ParsedFile {
+    summary:    CimFileSummary,
+    node_ids:   BTreeSet<String>,
+    node_links: Vec<(String, String)>,
+    loads:      Vec<CimLoad>,
+    outages:    Vec<Contingency>
+}
+
+fn error(file_id: Option<&str>, message: String) -> ModelProblem {
+    ModelProblem { severity: Severity::Error, file_id: file_id.map(String::from), message }
+}
+
+fn warning(file_id: Option<&str>, message: String) -> ModelProblem {
+    ModelProblem { severity: Severity::Warning, file_id: file_id.map(String::from), message }
+}
+
+#[doc = "The id an element defines, from rdf:ID or rdf:about, without the leading '#'"]
+fn element_id<'a>(node: &Node<'a, '_>) -> Option<&'a str> {
+    node.attribute((RDF_NS, "ID"))
+        .or_else(|| node.attribute((RDF_NS, "about")))
+        .map(|id| id.trim_start_matches('#'))
+}
+
+#[doc = "The contingency of taking an element out of service"]
+fn outage(node: &Node, element_type: ElementType) -> Option<Contingency> {
+    element_id(node).map(|mrid| Contingency { element_type, mrid: mrid.into() })
+}
+
+#[doc = "Whether an element is the full definition of an object, rather than an addition to it"]
+fn defines(node: &Node) -> bool {
+    node.attribute((RDF_NS, "ID")).is_some()
+}
+
+fn parse_file(file_id: &str, data: &[u8]) -> Result<ParsedFile, String> {
+    let text = std::str::from_utf8(data).map_err(|e| format!("File is not UTF-8: {}", e))?;
+    let document = Document::parse(text).map_err(|e| format!("File is not valid XML: {}", e))?;
+    let root = document.root_element();
+    if root.tag_name().name() != "RDF" || root.tag_name().namespace() != Some(RDF_NS) {
+        return Err(format!("File is not RDF/XML, its root element is {}", root.tag_name().name()))
+    }
+
+    let mut profiles = BTreeSet::new();
+    let mut counts = CimCounts::default();
+    let mut node_ids = BTreeSet::new();
+    let mut node_links = Vec::new();
+    let mut loads = Vec::new();
+    let mut outages = Vec::new();
+    let mut state_variables = false;
+    for node in root.children().filter(Node::is_element) {
+        let class = node.tag_name().name();
+        match class {
+            "FullModel" => {
+                let header_profiles = node.children()
+                    .filter(|child| child.tag_name().name() == "Model.profile")
+                    .filter_map(|child| child.text())
+                    .filter_map(|uri| CimProfile::from_uri(uri.trim()));
+                profiles.extend(header_profiles);
+            },
+            "TopologicalNode" if defines(&node) => {
+                counts.topological_nodes += 1;
+                node_ids.extend(element_id(&node).map(String::from));
+            },
+            "Terminal" => {
+                let link = node.children()
+                    .find(|child| child.tag_name().name() == "Terminal.TopologicalNode")
+                    .and_then(|child| child.attribute((RDF_NS, "resource")));
+                if let (Some(terminal), Some(link)) = (element_id(&node), link) {
+                    node_links.push((terminal.into(), link.trim_start_matches('#').into()));
+                }
+            },
+            _ if defines(&node) && LINE_CLASSES.contains(&class) => {
+                counts.lines += 1;
+                outages.extend(outage(&node, ElementType::Line));
+            },
+            _ if defines(&node) && TRANSFORMER_CLASSES.contains(&class) => {
+                counts.transformers += 1;
+                outages.extend(outage(&node, ElementType::Transformer));
+            },
+            _ if defines(&node) && GENERATOR_CLASSES.contains(&class) => outages.extend(outage(&node, ElementType::Generator)),
+            _ if defines(&node) && LOAD_CLASSES.contains(&class) => {
+                counts.loads += 1;
+                let name = node.children()
+                    .find(|child| child.tag_name().name() == "IdentifiedObject.name")
+                    .and_then(|child| child.text())
+                    .map(|name| name.trim().to_string());
+                loads.extend(element_id(&node).map(|mrid| CimLoad { mrid: mrid.into(), name }));
+            },
+            _ if class.starts_with("Sv") => state_variables = true,
+            _ => ()
+        }
+    }
+
+    // Without a header, guess the profiles from what the file defines
+    if profiles.is_empty() {
+        if counts.lines + counts.transformers + counts.loads > 0 {
+            profiles.insert(CimProfile::EQ);
+        }
+        if counts.topological_nodes > 0 {
+            profiles.insert(CimProfile::TP);
+        }
+        if state_variables {
+            profiles.insert(CimProfile::SV);
+        }
+    }
+
+    Ok(ParsedFile {
+        summary: CimFileSummary { file_id: file_id.into(), profiles: profiles.into_iter().collect(), counts },
+        node_ids,
+        node_links,
+        loads,
+        outages
+    })
+}
+
+#[doc = "Validate the files of a model, given as pairs of file id and contents"]
+pub fn validate(files: &[(String, Vec<u8>)]) -> ModelValidation {
+    let mut problems = Vec::new();
+    let mut parsed = Vec::new();
+    for (file_id, data) in files {
+        match parse_file(file_id, data) {
+            Ok(file) => {
+                if file.summary.profiles.is_empty() {
+                    problems.push(warning(Some(file_id), "No known CIM profile found in file".into()));
+                }
+                parsed.push(file);
+            },
+            Err(e) => problems.push(error(Some(file_id), e))
+        }
+    }
+
+    let mut profiles = BTreeSet::new();
+    let mut counts = CimCounts::default();
+    let mut node_ids = BTreeSet::new();
+    for file in &parsed {
+        for profile in &file.summary.profiles {
+            if !profiles.insert(*profile) {
+                problems.push(warning(Some(&file.summary.file_id), format!("Profile {} is in more than one file", profile.name())));
+            }
+        }
+        counts.topological_nodes += file.summary.counts.topological_nodes;
+        counts.lines             += file.summary.counts.lines;
+        counts.transformers      += file.summary.counts.transformers;
+        counts.loads             += file.summary.counts.loads;
+        node_ids.extend(file.node_ids.iter().cloned());
+    }
+
+    // The model as a whole is only checked if every file could be read
+    if parsed.len() == files.len() {
+        for (profile, severity) in [(CimProfile::EQ, Severity::Error), (CimProfile::TP, Severity::Error),
+                                    (CimProfile::SV, Severity::Warning), (CimProfile::Ssh, Severity::Warning)] {
+            if !profiles.contains(&profile) {
+                problems.push(ModelProblem { severity, file_id: None, message: format!("No {} profile in model", profile.name()) });
+            }
+        }
+        if counts.topological_nodes == 0 {
+            problems.push(error(None, "Model has no topological nodes".into()));
+        }
+        for file in &parsed {
+            for (terminal, node) in &file.node_links {
+                if !node_ids.contains(node) {
+                    problems.push(error(Some(&file.summary.file_id),
+                                        format!("Terminal {} is connected to unknown topological node {}", terminal, node)));
+                }
+            }
+        }
+    }
+
+    problems.sort_by_key(|problem| problem.severity == Severity::Warning);
+    ModelValidation {
+        valid:    problems.iter().all(|problem| problem.severity == Severity::Warning),
+        profiles: profiles.into_iter().collect(),
+        counts,
+        files:    parsed.into_iter().map(|file| file.summary).collect(),
+        problems
+    }
+}
+
+#[doc = "The loads defined in the files of a model. Files that cannot be read are left out"]
+pub fn loads(files: &[(String, Vec<u8>)]) -> Vec<CimLoad> {
+    files.iter()
+        .filter_map(|(file_id, data)| parse_file(file_id, data).ok())
+        .flat_map(|file| file.loads)
+        .collect()
+}
+
+#[doc = "The lines, transformers and generators defined in the files of a model, each as the contingency of \
+         taking it out of service. Files that cannot be read are left out"]
+pub fn outages(files: &[(String, Vec<u8>)]) -> Vec<Contingency> {
+    files.iter()
+        .filter_map(|(file_id, data)| parse_file(file_id, data).ok())
+        .flat_map(|file| file.outages)
+        .collect()
+}
@@ -0,0 +1,170 @@
+//!
+//! # Comparison of simulations
+//!
+//! The first simulation in a comparison is the base case. The results of
+//! every other simulation are aligned with it by timestamp, and for each
+//! signal that both hold the maximum absolute error and the RMSE over the
+//! shared timestamps are computed. The parameters of all the simulations
+//! that decide their results, as in [`ReproducibleParameters`], are compared
+//! field by field, together with their input files and limits. Ids, names,
+//! labels and status differ between any two runs and are left out.
+//!
+
+use std::collections::HashMap;
+use serde::{ Serialize, Deserialize };
+use serde_json::{json, Map, Value};
+use schemars::JsonSchema;
+use crate::provenance::ReproducibleParameters;
+use crate::results::ResultsTable;
+use crate::routes::Simulation;
+
+#[doc = "The difference between one signal of a simulation and the same signal of the base case"]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct SignalComparison {
+    pub name:          String,
+    pub max_abs_error: f64,
+    pub rmse:          f64
+}
+
+#[doc = "The difference between the results of a simulation and those of the base case"]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ResultsComparison {
+    pub simulation_id:   u64,
+    pub common_points:   usize,
+    pub signals:         Vec<SignalComparison>,
+    pub missing_signals: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub passed:          Option<bool>,
+    pub error:           String
+}
+
+#[doc = "A parameter that is not the same for all the compared simulations"]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ParameterDifference {
+    pub parameter: String,
+    pub values:    Vec<Value>
+}
+
+#[doc = "The comparison of a base case with one or more other simulations"]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct SimulationComparison {
+    pub base_id:               u64,
+    pub ids:                   Vec<u64>,
+    pub parameter_differences: Vec<ParameterDifference>,
+    pub results:               Vec<ResultsComparison>
+}
+
+#[doc = "Parse a comma separated list of at least two simulation ids"]
+pub fn parse_ids(ids: &str) -> Result<Vec<u64>, String> {
+    let mut parsed = Vec::new();
+    for id in ids.split(',').map(|id| id.trim()).filter(|id| !id.is_empty()) {
+        match id.parse::<u64>() {
+            Ok(id) => parsed.push(id),
+            Err(e) => return Err(format!("Invalid simulation id '{}': {}", id, e))
+        }
+    }
+    if parsed.len() < 2 {
+        return Err("At least two simulation ids are needed for a comparison".into())
+    }
+    Ok(parsed)
+}
+
+#[doc = "The parameters of a simulation that are compared: those that decide its results, its inputs and its limits"]
+fn compared_parameters(sim: &Simulation) -> Map<String, Value> {
+    let mut record = match serde_json::to_value(ReproducibleParameters::from_simulation(sim)) {
+        Ok(Value::Object(record)) => record,
+        _ => Map::new()
+    };
+    record.insert("model_id".into(), json!(sim.model_id));
+    record.insert("model".into(), json!(sim.model));
+    record.insert("model_files".into(), json!(sim.model_files));
+    record.insert("load_profile_id".into(), json!(sim.load_profile_id));
+    record.insert("load_profile".into(), json!(sim.load_profile));
+    record.insert("limits".into(), json!(sim.limits));
+    record
+}
+
+#[doc = "List the parameters that are not the same for all the simulations"]
+pub fn compare_parameters(simulations: &[Simulation]) -> Vec<ParameterDifference> {
+    let records: Vec<Map<String, Value>> = simulations.iter().map(compared_parameters).collect();
+    let mut parameters: Vec<&String> = records.iter().flat_map(|record| record.keys()).collect();
+    parameters.sort();
+    parameters.dedup();
+    parameters.into_iter()
+        .filter_map(|parameter| {
+            let values: Vec<Value> = records.iter()
+                .map(|record| record.get(parameter).cloned().unwrap_or(Value::Null))
+                .collect();
+            if values.iter().all(|value| *value == values[0]) {
+                None
+            } else {
+                Some(ParameterDifference { parameter: parameter.clone(), values })
+            }
+        })
+        .collect()
+}
+
+// Timestamps are matched to the nanosecond, the resolution of SimulationTime
+fn timestamp_key(time: f64) -> i64 {
+    (time * 1e9).round() as i64
+}
+
+#[doc = "Compare the results of a simulation with those of the base case"]
+pub fn compare_results(simulation_id: u64, base: &ResultsTable, other: &ResultsTable, tolerance: Option<f64>) -> ResultsComparison {
+    let other_rows: HashMap<i64, usize> = other.time.iter().enumerate()
+        .map(|(row, time)| (timestamp_key(*time), row))
+        .collect();
+    let common_rows: Vec<(usize, usize)> = base.time.iter().enumerate()
+        .filter_map(|(base_row, time)| other_rows.get(&timestamp_key(*time)).map(|other_row| (base_row, *other_row)))
+        .collect();
+
+    let mut signals = Vec::new();
+    let mut missing_signals = Vec::new();
+    for base_signal in &base.signals {
+        let other_signal = match other.signals.iter().find(|signal| signal.name == base_signal.name) {
+            Some(signal) => signal,
+            None => {
+                missing_signals.push(base_signal.name.clone());
+                continue
+            }
+        };
+        let mut max_abs_error: f64 = 0.0;
+        let mut sum_of_squares = 0.0;
+        for (base_row, other_row) in &common_rows {
+            let error = other_signal.values[*other_row] - base_signal.values[*base_row];
+            max_abs_error = max_abs_error.max(error.abs());
+            sum_of_squares += error * error;
+        }
+        let rmse = if common_rows.is_empty() { 0.0 } else { (sum_of_squares / common_rows.len() as f64).sqrt() };
+        signals.push(SignalComparison { name: base_signal.name.clone(), max_abs_error, rmse });
+    }
+    for other_signal in &other.signals {
+        if base.signals.iter().all(|signal| signal.name != other_signal.name) {
+            missing_signals.push(other_signal.name.clone());
+        }
+    }
+    let passed = tolerance.map(|tolerance| {
+        !common_rows.is_empty() && missing_signals.is_empty()
+            && signals.iter().all(|signal| signal.max_abs_error <= tolerance)
+    });
+    ResultsComparison {
+        simulation_id,
+        common_points: common_rows.len(),
+        signals,
+        missing_signals,
+        passed,
+        error: "".into()
+    }
+}
+
+#[doc = "The comparison entry for a simulation whose results could not be read"]
+pub fn failed_results_comparison(simulation_id: u64, tolerance: Option<f64>, error: String) -> ResultsComparison {
+    ResultsComparison {
+        simulation_id,
+        common_points:   0,
+        signals:         Vec::new(),
+        missing_signals: Vec::new(),
+        passed:          tolerance.map(|_| false),
+        error
+    }
+}
@@ -0,0 +1,173 @@
+//!
+//! # N-1 contingency analysis
+//!
+//! An Outage simulation names the grid elements that should be taken
+//! out of service, one at a time. Each contingency is sent to the
+//! worker as its own job with its own results file, and the results
+//! of all jobs are aggregated into a [`ContingencyReport`]. The worker
+//! reports the status of each job on its own, see [`crate::status`].
+//!
+//! With `all_n_minus_1` the contingencies are every line, transformer and
+//! generator that the model defines, as found by [`crate::cim::outages`],
+//! and each is queued as its own job in the same way. Simulations created
+//! before that had a single job, in which the worker enumerated the outages
+//! itself and wrote one results file with every column name prefixed with
+//! the mRID of the outaged element, e.g. `line7:n3.v`.
+//!
+//! Violations are found with the limits of the simulation, see [`crate::limits`].
+//!
+
+use serde::{ Serialize, Deserialize };
+use schemars::JsonSchema;
+use rocket::http::Status;
+use crate::cim;
+use crate::limits::{find_violations, Limits, Violation};
+use crate::results::{ResultsTable, Signal};
+use crate::model;
+use crate::routes::{SimulationError, SimulationForm, SimulationType};
+use crate::status::SimulationStatus;
+
+#[doc = "Enum for the kinds of grid element that can be taken out of service"]
+#[derive(JsonSchema, FromFormField, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum ElementType {
+    Line,
+    Transformer,
+    Generator
+}
+
+#[doc = "A single grid element to take out of service, identified by its CIM mRID"]
+#[derive(FromForm, Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Contingency {
+    pub element_type: ElementType,
+    pub mrid:         String
+}
+
+#[doc = "A worker job that was queued for one contingency of an Outage simulation, with the status its worker reported"]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ContingencyJob {
+    pub contingency: Contingency,
+    pub results_id:  String,
+    #[serde(default)]
+    pub status:      SimulationStatus,
+    #[serde(default)]
+    pub progress:    u8,
+    #[serde(default)]
+    pub error:       String
+}
+
+impl ContingencyJob {
+    pub fn new(contingency: Contingency, results_id: String) -> ContingencyJob {
+        ContingencyJob {
+            contingency,
+            results_id,
+            status:   SimulationStatus::Queued,
+            progress: 0,
+            error:    "".into()
+        }
+    }
+}
+
+#[doc = "The violations found for one contingency"]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct OutageReport {
+    pub mrid:         String,
+    pub element_type: Option<ElementType>,
+    pub results_id:   String,
+    pub severity:     f64,
+    pub violations:   Vec<Violation>,
+    pub error:        String
+}
+
+#[doc = "The outages of a simulation, ranked from most to least severe"]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ContingencyReport {
+    pub simulation_id: u64,
+    pub outages:       Vec<OutageReport>
+}
+
+#[doc = "Check that the contingency fields of a form match its simulation type"]
+pub fn validate_form(form: &SimulationForm) -> Result<(), String> {
+    match form.simulation_type {
+        SimulationType::Outage => {
+            if form.contingencies.is_empty() && !form.all_n_minus_1 {
+                return Err("An Outage simulation needs a list of contingencies or all_n_minus_1".into())
+            }
+            if !form.contingencies.is_empty() && form.all_n_minus_1 {
+                return Err("Specify either a list of contingencies or all_n_minus_1, not both".into())
+            }
+            if let Some(contingency) = form.contingencies.iter().find(|c| c.mrid.trim().is_empty()) {
+                return Err(format!("Contingency for {:?} has an empty mrid", contingency.element_type))
+            }
+            Ok(())
+        },
+        _ => {
+            if !form.contingencies.is_empty() || form.all_n_minus_1 {
+                return Err("Contingencies can only be given for an Outage simulation".into())
+            }
+            Ok(())
+        }
+    }
+}
+
+#[doc = "The contingencies to queue a job for: those of the form, or with all_n_minus_1 every element of the model"]
+pub async fn contingencies(form: &SimulationForm, model_files: &[String]) -> Result<Vec<Contingency>, SimulationError> {
+    if !form.all_n_minus_1 {
+        return Ok(form.contingencies.clone())
+    }
+    let outages = cim::outages(&model::read_files(model_files).await?);
+    if outages.is_empty() {
+        return Err(SimulationError {
+            err: "all_n_minus_1 needs a model with at least one line, transformer or generator".into(),
+            http_status_code: Status::BadRequest
+        })
+    }
+    Ok(outages)
+}
+
+#[doc = "Sort the outages so that the most severe comes first"]
+pub fn rank_outages(outages: &mut [OutageReport]) {
+    outages.sort_by(|a, b| b.severity.partial_cmp(&a.severity).unwrap_or(std::cmp::Ordering::Equal));
+}
+
+#[doc = "Split the results of a single all_n_minus_1 job, as queued before the jobs were fanned out, into one table per outaged element"]
+pub fn split_by_outage(table: &ResultsTable) -> Vec<(String, ResultsTable)> {
+    let mut outages: Vec<(String, ResultsTable)> = Vec::new();
+    for signal in &table.signals {
+        let (mrid, name) = match signal.name.split_once(':') {
+            Some(parts) => parts,
+            None => continue
+        };
+        let renamed = Signal { name: name.to_string(), values: signal.values.clone() };
+        match outages.iter_mut().find(|(outage, _)| outage == mrid) {
+            Some((_, outage_table)) => outage_table.signals.push(renamed),
+            None => outages.push((mrid.to_string(), ResultsTable { time: table.time.clone(), signals: vec![renamed] }))
+        }
+    }
+    outages
+}
+
+#[doc = "Build the report for one outage from its results"]
+pub fn outage_report(mrid: &str, element_type: Option<ElementType>, results_id: &str,
+                     table: &ResultsTable, limits: &Limits) -> OutageReport {
+    let violations = find_violations(table, limits);
+    OutageReport {
+        mrid:         mrid.to_string(),
+        element_type,
+        results_id:   results_id.to_string(),
+        severity:     violations.iter().map(|v| v.excess()).sum(),
+        violations,
+        error:        "".into()
+    }
+}
+
+#[doc = "Build the report for an outage whose results could not be read"]
+pub fn failed_outage_report(mrid: &str, element_type: Option<ElementType>, results_id: &str, error: String) -> OutageReport {
+    OutageReport {
+        mrid:         mrid.to_string(),
+        element_type,
+        results_id:   results_id.to_string(),
+        severity:     0.0,
+        violations:   Vec::new(),
+        error
+    }
+}
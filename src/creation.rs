@@ -0,0 +1,165 @@
+//!
+//! # Creating a simulation
+//!
+//! Creating a simulation takes several steps against different services:
+//! a new id from the db, a results file per job from the file service, the
+//! simulation record in the db, and finally a job per results file on the
+//! AMQP queue. A [`CreationSaga`] keeps track of what has been done so
+//! far, so that if a later step fails, the earlier ones can be undone:
+//!
+//! | Step done            | Compensation                                      |
+//! |----------------------|---------------------------------------------------|
+//! | results file created | the file is deleted from the file service         |
+//! | record written       | the record is marked Failed, with the reason, and |
+//! |                      | without the results files that were deleted, and  |
+//! |                      | its creation is recorded in the audit log         |
+//!
+//! The id is not given back, as ids are never reused. A simulation that
+//! could not be started is therefore never left Queued in the db. The jobs
+//! are published in one AMQP transaction, so a failed publish leaves no
+//! job on the queue that refers to a deleted results file.
+//!
+
+use rocket::http::Status;
+use log::info;
+use crate::audit;
+use crate::audit::{AuditContext, AuditOperation};
+use crate::db;
+use crate::file_service;
+use crate::logging;
+use crate::routes::{Simulation, SimulationError};
+use crate::search;
+use crate::status;
+use crate::status::{SimulationStatus, StatusEvent};
+use crate::webhook;
+
+#[doc = "The steps of creating a simulation that have been done so far"]
+#[derive(Debug)]
+pub struct CreationSaga {
+    audit_context: AuditContext,
+    operation:     AuditOperation,
+    simulation_id: Option<u64>,
+    results_files: Vec<String>,
+    simulation:    Option<Simulation>
+}
+
+impl CreationSaga {
+    #[doc = "Start the creation of a simulation, audited as the given operation of the given context"]
+    pub fn new(audit_context: AuditContext, operation: AuditOperation) -> CreationSaga {
+        CreationSaga {
+            audit_context,
+            operation,
+            simulation_id: None,
+            results_files: Vec::new(),
+            simulation:    None
+        }
+    }
+
+    #[cfg(test)]
+    #[doc = "The simulation record, once it has been written"]
+    pub fn simulation(&self) -> Option<&Simulation> {
+        self.simulation.as_ref()
+    }
+
+    #[cfg(test)]
+    #[doc = "The results files that have been created and not deleted"]
+    pub fn results_files(&self) -> &[String] {
+        &self.results_files
+    }
+
+    #[doc = "Step 1: obtain a new simulation id"]
+    pub fn reserve_id(&mut self) -> Result<u64, SimulationError> {
+        match db::get_new_simulation_id() {
+            Ok(id) => {
+                logging::set_simulation_id(id);
+                self.simulation_id = Some(id);
+                Ok(id)
+            },
+            Err(e) => Err(SimulationError {
+                err: format!("Failed to obtain new simulation id: {}", e),
+                http_status_code: Status::BadGateway
+            })
+        }
+    }
+
+    #[doc = "Step 2: create a results file, once per job"]
+    pub async fn create_results_file(&mut self) -> Result<String, SimulationError> {
+        let results_id = file_service::create_results_file().await?;
+        self.results_files.push(results_id.clone());
+        Ok(results_id)
+    }
+
+    #[doc = "Step 3: write the simulation record, and add it to the search index"]
+    pub fn write_record(&mut self, simulation: Simulation) -> Result<(), SimulationError> {
+        if let Err(e) = db::write_simulation(&simulation.simulation_id.to_string(), &simulation) {
+            return Err(SimulationError {
+                err: format!("Could not write to db: {}", e),
+                http_status_code: Status::BadGateway
+            })
+        }
+        status::publish(StatusEvent::from_simulation(&simulation));
+        let index_keys: Vec<String> = search::index_keys(&simulation).into_iter().collect();
+        let indexed = db::update_search_index(simulation.simulation_id, &[], &index_keys);
+        self.simulation = Some(simulation);
+        match indexed {
+            Ok(()) => Ok(()),
+            Err(e) => Err(SimulationError {
+                err: format!("Could not update search index: {}", e),
+                http_status_code: Status::BadGateway
+            })
+        }
+    }
+
+    #[doc = "Undo the steps done so far, after a later step failed with the given error"]
+    pub async fn abort(&mut self, error: SimulationError) -> SimulationError {
+        let mut reason = error.err.clone();
+        let mut leftover_files = Vec::new();
+        let mut deleted_files = Vec::new();
+        for results_id in self.results_files.drain(..) {
+            match file_service::delete_file(&results_id).await {
+                Ok(()) => deleted_files.push(results_id),
+                Err(e) => {
+                    info!("Could not delete results file {}: {}", results_id, e);
+                    leftover_files.push(results_id);
+                }
+            }
+        }
+        if !leftover_files.is_empty() {
+            reason = format!("{}; results files {} could not be deleted", reason, leftover_files.join(", "));
+        }
+        self.results_files = leftover_files;
+        if let Some(simulation) = &mut self.simulation {
+            let old_keys = search::index_keys(simulation);
+            simulation.status = SimulationStatus::Failed;
+            simulation.error = format!("Simulation could not be started: {}", reason);
+            if deleted_files.contains(&simulation.results_id) {
+                simulation.results_id.clear();
+            }
+            for job in &mut simulation.contingency_jobs {
+                if deleted_files.contains(&job.results_id) {
+                    job.results_id.clear();
+                }
+            }
+            match db::write_simulation(&simulation.simulation_id.to_string(), simulation) {
+                Ok(()) => {
+                    audit::record(&self.audit_context, self.operation, None, simulation);
+                    let (removed, added) = search::index_changes(&old_keys, simulation);
+                    if let Err(e) = db::update_search_index(simulation.simulation_id, &removed, &added) {
+                        info!("Could not update search index for simulation {}: {}", simulation.simulation_id, e);
+                    }
+                    let event = StatusEvent::from_simulation(simulation);
+                    webhook::notify(&event);
+                    status::publish(event);
+                    reason = format!("{}; simulation {} has been marked as Failed", reason, simulation.simulation_id);
+                },
+                Err(e) => {
+                    info!("Could not mark simulation {} as Failed: {}", simulation.simulation_id, e);
+                    reason = format!("{}; simulation {} could not be marked as Failed: {}", reason, simulation.simulation_id, e);
+                }
+            }
+        } else if let Some(id) = self.simulation_id {
+            info!("Abandoning simulation id {} before its record was written", id);
+        }
+        SimulationError { err: reason, http_status_code: error.http_status_code }
+    }
+}
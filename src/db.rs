@@ -1,58 +1,508 @@
 
 extern crate redis;
+use std::time::Duration;
 use redis::{Commands, RedisResult};
 use crate::routes::Simulation;
+use crate::metrics;
+use crate::search;
 
 fn get_connection() -> redis::RedisResult<redis::Connection> {
     let client = redis::Client::open("redis://redis-master/")?;
     client.get_connection()
 }
 
+#[doc = "Check that the Redis DB answers, connecting and waiting for the answer at most for the given time each"]
+pub fn ping(timeout: Duration) -> RedisResult<()> {
+    metrics::observe_redis("ping", || {
+        let client = redis::Client::open("redis://redis-master/")?;
+        let mut conn = client.get_connection_with_timeout(timeout)?;
+        conn.set_read_timeout(Some(timeout))?;
+        conn.set_write_timeout(Some(timeout))?;
+        redis::cmd("PING").query(&mut conn)
+    })
+}
+
 pub fn get_number_of_simulations() -> RedisResult<u64> {
-    let mut conn = get_connection()?;
-    conn.get("models")
+    metrics::observe_redis("get_number_of_simulations", || {
+        let mut conn = get_connection()?;
+        conn.get("models")
+    })
 }
 
 #[doc = "Function for requesting a new Simulation id from the Redis DB"]
 pub fn get_new_simulation_id() -> RedisResult<u64> {
-    let mut conn = get_connection()?;
-    conn.incr("models", 1)
+    metrics::observe_redis("get_new_simulation_id", || {
+        let mut conn = get_connection()?;
+        conn.incr("models", 1)
+    })
 }
 
 #[doc = "Function for writing a Simulation into a Redis DB"]
 pub fn write_simulation(key: &String, value: &Simulation) -> Result<(), redis::RedisError> {
-    let mut conn = get_connection()?;
-    match serde_json::to_string(value) {
-        Ok(value_str) => conn.set(key, value_str),
-        Err(e) => Err((redis::ErrorKind::IoError, "".into(), e.to_string()).into())
-    }
+    metrics::observe_redis("write_simulation", || {
+        let mut conn = get_connection()?;
+        match serde_json::to_string(value) {
+            Ok(value_str) => conn.set(key, value_str),
+            Err(e) => Err((redis::ErrorKind::IoError, "".into(), e.to_string()).into())
+        }
+    })
 }
 
 use redis::RedisError;
 
+#[doc = "Convert a value read from a Redis DB into a Simulation"]
+fn parse_simulation(key: u64, value_utf8: Vec<u8>) -> Result<Simulation, RedisError> {
+    if value_utf8.len() == 0 {
+        return Err(RedisError::from((redis::ErrorKind::IoError,
+            "Simulation does not exist in database".into(), key.to_string())))
+    }
+    match String::from_utf8(value_utf8) {
+        Ok(value_string) => match serde_json::from_str(&value_string) {
+            Ok(sim) => Ok(sim),
+            Err(e) => {
+                let error_string = format!("value: {} error: {}", value_string, e.to_string());
+                Err(RedisError::from((redis::ErrorKind::IoError,
+                    "Could not convert string to Simulation! ", error_string.into())))
+            }
+        }
+        Err(e) => return Err((redis::ErrorKind::IoError,
+            "Could not convert utf8 from Redis into string: ".into(), e.to_string()).into())
+    }
+}
+
 #[doc = "Function for reading a Simulation from a Redis DB"]
 pub fn read_simulation(key: u64) -> Result<Simulation, RedisError> {
-    let mut conn = get_connection()?;
-    match conn.get::<u64, Vec<u8>>(key) {
-        Ok(value_utf8) => {
-            if value_utf8.len() == 0 {
-                return Err(RedisError::from((redis::ErrorKind::IoError,
-                    "Simulation does not exist in database".into(), key.to_string())))
+    metrics::observe_redis("read_simulation", || {
+        let mut conn = get_connection()?;
+        match conn.get::<u64, Vec<u8>>(key) {
+            Ok(value_utf8) => parse_simulation(key, value_utf8),
+            Err(e) => return Err((redis::ErrorKind::IoError,
+                "Could not fetch item from Redis: ".into(), e.to_string()).into())
+        }
+    })
+}
+
+#[doc = "Function for changing a Simulation in a Redis DB, and its entries in the search index with it. If the Simulation \
+         is written by anyone else in the meantime, the change is made again to the new value, so that no write is lost. \
+         Returns the Simulation before and after the change, or the error of the change, in which case nothing is written"]
+pub fn update_simulation<E>(key: u64, mut change: impl FnMut(&mut Simulation) -> Result<(), E>)
+    -> RedisResult<Result<(Simulation, Simulation), E>> {
+    metrics::observe_redis("update_simulation", || {
+        let mut conn = get_connection()?;
+        redis::transaction(&mut conn, &[key], |conn, pipe| {
+            let before = parse_simulation(key, conn.get(key)?)?;
+            let mut after = before.clone();
+            if let Err(e) = change(&mut after) {
+                return Ok(Some(Err(e)))
+            }
+            let value_str = match serde_json::to_string(&after) {
+                Ok(value_str) => value_str,
+                Err(e) => return Err((redis::ErrorKind::IoError, "", e.to_string()).into())
+            };
+            let (removed, added) = search::index_changes(&search::index_keys(&before), &after);
+            for index_key in &removed {
+                pipe.srem(index_key, key).ignore();
             }
-            match String::from_utf8(value_utf8) {
-                Ok(value_string) => match serde_json::from_str(&value_string) {
-                    Ok(sim) => Ok(sim),
-                    Err(e) => {
-                        let error_string = format!("value: {} error: {}", value_string, e.to_string());
-                        Err(RedisError::from((redis::ErrorKind::IoError,
-                            "Could not convert string to Simulation! ", error_string.into())))
-                    }
-                }
-                Err(e) => return Err((redis::ErrorKind::IoError,
-                    "Could not convert utf8 from Redis into string: ".into(), e.to_string()).into())
+            for index_key in &added {
+                pipe.sadd(index_key, key).ignore();
+            }
+            let written: Option<(String,)> = pipe.set(key, value_str).query(conn)?;
+            Ok(written.map(|_| Ok((before, after))))
+        })
+    })
+}
+
+use serde::{ Serialize, de::DeserializeOwned };
+use crate::provenance::Provenance;
+use crate::webhook::{Delivery, Webhook};
+
+// Only the most recent deliveries of each webhook are kept
+const WEBHOOK_DELIVERY_LOG_LENGTH: isize = 100;
+
+#[doc = "Function for writing any serializable value into a Redis DB as json"]
+fn write_json<T: Serialize>(key: &str, value: &T) -> RedisResult<()> {
+    let mut conn = get_connection()?;
+    match serde_json::to_string(value) {
+        Ok(value_str) => conn.set(key, value_str),
+        Err(e) => Err((redis::ErrorKind::IoError, "", e.to_string()).into())
+    }
+}
+
+#[doc = "Function for reading a json value from a Redis DB, None if the key does not exist"]
+fn read_json<T: DeserializeOwned>(key: &str) -> RedisResult<Option<T>> {
+    let mut conn = get_connection()?;
+    let value: Option<String> = conn.get(key)?;
+    match value {
+        Some(value_string) => match serde_json::from_str(&value_string) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) => Err((redis::ErrorKind::IoError, "Could not convert json from Redis",
+                           format!("key: {} error: {}", key, e)).into())
+        },
+        None => Ok(None)
+    }
+}
+
+fn provenance_key(simulation_id: u64) -> String {
+    format!("simulation:{}:provenance", simulation_id)
+}
+
+#[doc = "Function for writing the Provenance of a Simulation into a Redis DB"]
+pub fn write_provenance(simulation_id: u64, value: &Provenance) -> RedisResult<()> {
+    metrics::observe_redis("write_provenance", || {
+        write_json(&provenance_key(simulation_id), value)
+    })
+}
+
+#[doc = "Function for reading the Provenance of a Simulation from a Redis DB, None if none was recorded"]
+pub fn read_provenance(simulation_id: u64) -> RedisResult<Option<Provenance>> {
+    metrics::observe_redis("read_provenance", || {
+        read_json(&provenance_key(simulation_id))
+    })
+}
+
+#[doc = "Function for changing the Provenance of a Simulation in a Redis DB, if one was recorded. \
+         If it is written by anyone else in the meantime, the change is made again to the new value"]
+pub fn update_provenance(simulation_id: u64, mut change: impl FnMut(&mut Provenance)) -> RedisResult<()> {
+    metrics::observe_redis("update_provenance", || {
+        let key = provenance_key(simulation_id);
+        let mut conn = get_connection()?;
+        redis::transaction(&mut conn, &[&key], |conn, pipe| {
+            let value: Option<String> = conn.get(&key)?;
+            let mut provenance: Provenance = match value.map(|value| serde_json::from_str(&value)) {
+                Some(Ok(provenance)) => provenance,
+                Some(Err(e)) => return Err((redis::ErrorKind::IoError, "Could not convert json from Redis",
+                                            format!("key: {} error: {}", key, e)).into()),
+                None => return Ok(Some(()))
+            };
+            change(&mut provenance);
+            let value_str = match serde_json::to_string(&provenance) {
+                Ok(value_str) => value_str,
+                Err(e) => return Err((redis::ErrorKind::IoError, "", e.to_string()).into())
+            };
+            let written: Option<(String,)> = pipe.set(&key, value_str).query(conn)?;
+            Ok(written.map(|_| ()))
+        })
+    })
+}
+
+#[doc = "Function for requesting a new Webhook id from the Redis DB"]
+pub fn get_new_webhook_id() -> RedisResult<u64> {
+    metrics::observe_redis("get_new_webhook_id", || {
+        let mut conn = get_connection()?;
+        conn.incr("webhooks", 1)
+    })
+}
+
+pub fn get_number_of_webhooks() -> RedisResult<u64> {
+    metrics::observe_redis("get_number_of_webhooks", || {
+        let mut conn = get_connection()?;
+        let number: Option<u64> = conn.get("webhooks")?;
+        Ok(number.unwrap_or(0))
+    })
+}
+
+#[doc = "Function for writing a Webhook into a Redis DB"]
+pub fn write_webhook(value: &Webhook) -> RedisResult<()> {
+    metrics::observe_redis("write_webhook", || {
+        write_json(&format!("webhook:{}", value.webhook_id), value)
+    })
+}
+
+#[doc = "Function for reading a Webhook from a Redis DB, None if it does not exist"]
+pub fn read_webhook(id: u64) -> RedisResult<Option<Webhook>> {
+    metrics::observe_redis("read_webhook", || {
+        read_json(&format!("webhook:{}", id))
+    })
+}
+
+#[doc = "Function for removing a Webhook and its deliveries from a Redis DB"]
+pub fn delete_webhook(id: u64) -> RedisResult<()> {
+    metrics::observe_redis("delete_webhook", || {
+        let mut conn = get_connection()?;
+        conn.del(&[format!("webhook:{}", id), format!("webhook:{}:deliveries", id)])
+    })
+}
+
+#[doc = "Function for adding a Delivery to the log of its Webhook"]
+pub fn add_webhook_delivery(delivery: &Delivery) -> RedisResult<()> {
+    metrics::observe_redis("add_webhook_delivery", || {
+        let mut conn = get_connection()?;
+        let key = format!("webhook:{}:deliveries", delivery.webhook_id);
+        match serde_json::to_string(delivery) {
+            Ok(value_str) => {
+                let _: () = conn.lpush(&key, value_str)?;
+                conn.ltrim(&key, 0, WEBHOOK_DELIVERY_LOG_LENGTH - 1)
+            },
+            Err(e) => Err((redis::ErrorKind::IoError, "", e.to_string()).into())
+        }
+    })
+}
+
+#[doc = "Function for reading the deliveries of a Webhook, most recent first"]
+pub fn read_webhook_deliveries(id: u64) -> RedisResult<Vec<Delivery>> {
+    metrics::observe_redis("read_webhook_deliveries", || {
+        let mut conn = get_connection()?;
+        let values: Vec<String> = conn.lrange(format!("webhook:{}:deliveries", id), 0, -1)?;
+        let mut deliveries = Vec::with_capacity(values.len());
+        for value in values {
+            match serde_json::from_str(&value) {
+                Ok(delivery) => deliveries.push(delivery),
+                Err(e) => return Err((redis::ErrorKind::IoError, "Could not convert json from Redis", e.to_string()).into())
             }
         }
-        Err(e) => return Err((redis::ErrorKind::IoError,
-            "Could not fetch item from Redis: ".into(), e.to_string()).into())
+        Ok(deliveries)
+    })
+}
+
+use crate::idempotency::IdempotencyRecord;
+
+fn idempotency_key(key: &str) -> String {
+    format!("idempotency:{}", key)
+}
+
+#[doc = "Function for storing an IdempotencyRecord, only if its key is not in use. Returns whether it was stored"]
+pub fn reserve_idempotency_key(key: &str, value: &IdempotencyRecord, ttl_seconds: usize) -> RedisResult<bool> {
+    metrics::observe_redis("reserve_idempotency_key", || {
+        let mut conn = get_connection()?;
+        match serde_json::to_string(value) {
+            Ok(value_str) => {
+                let reply: Option<String> = redis::cmd("SET").arg(idempotency_key(key)).arg(value_str)
+                    .arg("NX").arg("EX").arg(ttl_seconds)
+                    .query(&mut conn)?;
+                Ok(reply.is_some())
+            },
+            Err(e) => Err((redis::ErrorKind::IoError, "", e.to_string()).into())
+        }
+    })
+}
+
+#[doc = "Function for writing an IdempotencyRecord into a Redis DB, expiring after ttl_seconds"]
+pub fn write_idempotency_record(key: &str, value: &IdempotencyRecord, ttl_seconds: usize) -> RedisResult<()> {
+    metrics::observe_redis("write_idempotency_record", || {
+        let mut conn = get_connection()?;
+        match serde_json::to_string(value) {
+            Ok(value_str) => conn.set_ex(idempotency_key(key), value_str, ttl_seconds),
+            Err(e) => Err((redis::ErrorKind::IoError, "", e.to_string()).into())
+        }
+    })
+}
+
+#[doc = "Function for reading an IdempotencyRecord from a Redis DB, None if the key is unused or has expired"]
+pub fn read_idempotency_record(key: &str) -> RedisResult<Option<IdempotencyRecord>> {
+    metrics::observe_redis("read_idempotency_record", || {
+        read_json(&idempotency_key(key))
+    })
+}
+
+#[doc = "Function for removing an IdempotencyRecord from a Redis DB"]
+pub fn delete_idempotency_record(key: &str) -> RedisResult<()> {
+    metrics::observe_redis("delete_idempotency_record", || {
+        let mut conn = get_connection()?;
+        conn.del(idempotency_key(key))
+    })
+}
+
+use crate::template::SimulationTemplate;
+
+#[doc = "Function for requesting a new SimulationTemplate id from the Redis DB"]
+pub fn get_new_template_id() -> RedisResult<u64> {
+    metrics::observe_redis("get_new_template_id", || {
+        let mut conn = get_connection()?;
+        conn.incr("templates", 1)
+    })
+}
+
+pub fn get_number_of_templates() -> RedisResult<u64> {
+    metrics::observe_redis("get_number_of_templates", || {
+        let mut conn = get_connection()?;
+        let number: Option<u64> = conn.get("templates")?;
+        Ok(number.unwrap_or(0))
+    })
+}
+
+#[doc = "Function for writing a SimulationTemplate into a Redis DB"]
+pub fn write_template(value: &SimulationTemplate) -> RedisResult<()> {
+    metrics::observe_redis("write_template", || {
+        write_json(&format!("template:{}", value.template_id), value)
+    })
+}
+
+#[doc = "Function for reading a SimulationTemplate from a Redis DB, None if it does not exist"]
+pub fn read_template(id: u64) -> RedisResult<Option<SimulationTemplate>> {
+    metrics::observe_redis("read_template", || {
+        read_json(&format!("template:{}", id))
+    })
+}
+
+#[doc = "Function for removing a SimulationTemplate from a Redis DB"]
+pub fn delete_template(id: u64) -> RedisResult<()> {
+    metrics::observe_redis("delete_template", || {
+        let mut conn = get_connection()?;
+        conn.del(format!("template:{}", id))
+    })
+}
+
+#[doc = "Function for moving a Simulation between the sets of the search index"]
+pub fn update_search_index(simulation_id: u64, removed: &[String], added: &[String]) -> RedisResult<()> {
+    metrics::observe_redis("update_search_index", || {
+        let mut conn = get_connection()?;
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for key in removed {
+            pipe.srem(key, simulation_id).ignore();
+        }
+        for key in added {
+            pipe.sadd(key, simulation_id).ignore();
+        }
+        pipe.query(&mut conn)
+    })
+}
+
+#[doc = "Function for finding the Simulations that are in all of the given sets of the search index"]
+pub fn search_index(keys: &[String]) -> RedisResult<Vec<u64>> {
+    metrics::observe_redis("search_index", || {
+        let mut conn = get_connection()?;
+        let mut ids: Vec<u64> = conn.sinter(keys)?;
+        ids.sort_unstable();
+        Ok(ids)
+    })
+}
+
+#[doc = "Function for counting the Simulations in a set of the search index"]
+pub fn count_index(key: &str) -> RedisResult<u64> {
+    metrics::observe_redis("count_index", || {
+        let mut conn = get_connection()?;
+        conn.scard(key)
+    })
+}
+
+use crate::audit::AuditEntry;
+
+fn audit_key(simulation_id: Option<u64>) -> String {
+    match simulation_id {
+        Some(id) => format!("audit:simulation:{}", id),
+        None => "audit".into()
     }
 }
+
+#[doc = "Function for requesting a new AuditEntry id from the Redis DB"]
+pub fn get_new_audit_id() -> RedisResult<u64> {
+    metrics::observe_redis("get_new_audit_id", || {
+        let mut conn = get_connection()?;
+        conn.incr("audit_entries", 1)
+    })
+}
+
+#[doc = "Function for appending an AuditEntry to the audit log, and to that of its Simulation"]
+pub fn append_audit_entry(entry: &AuditEntry) -> RedisResult<()> {
+    metrics::observe_redis("append_audit_entry", || {
+        let mut conn = get_connection()?;
+        match serde_json::to_string(entry) {
+            Ok(value_str) => redis::pipe()
+                .atomic()
+                .rpush(audit_key(None), &value_str).ignore()
+                .rpush(audit_key(Some(entry.simulation_id)), &value_str).ignore()
+                .query(&mut conn),
+            Err(e) => Err((redis::ErrorKind::IoError, "", e.to_string()).into())
+        }
+    })
+}
+
+#[doc = "Function for reading the audit log, or that of one Simulation, oldest first"]
+pub fn read_audit_entries(simulation_id: Option<u64>) -> RedisResult<Vec<AuditEntry>> {
+    metrics::observe_redis("read_audit_entries", || {
+        let mut conn = get_connection()?;
+        let values: Vec<String> = conn.lrange(audit_key(simulation_id), 0, -1)?;
+        let mut entries = Vec::with_capacity(values.len());
+        for value in values {
+            match serde_json::from_str(&value) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => return Err((redis::ErrorKind::IoError, "Could not convert json from Redis", e.to_string()).into())
+            }
+        }
+        Ok(entries)
+    })
+}
+
+use crate::model::Model;
+
+#[doc = "Function for reading the latest version of a Model from the Redis DB, None if there is no such Model"]
+pub fn get_latest_model_version(name: &str) -> RedisResult<Option<u32>> {
+    metrics::observe_redis("get_latest_model_version", || {
+        let mut conn = get_connection()?;
+        conn.get(format!("model:{}:versions", name))
+    })
+}
+
+#[doc = "Function for writing a Model into a Redis DB as its next version, returning it with that version"]
+pub fn add_model_version(value: &Model) -> RedisResult<Model> {
+    metrics::observe_redis("add_model_version", || {
+        let mut conn = get_connection()?;
+        let versions_key = format!("model:{}:versions", value.name);
+        // The version is only counted once the model is written, so the latest version always exists
+        redis::transaction(&mut conn, &[&versions_key], |conn, pipe| {
+            let latest: Option<u32> = conn.get(&versions_key)?;
+            let model = Model { version: latest.unwrap_or(0) + 1, ..value.clone() };
+            let value_str = match serde_json::to_string(&model) {
+                Ok(value_str) => value_str,
+                Err(e) => return Err((redis::ErrorKind::IoError, "", e.to_string()).into())
+            };
+            let written: Option<(u32,)> = pipe
+                .set(format!("model:{}:{}", model.name, model.version), value_str).ignore()
+                .sadd("model_names", &model.name).ignore()
+                .set(&versions_key, model.version).ignore()
+                .get(&versions_key)
+                .query(conn)?;
+            Ok(written.map(|_| model))
+        })
+    })
+}
+
+#[doc = "Function for reading a version of a Model from a Redis DB, None if it does not exist"]
+pub fn read_model(name: &str, version: u32) -> RedisResult<Option<Model>> {
+    metrics::observe_redis("read_model", || {
+        read_json(&format!("model:{}:{}", name, version))
+    })
+}
+
+#[doc = "Function for reading the names of all Models, in order"]
+pub fn read_model_names() -> RedisResult<Vec<String>> {
+    metrics::observe_redis("read_model_names", || {
+        let mut conn = get_connection()?;
+        let mut names: Vec<String> = conn.smembers("model_names")?;
+        names.sort();
+        Ok(names)
+    })
+}
+
+use crate::load_profile::LoadProfile;
+
+#[doc = "Function for requesting a new LoadProfile id from the Redis DB"]
+pub fn get_new_load_profile_id() -> RedisResult<u64> {
+    metrics::observe_redis("get_new_load_profile_id", || {
+        let mut conn = get_connection()?;
+        conn.incr("load_profiles", 1)
+    })
+}
+
+pub fn get_number_of_load_profiles() -> RedisResult<u64> {
+    metrics::observe_redis("get_number_of_load_profiles", || {
+        let mut conn = get_connection()?;
+        let number: Option<u64> = conn.get("load_profiles")?;
+        Ok(number.unwrap_or(0))
+    })
+}
+
+#[doc = "Function for writing a LoadProfile into a Redis DB"]
+pub fn write_load_profile(value: &LoadProfile) -> RedisResult<()> {
+    metrics::observe_redis("write_load_profile", || {
+        write_json(&format!("load_profile:{}", value.load_profile_id), value)
+    })
+}
+
+#[doc = "Function for reading a LoadProfile from a Redis DB, None if it does not exist"]
+pub fn read_load_profile(id: u64) -> RedisResult<Option<LoadProfile>> {
+    metrics::observe_redis("read_load_profile", || {
+        read_json(&format!("load_profile:{}", id))
+    })
+}
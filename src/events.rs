@@ -0,0 +1,95 @@
+//!
+//! # Events for dynamic simulations
+//!
+//! A simulation can carry a list of events, each of which happens to
+//! one component at a given [`SimulationTime`]. The fields that an event
+//! needs depend on its kind:
+//!
+//! | Kind          | Required            | Optional                          |
+//! |---------------|---------------------|-----------------------------------|
+//! | Fault         | time, component     | clear_time, resistance            |
+//! | SwitchOpen    | time, component     |                                   |
+//! | SwitchClose   | time, component     |                                   |
+//! | LoadStep      | time, component     | active_power and/or reactive_power, at least one |
+//! | GeneratorTrip | time, component     |                                   |
+//!
+
+use serde::{ Serialize, Deserialize };
+use schemars::JsonSchema;
+use crate::simulation_time::SimulationTime;
+
+#[doc = "Enum for the various kinds of event"]
+#[derive(JsonSchema, FromFormField, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum EventKind {
+    Fault,
+    SwitchOpen,
+    SwitchClose,
+    LoadStep,
+    GeneratorTrip
+}
+
+#[doc = "Something that happens to a component during a simulation"]
+#[derive(FromForm, Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Event {
+    pub kind:           EventKind,
+    pub time:           SimulationTime,
+    pub component:      String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clear_time:     Option<SimulationTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resistance:     Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active_power:   Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reactive_power: Option<f64>
+}
+
+#[doc = "Check a single event against the final time of its simulation"]
+fn validate_event(index: usize, event: &Event, finaltime: SimulationTime) -> Result<(), String> {
+    let name = format!("Event {} ({:?})", index, event.kind);
+    if event.time > finaltime {
+        return Err(format!("{}: time {} is after finaltime {}", name, event.time, finaltime))
+    }
+    if event.component.trim().is_empty() {
+        return Err(format!("{}: component must not be empty", name))
+    }
+    let is_fault = event.kind == EventKind::Fault;
+    let is_load_step = event.kind == EventKind::LoadStep;
+    if !is_fault && (event.clear_time.is_some() || event.resistance.is_some()) {
+        return Err(format!("{}: clear_time and resistance are only allowed for a Fault", name))
+    }
+    if !is_load_step && (event.active_power.is_some() || event.reactive_power.is_some()) {
+        return Err(format!("{}: active_power and reactive_power are only allowed for a LoadStep", name))
+    }
+    if let Some(clear_time) = event.clear_time {
+        if clear_time <= event.time || clear_time > finaltime {
+            return Err(format!("{}: clear_time {} must be after time {} and not after finaltime {}",
+                               name, clear_time, event.time, finaltime))
+        }
+    }
+    if let Some(resistance) = event.resistance {
+        if !resistance.is_finite() || resistance < 0.0 {
+            return Err(format!("{}: resistance {} must not be negative", name, resistance))
+        }
+    }
+    if is_load_step {
+        let powers = [event.active_power, event.reactive_power];
+        if powers.iter().all(|power| power.is_none()) {
+            return Err(format!("{}: a LoadStep needs active_power or reactive_power", name))
+        }
+        if powers.iter().flatten().any(|power| !power.is_finite()) {
+            return Err(format!("{}: active_power and reactive_power must be finite", name))
+        }
+    }
+    Ok(())
+}
+
+#[doc = "Check a list of events and return it sorted by time"]
+pub fn validate_events(events: &[Event], finaltime: SimulationTime) -> Result<Vec<Event>, String> {
+    for (index, event) in events.iter().enumerate() {
+        validate_event(index, event, finaltime)?;
+    }
+    let mut sorted = events.to_vec();
+    sorted.sort_by_key(|event| event.time);
+    Ok(sorted)
+}
@@ -0,0 +1,213 @@
+//!
+//! # Conversion of DPsim results into other formats
+//!
+//! Every export carries the same metadata: the simulation and model it
+//! came from, the solver, domain and timestep, and the unit of the time
+//! column, which is always seconds. Where the metadata goes depends on
+//! the format:
+//!
+//! | Format  | Content type                     | Metadata                               |
+//! |---------|----------------------------------|----------------------------------------|
+//! | Csv     | text/csv                         | HTTP headers only                      |
+//! | Json    | application/json                 | a `metadata` object                    |
+//! | Ndjson  | application/x-ndjson             | the first line, then one line per row  |
+//! | Parquet | application/vnd.apache.parquet   | the key/value metadata of the file     |
+//!
+//! The HTTP headers (`X-Simulation-Id`, `X-Model-Id`, `X-Solver`, `X-Domain`,
+//! `X-Timestep`) are set for every format.
+//!
+
+use std::sync::Arc;
+use serde::{ Serialize, Deserialize };
+use serde_json::{json, Map, Value};
+use schemars::JsonSchema;
+use rocket::http::{ContentType, Header};
+use rocket::response::{self, Responder, Response};
+use rocket::Request;
+use rocket_okapi::{ OpenApiError, response::OpenApiResponderInner, gen::OpenApiGenerator };
+use okapi::openapi3::Responses;
+use parquet::basic::{Repetition, Type as PhysicalType};
+use parquet::data_type::DoubleType;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::format::KeyValue;
+use parquet::schema::types::Type;
+use crate::results::ResultsTable;
+use crate::routes::{DomainType, Simulation, SolverType};
+use crate::simulation_time::SimulationTime;
+
+#[doc = "Enum for the formats that results can be exported in"]
+#[derive(JsonSchema, FromFormField, Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Default)]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Json,
+    Ndjson,
+    Parquet
+}
+
+#[doc = "Details of the simulation that an export came from"]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ExportMetadata {
+    pub simulation_id: u64,
+    pub model_id:      String,
+    pub results_id:    String,
+    pub solver:        SolverType,
+    pub domain:        DomainType,
+    pub timestep:      SimulationTime,
+    pub time_unit:     String
+}
+
+impl ExportMetadata {
+    pub fn from_simulation(sim: &Simulation) -> ExportMetadata {
+        ExportMetadata {
+            simulation_id: sim.simulation_id,
+            model_id:      sim.model_id.clone(),
+            results_id:    sim.results_id.clone(),
+            solver:        sim.solver,
+            domain:        sim.domain,
+            timestep:      sim.timestep,
+            time_unit:     "s".into()
+        }
+    }
+
+    fn key_values(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("simulation_id", self.simulation_id.to_string()),
+            ("model_id",      self.model_id.clone()),
+            ("results_id",    self.results_id.clone()),
+            ("solver",        format!("{:?}", self.solver)),
+            ("domain",        format!("{:?}", self.domain)),
+            ("timestep",      self.timestep.as_secs_f64().to_string()),
+            ("time_unit",     self.time_unit.clone())
+        ]
+    }
+}
+
+#[doc = "Results converted into one of the export formats"]
+pub struct ExportedResults {
+    pub format:   ExportFormat,
+    pub metadata: ExportMetadata,
+    pub body:     Vec<u8>
+}
+
+fn to_csv(table: &ResultsTable) -> Vec<u8> {
+    let mut csv = String::from("time");
+    for signal in &table.signals {
+        csv.push(',');
+        csv.push_str(&signal.name);
+    }
+    csv.push('\n');
+    for (row, time) in table.time.iter().enumerate() {
+        csv.push_str(&time.to_string());
+        for signal in &table.signals {
+            csv.push(',');
+            csv.push_str(&signal.values[row].to_string());
+        }
+        csv.push('\n');
+    }
+    csv.into_bytes()
+}
+
+fn to_json(table: &ResultsTable, metadata: &ExportMetadata) -> Vec<u8> {
+    let signals: Vec<Value> = table.signals.iter()
+        .map(|signal| json!({ "name": signal.name, "values": signal.values }))
+        .collect();
+    let document = json!({
+        "metadata": metadata,
+        "time":     table.time,
+        "signals":  signals
+    });
+    serde_json::to_vec(&document).unwrap()
+}
+
+fn to_ndjson(table: &ResultsTable, metadata: &ExportMetadata) -> Vec<u8> {
+    let mut lines = vec![json!({ "metadata": metadata }).to_string()];
+    for (row, time) in table.time.iter().enumerate() {
+        let mut record = Map::new();
+        record.insert("time".into(), json!(time));
+        for signal in &table.signals {
+            record.insert(signal.name.clone(), json!(signal.values[row]));
+        }
+        lines.push(Value::Object(record).to_string());
+    }
+    let mut ndjson = lines.join("\n");
+    ndjson.push('\n');
+    ndjson.into_bytes()
+}
+
+fn to_parquet(table: &ResultsTable, metadata: &ExportMetadata) -> parquet::errors::Result<Vec<u8>> {
+    let mut columns: Vec<(&str, &[f64])> = vec![("time", &table.time)];
+    for signal in &table.signals {
+        columns.push((&signal.name, &signal.values));
+    }
+    let mut fields = Vec::with_capacity(columns.len());
+    for (name, _) in &columns {
+        fields.push(Arc::new(Type::primitive_type_builder(name, PhysicalType::DOUBLE)
+            .with_repetition(Repetition::REQUIRED)
+            .build()?));
+    }
+    let schema = Arc::new(Type::group_type_builder("results").with_fields(fields).build()?);
+    let key_values = metadata.key_values().into_iter()
+        .map(|(key, value)| KeyValue::new(key.to_string(), value))
+        .collect();
+    let properties = Arc::new(WriterProperties::builder()
+        .set_key_value_metadata(Some(key_values))
+        .build());
+
+    let mut buffer = Vec::new();
+    let mut writer = SerializedFileWriter::new(&mut buffer, schema, properties)?;
+    let mut row_group = writer.next_row_group()?;
+    for (_, values) in &columns {
+        if let Some(mut column) = row_group.next_column()? {
+            column.typed::<DoubleType>().write_batch(values, None, None)?;
+            column.close()?;
+        }
+    }
+    row_group.close()?;
+    writer.close()?;
+    Ok(buffer)
+}
+
+#[doc = "Convert a results table into the requested format"]
+pub fn export(table: &ResultsTable, metadata: ExportMetadata, format: ExportFormat) -> Result<ExportedResults, String> {
+    let body = match format {
+        ExportFormat::Csv     => to_csv(table),
+        ExportFormat::Json    => to_json(table, &metadata),
+        ExportFormat::Ndjson  => to_ndjson(table, &metadata),
+        ExportFormat::Parquet => to_parquet(table, &metadata)
+                                     .map_err(|e| format!("Could not write parquet: {}", e))?
+    };
+    Ok(ExportedResults { format, metadata, body })
+}
+
+impl ExportFormat {
+    fn content_type(&self) -> ContentType {
+        match self {
+            ExportFormat::Csv     => ContentType::CSV,
+            ExportFormat::Json    => ContentType::JSON,
+            ExportFormat::Ndjson  => ContentType::new("application", "x-ndjson"),
+            ExportFormat::Parquet => ContentType::new("application", "vnd.apache.parquet")
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ExportedResults {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build();
+        response.header(self.format.content_type());
+        response.header(Header::new("X-Simulation-Id", self.metadata.simulation_id.to_string()));
+        response.header(Header::new("X-Model-Id", self.metadata.model_id.clone()));
+        response.header(Header::new("X-Solver", format!("{:?}", self.metadata.solver)));
+        response.header(Header::new("X-Domain", format!("{:?}", self.metadata.domain)));
+        response.header(Header::new("X-Timestep", self.metadata.timestep.as_secs_f64().to_string()));
+        let len = self.body.len();
+        response.sized_body(len, std::io::Cursor::new(self.body)).ok()
+    }
+}
+
+impl OpenApiResponderInner for ExportedResults {
+    fn responses(_gen: &mut OpenApiGenerator) -> Result<Responses, OpenApiError> {
+        Ok(Responses::default())
+    }
+}
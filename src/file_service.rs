@@ -6,8 +6,53 @@ use hyper::{body::HttpBody as _, Client, Request};
 use bytes::{BytesMut,Bytes};
 use hyper_multipart::client::{multipart};
 use std::io::Cursor;
+use std::fmt;
 #[cfg(test)]
 use std::{fs::File,io::Read};
+#[cfg(not(test))]
+use crate::metrics;
+#[cfg(not(test))]
+use crate::logging;
+#[cfg(not(test))]
+use crate::telemetry;
+
+#[doc = "A request to sogno-file-service that failed, or that it refused"]
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileServiceError(pub String);
+
+impl fmt::Display for FileServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[doc = "A field of the data of a reply from sogno-file-service, or the error message it replied with"]
+pub fn reply_field(body: &[u8], field: &str) -> Result<String, FileServiceError> {
+    let body_json: serde_json::Value = match serde_json::from_slice(body) {
+        Ok(body_json) => body_json,
+        Err(e) => return Err(FileServiceError(format!("sogno-file-service replied with something that is not json: {}", e)))
+    };
+    if let Some(value) = body_json["data"][field].as_str() {
+        return Ok(value.into())
+    }
+    match body_json["error"]["message"].as_str() {
+        Some(message) => Err(FileServiceError(format!("sogno-file-service replied with an error: {}", message))),
+        None => Err(FileServiceError(format!("sogno-file-service replied without a {}: {}", field, body_json)))
+    }
+}
+
+#[cfg(not(test))]
+#[doc = "A request to sogno-file-service, carrying the id and the trace of the request being handled"]
+fn file_service_request(method: hyper::Method, uri: &str) -> http::request::Builder {
+    let mut builder = Request::builder().method(method).uri(uri);
+    if let Some(request_id) = logging::request_id() {
+        builder = builder.header(logging::REQUEST_ID_HEADER, request_id);
+    }
+    for (name, value) in telemetry::trace_headers() {
+        builder = builder.header(name, value);
+    }
+    builder
+}
 
 #[cfg(not(test))]
 pub async fn post_results_file() -> Result<Box<Bytes>, Box<dyn std::error::Error + Send + Sync>> {
@@ -15,7 +60,7 @@ pub async fn post_results_file() -> Result<Box<Bytes>, Box<dyn std::error::Error
     let mut form = multipart::Form::default();
     let bytes = Cursor::new("{\"ready\":\"false\"}");
     form.add_reader_file("file", bytes, "ready.json");
-    let req_builder = Request::post("http://sogno-file-service:8080/api/files");
+    let req_builder = file_service_request(hyper::Method::POST, "http://sogno-file-service:8080/api/files");
     let req = form.set_body_convert::<hyper::Body, multipart::Body>(req_builder)
         .unwrap();
     let mut resp = client.request(req).await?;
@@ -29,30 +74,41 @@ pub async fn post_results_file() -> Result<Box<Bytes>, Box<dyn std::error::Error
 }
 
 #[cfg(not(test))]
-pub async fn create_results_file() -> Result<String, hyper::Error>{
-    let data = post_results_file().await;
-    let file_id = match data {
-        Ok(boxed_data) => {
-            let body = std::str::from_utf8(&boxed_data).unwrap();
-            let body_json: serde_json::Value = serde_json::from_str(body).unwrap();
-            if body_json.get("data".to_string()) != None {
-                let url_str = body_json["data"]["fileID"].as_str().unwrap();
-                url_str.into()
-            }
-            else {
-                let error_str = body_json["error"]["message"].as_str().unwrap();
-                error_str.into()
-            }
-        },
-        Err(error) => {
-            error.to_string()
+pub async fn create_results_file() -> Result<String, FileServiceError>{
+    match metrics::observe_file_service("create_results_file", post_results_file()).await {
+        Ok(boxed_data) => reply_field(&boxed_data, "fileID"),
+        Err(error) => Err(FileServiceError(format!("Could not create results file: {}", error)))
+    }
+}
+
+#[cfg(not(test))]
+#[doc = "Function to add a file to sogno-file-service, returning its file ID"]
+pub async fn upload_file(file_name: &str, data: Vec<u8>) -> Result<String, String> {
+    metrics::observe_file_service("upload_file", async {
+        let client = Client::new();
+        let mut form = multipart::Form::default();
+        form.add_reader_file("file", Cursor::new(data), file_name);
+        let req_builder = file_service_request(hyper::Method::POST, "http://sogno-file-service:8080/api/files");
+        let req = form.set_body_convert::<hyper::Body, multipart::Body>(req_builder)
+            .map_err(|e| e.to_string())?;
+        let resp = client.request(req).await.map_err(|e| e.to_string())?;
+        let body = hyper::body::to_bytes(resp.into_body()).await.map_err(|e| e.to_string())?;
+        let body_json: serde_json::Value = serde_json::from_slice(&body).map_err(|e| e.to_string())?;
+        match body_json["data"]["fileID"].as_str() {
+            Some(file_id) => Ok(file_id.into()),
+            None => Err(format!("sogno-file-service did not store {}: {}", file_name, body_json["error"]["message"]))
         }
-    };
-    Ok(file_id)
+    }).await
+}
+
+#[cfg(test)]
+pub async fn upload_file(file_name: &str, data: Vec<u8>) -> Result<String, String> {
+    println!("upload_file {:?}, {} bytes", file_name, data.len());
+    Ok("101".to_string())
 }
 
 #[cfg(test)]
-pub async fn create_results_file() -> Result<String, hyper::Error>{
+pub async fn create_results_file() -> Result<String, FileServiceError>{
     let file_id:String = "100".to_string();
     Ok(file_id)
 }
@@ -60,6 +116,16 @@ pub async fn create_results_file() -> Result<String, hyper::Error>{
 #[cfg(test)]
 pub async fn get_data_from_url(url: &str) -> Result<Box<Bytes>, hyper::Error> {
     println!("get_data_from_url{:?}", url);
+    // Files with a fixture in testdata/cim or testdata/load_profiles are served from there
+    let file_id = url.trim_start_matches("http://sogno-file-service:8080/api/files/");
+    let fixtures = [format!("testdata/cim/{}.xml", file_id), format!("testdata/load_profiles/{}", file_id)];
+    if let Some(fixture) = fixtures.iter().find(|fixture| std::path::Path::new(fixture).is_file()) {
+        let body = serde_json::json!({ "data": { "fileID": file_id, "url": fixture } });
+        return Ok(Box::new(Bytes::from(body.to_string())))
+    }
+    if url.starts_with("testdata/") {
+        return Ok(Box::new(Bytes::from(std::fs::read(url).unwrap())))
+    }
     let mut f = File::open("testdata/file_service_test.json").unwrap();
     let mut buf = BytesMut::with_capacity(1024*10);
     buf.resize(2014 * 10, 0);
@@ -72,43 +138,72 @@ pub async fn get_data_from_url(url: &str) -> Result<Box<Bytes>, hyper::Error> {
 
 #[cfg(not(test))]
 pub async fn get_data_from_url(url: &str) -> Result<Box<Bytes>, hyper::Error> {
-    let client = Client::new();
-    let uri = url.parse::<hyper::Uri>().unwrap();
+    metrics::observe_file_service("get_data_from_url", async {
+        let client = Client::new();
+        let req = file_service_request(hyper::Method::GET, url)
+            .body(hyper::Body::empty())
+            .unwrap();
 
-    // Await the response...
-    let mut resp = client.get(uri).await?;
-    let body = resp.body_mut();
-    let mut buf = BytesMut::with_capacity(body.size_hint().lower() as usize);
-    while let Some(chunk) = body.data().await {
-        buf.extend_from_slice(&chunk?);
-    }
-    let frozen = buf.freeze();
-    Ok(Box::new(frozen))
+        // Await the response...
+        let mut resp = client.request(req).await?;
+        let body = resp.body_mut();
+        let mut buf = BytesMut::with_capacity(body.size_hint().lower() as usize);
+        while let Some(chunk) = body.data().await {
+            buf.extend_from_slice(&chunk?);
+        }
+        let frozen = buf.freeze();
+        Ok(Box::new(frozen))
+    }).await
 }
 
 #[doc = "Function to get a URL from sogno-file-service using a file ID"]
-pub async fn convert_id_to_url(model_id: &str) -> Result<String, hyper::Error>{
+pub async fn convert_id_to_url(model_id: &str) -> Result<String, FileServiceError>{
     let model_id_url = format!("http://sogno-file-service:8080/api/files/{}", model_id);
-    let data = get_data_from_url(&model_id_url).await;
-    let url = match data {
-        Ok(boxed_data) => {
-            let body = std::str::from_utf8(&boxed_data).unwrap();
-            let body_json: serde_json::Value = serde_json::from_str(body).unwrap();
-            if body_json.get("data".to_string()) != None {
-                let url_str = body_json["data"]["url"].as_str().unwrap();
-                url_str.into()
-            }
-            else {
-                let error_str = body_json["error"]["message"].as_str().unwrap();
-                error_str.into()
-            }
-        },
-        Err(error) => {
-            error.to_string()
+    match get_data_from_url(&model_id_url).await {
+        Ok(boxed_data) => reply_field(&boxed_data, "url"),
+        Err(error) => Err(FileServiceError(format!("Could not look up file {}: {}", model_id, error)))
+    }
+}
+
+#[cfg(not(test))]
+#[doc = "Function to remove a file from sogno-file-service using its file ID"]
+pub async fn delete_file(file_id: &str) -> Result<(), String> {
+    metrics::observe_file_service("delete_file", async {
+        let client = Client::new();
+        let req = file_service_request(hyper::Method::DELETE, &format!("http://sogno-file-service:8080/api/files/{}", file_id))
+            .body(hyper::Body::empty())
+            .map_err(|e| e.to_string())?;
+        let resp = client.request(req).await.map_err(|e| e.to_string())?;
+        if resp.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!("sogno-file-service responded with {}", resp.status()))
         }
-    };
+    }).await
+}
 
-    Ok(url)
+#[cfg(test)]
+pub async fn delete_file(file_id: &str) -> Result<(), String> {
+    println!("delete_file {:?}", file_id);
+    Ok(())
 }
 
+#[cfg(not(test))]
+#[doc = "Check that sogno-file-service answers. Any response that is not a server error will do"]
+pub async fn check_service() -> Result<(), String> {
+    let client = Client::new();
+    let req = file_service_request(hyper::Method::GET, "http://sogno-file-service:8080/api/files")
+        .body(hyper::Body::empty())
+        .map_err(|e| e.to_string())?;
+    let resp = client.request(req).await.map_err(|e| e.to_string())?;
+    if resp.status().is_server_error() {
+        Err(format!("sogno-file-service responded with {}", resp.status()))
+    } else {
+        Ok(())
+    }
+}
 
+#[cfg(test)]
+pub async fn check_service() -> Result<(), String> {
+    Ok(())
+}
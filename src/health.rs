@@ -0,0 +1,90 @@
+//!
+//! # Health probes
+//!
+//! `GET /healthz` answers as long as the process is serving requests, and
+//! is meant for a liveness probe. `GET /readyz` is meant for a readiness
+//! probe: it checks each service a simulation needs, all at once and each
+//! within [`CHECK_TIMEOUT`], and answers 503 Service Unavailable if any of
+//! them is down.
+//!
+//! | Dependency   | Check                                        |
+//! |--------------|----------------------------------------------|
+//! | redis        | `PING`                                       |
+//! | amqp         | a connection to the broker is opened         |
+//! | file_service | any response that is not a server error      |
+//!
+
+use std::future::Future;
+use std::time::{Duration, Instant};
+use serde::{ Serialize, Deserialize };
+use schemars::JsonSchema;
+use rocket::tokio::{task, time};
+use crate::amqp;
+use crate::db;
+use crate::file_service;
+
+pub const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[doc = "Reply of the liveness probe"]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Health {
+    pub status: String
+}
+
+#[doc = "The outcome of checking one dependency"]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct DependencyStatus {
+    pub name:       String,
+    pub ok:         bool,
+    pub latency_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error:      Option<String>
+}
+
+#[doc = "Reply of the readiness probe"]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Readiness {
+    pub ready:        bool,
+    pub dependencies: Vec<DependencyStatus>
+}
+
+impl Health {
+    pub fn ok() -> Health {
+        Health { status: "ok".into() }
+    }
+}
+
+async fn check<E: ToString>(name: &str, call: impl Future<Output = Result<(), E>>) -> DependencyStatus {
+    let start = Instant::now();
+    let error = match time::timeout(CHECK_TIMEOUT, call).await {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(_) => Some(format!("No answer within {} ms", CHECK_TIMEOUT.as_millis()))
+    };
+    DependencyStatus {
+        name:       name.into(),
+        ok:         error.is_none(),
+        latency_ms: start.elapsed().as_millis() as u64,
+        error
+    }
+}
+
+// The connection and the answer are bounded by the timeout themselves, so
+// that a blocking task is never left behind when the check gives up
+async fn ping_redis() -> Result<(), String> {
+    match task::spawn_blocking(|| db::ping(CHECK_TIMEOUT)).await {
+        Ok(result) => result.map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string())
+    }
+}
+
+#[doc = "Check all the dependencies"]
+pub async fn readiness() -> Readiness {
+    let (redis, amqp, file_service) = rocket::tokio::join!(
+        check("redis", ping_redis()),
+        check("amqp", amqp::check_connection()),
+        check("file_service", file_service::check_service())
+    );
+    let dependencies = vec![redis, amqp, file_service];
+    Readiness { ready: dependencies.iter().all(|dependency| dependency.ok), dependencies }
+}
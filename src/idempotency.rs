@@ -0,0 +1,187 @@
+//!
+//! # Idempotency keys
+//!
+//! A client that sends `POST /simulation` with an `Idempotency-Key` header
+//! can safely retry the request: the first request with a key creates the
+//! simulation, and its response is stored under the key. Any repeat with
+//! the same key and the same body gets that response back as it was, rather
+//! than the current state of the simulation, and no other simulation is
+//! created. A repeat with a
+//! different body is refused with 409 Conflict, as is a repeat that arrives
+//! while the first request is still being processed.
+//!
+//! Keys are remembered for [`KEY_TTL_SECONDS`] once their response is
+//! stored. While the first request is being processed, its claim on the key
+//! only lasts [`CLAIM_TTL_SECONDS`], so that a request that never finishes,
+//! because the API was stopped, does not block its key for long. A key
+//! whose request failed is forgotten straight away, so that the request can
+//! be retried.
+//!
+
+use serde::{ Serialize, Deserialize };
+use schemars::JsonSchema;
+use sha2::{Digest, Sha256};
+use rocket::http::{ContentType, Status};
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::Json;
+use rocket_okapi::{ OpenApiError, response::OpenApiResponderInner };
+use rocket_okapi::gen::OpenApiGenerator;
+use rocket_okapi::request::{OpenApiFromRequest, RequestHeaderInput};
+use okapi::openapi3::{Parameter, ParameterValue, Responses};
+use log::info;
+use crate::db;
+use crate::routes::{Simulation, SimulationError};
+use crate::template::SimulationRequest;
+
+pub const KEY_TTL_SECONDS: usize = 24 * 60 * 60;
+// A few times as long as creating a simulation can take, with every call to
+// the file service and the broker at its timeout
+pub const CLAIM_TTL_SECONDS: usize = 5 * 60;
+const MAX_KEY_LENGTH: usize = 255;
+const HEADER_NAME: &str = "Idempotency-Key";
+
+#[doc = "The value of the Idempotency-Key header, if the request has one"]
+#[derive(Debug, Clone, PartialEq)]
+pub struct IdempotencyKey(pub Option<String>);
+
+#[doc = "The response to the first request with a key, as it was sent"]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct StoredResponse {
+    pub status: u16,
+    pub body:   String
+}
+
+#[doc = "What is remembered about the first request with a key"]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct IdempotencyRecord {
+    pub request_hash:  String,
+    #[doc = "None while the first request is being processed"]
+    pub simulation_id: Option<u64>,
+    #[doc = "None while the first request is being processed"]
+    #[serde(default)]
+    pub response:      Option<StoredResponse>
+}
+
+#[doc = "The response to POST /simulation: the new simulation, or the stored response to the first request with its key"]
+#[derive(Debug)]
+pub enum CreationResponse {
+    Created(Box<Simulation>),
+    Repeated(StoredResponse)
+}
+
+impl<'r> Responder<'r, 'static> for CreationResponse {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        match self {
+            CreationResponse::Created(simulation) => Json(*simulation).respond_to(request),
+            CreationResponse::Repeated(stored) => {
+                let len = stored.body.len();
+                Response::build()
+                    .sized_body(len, std::io::Cursor::new(stored.body))
+                    .header(ContentType::JSON)
+                    .status(Status::from_code(stored.status).unwrap_or(Status::Ok))
+                    .ok()
+            }
+        }
+    }
+}
+
+impl OpenApiResponderInner for CreationResponse {
+    fn responses(gen: &mut OpenApiGenerator) -> Result<Responses, OpenApiError> {
+        Json::<Simulation>::responses(gen)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IdempotencyKey {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        request::Outcome::Success(IdempotencyKey(request.headers().get_one(HEADER_NAME).map(String::from)))
+    }
+}
+
+impl<'r> OpenApiFromRequest<'r> for IdempotencyKey {
+    fn from_request_input(gen: &mut OpenApiGenerator, _name: String, _required: bool)
+        -> rocket_okapi::Result<RequestHeaderInput> {
+        Ok(RequestHeaderInput::Parameter(Parameter {
+            name:              HEADER_NAME.into(),
+            location:          "header".into(),
+            description:       Some("Repeating a request with the same key returns the original simulation".into()),
+            required:          false,
+            deprecated:        false,
+            allow_empty_value: false,
+            value:             ParameterValue::Schema {
+                style:          None,
+                explode:        None,
+                allow_reserved: false,
+                schema:         gen.json_schema::<String>(),
+                example:        None,
+                examples:       None
+            },
+            extensions:        Default::default()
+        }))
+    }
+}
+
+#[doc = "A digest of a simulation request, for telling repeated requests apart from different ones"]
+pub fn request_hash(request: &SimulationRequest) -> String {
+    let body = serde_json::to_vec(request).unwrap_or_default();
+    hex::encode(Sha256::digest(&body))
+}
+
+fn validate_key(key: &str) -> Result<(), String> {
+    if key.trim().is_empty() {
+        return Err(format!("{} must not be empty", HEADER_NAME))
+    }
+    if key.len() > MAX_KEY_LENGTH {
+        return Err(format!("{} must not be longer than {} characters", HEADER_NAME, MAX_KEY_LENGTH))
+    }
+    Ok(())
+}
+
+#[doc = "Claim a key for a request. Returns the stored response if the request has already been made"]
+pub fn begin(key: &str, request_hash: &str) -> Result<Option<StoredResponse>, SimulationError> {
+    if let Err(e) = validate_key(key) {
+        return Err(SimulationError { err: e, http_status_code: Status::BadRequest })
+    }
+    let claim = IdempotencyRecord { request_hash: request_hash.into(), simulation_id: None, response: None };
+    let existing = match db::reserve_idempotency_key(key, &claim, CLAIM_TTL_SECONDS) {
+        Ok(true) => return Ok(None),
+        Ok(false) => db::read_idempotency_record(key),
+        Err(e) => Err(e)
+    };
+    match existing {
+        Ok(Some(record)) if record.request_hash != request_hash => Err(SimulationError {
+            err: format!("{} '{}' has already been used for a different request", HEADER_NAME, key),
+            http_status_code: Status::Conflict
+        }),
+        Ok(Some(IdempotencyRecord { response: Some(response), .. })) => Ok(Some(response)),
+        Ok(_) => Err(SimulationError {
+            err: format!("A request with {} '{}' is still being processed", HEADER_NAME, key),
+            http_status_code: Status::Conflict
+        }),
+        Err(e) => Err(SimulationError {
+            err: format!("Could not check {} in db: {}", HEADER_NAME, e),
+            http_status_code: Status::BadGateway
+        })
+    }
+}
+
+#[doc = "Store the response to the request a key created a simulation for, or forget the key if the request failed"]
+pub fn finish(key: &str, request_hash: &str, simulation: Option<&Simulation>) {
+    let result = match simulation {
+        Some(simulation) => match serde_json::to_string(simulation) {
+            Ok(body) => db::write_idempotency_record(key, &IdempotencyRecord {
+                request_hash:  request_hash.into(),
+                simulation_id: Some(simulation.simulation_id),
+                response:      Some(StoredResponse { status: Status::Ok.code, body })
+            }, KEY_TTL_SECONDS),
+            Err(e) => Err((redis::ErrorKind::IoError, "Could not convert response to json", e.to_string()).into())
+        },
+        None => db::delete_idempotency_record(key)
+    };
+    if let Err(e) = result {
+        info!("Could not update {} '{}': {}", HEADER_NAME, key, e);
+    }
+}
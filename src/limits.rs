@@ -0,0 +1,155 @@
+//!
+//! # Limits and limit violations
+//!
+//! Signals in the DPsim results are matched to limits by the last part
+//! of their name:
+//! * `<component>.v` is a voltage magnitude in per unit
+//! * `<component>.i` is a current magnitude in A
+//! * `<component>.loading` is a loading in per unit of the thermal rating
+//!
+//! The voltage band and the loading limit apply to every component,
+//! unless a [`ComponentLimit`] for that component replaces them. Currents
+//! are only checked for components that have a `current_max`.
+//!
+
+use serde::{ Serialize, Deserialize };
+use schemars::JsonSchema;
+use crate::results::ResultsTable;
+
+#[doc = "Limits that replace the defaults for a single component"]
+#[derive(FromForm, Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ComponentLimit {
+    pub component:   String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub voltage_min: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub voltage_max: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub current_max: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loading_max: Option<f64>
+}
+
+#[doc = "Limits used when checking results for violations"]
+#[derive(FromForm, Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct Limits {
+    pub voltage_min: f64,
+    pub voltage_max: f64,
+    pub loading_max: f64,
+    pub components:  Vec<ComponentLimit>
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            voltage_min: 0.9,
+            voltage_max: 1.1,
+            loading_max: 1.0,
+            components:  Vec::new()
+        }
+    }
+}
+
+#[doc = "Enum for the kinds of limit violation"]
+#[derive(JsonSchema, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum ViolationKind {
+    Undervoltage,
+    Overvoltage,
+    Overcurrent,
+    Overload
+}
+
+#[doc = "The worst violation of a limit by a single signal"]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Violation {
+    pub signal: String,
+    pub kind:   ViolationKind,
+    pub value:  f64,
+    pub limit:  f64,
+    pub time:   f64
+}
+
+impl Violation {
+    #[doc = "How far beyond its limit the signal went"]
+    pub fn excess(&self) -> f64 {
+        (self.value - self.limit).abs()
+    }
+}
+
+#[doc = "Check that the limits describe a usable voltage band and are not negative"]
+pub fn validate_limits(limits: &Limits) -> Result<(), String> {
+    let check_band = |name: &str, min: f64, max: f64| {
+        if !(min.is_finite() && max.is_finite() && 0.0 <= min && min < max) {
+            return Err(format!("{}: voltage_min {} and voltage_max {} are not a valid band", name, min, max))
+        }
+        Ok(())
+    };
+    check_band("limits", limits.voltage_min, limits.voltage_max)?;
+    if !(limits.loading_max.is_finite() && limits.loading_max > 0.0) {
+        return Err(format!("limits: loading_max {} must be greater than zero", limits.loading_max))
+    }
+    for component in &limits.components {
+        if component.component.trim().is_empty() {
+            return Err("A component limit has an empty component".into())
+        }
+        check_band(&component.component,
+                   component.voltage_min.unwrap_or(limits.voltage_min),
+                   component.voltage_max.unwrap_or(limits.voltage_max))?;
+        for limit in [component.current_max, component.loading_max].iter().flatten() {
+            if !(limit.is_finite() && *limit > 0.0) {
+                return Err(format!("{}: limit {} must be greater than zero", component.component, limit))
+            }
+        }
+    }
+    Ok(())
+}
+
+#[doc = "The checks that apply to a signal, from its name"]
+fn checks_for(name: &str, limits: &Limits) -> Vec<(ViolationKind, f64)> {
+    let (component, quantity) = match name.rsplit_once('.') {
+        Some(parts) => parts,
+        None => return Vec::new()
+    };
+    let component_limit = limits.components.iter().find(|limit| limit.component == component);
+    let limit_or = |select: fn(&ComponentLimit) -> Option<f64>, default: f64| {
+        component_limit.and_then(select).unwrap_or(default)
+    };
+    match quantity {
+        "v" => vec![(ViolationKind::Undervoltage, limit_or(|c| c.voltage_min, limits.voltage_min)),
+                    (ViolationKind::Overvoltage,  limit_or(|c| c.voltage_max, limits.voltage_max))],
+        "i" => match component_limit.and_then(|c| c.current_max) {
+            Some(current_max) => vec![(ViolationKind::Overcurrent, current_max)],
+            None => Vec::new()
+        },
+        "loading" => vec![(ViolationKind::Overload, limit_or(|c| c.loading_max, limits.loading_max))],
+        _ => Vec::new()
+    }
+}
+
+#[doc = "Find the worst violation of each limit by each signal in a results table"]
+pub fn find_violations(table: &ResultsTable, limits: &Limits) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    for signal in &table.signals {
+        for (kind, limit) in checks_for(&signal.name, limits) {
+            let mut worst: Option<Violation> = None;
+            for (time, value) in table.time.iter().zip(signal.values.iter()) {
+                let exceeded = match kind {
+                    ViolationKind::Undervoltage => *value < limit,
+                    _ => *value > limit
+                };
+                if !exceeded {
+                    continue
+                }
+                let candidate = Violation { signal: signal.name.clone(), kind, value: *value, limit, time: *time };
+                if worst.as_ref().is_none_or(|w| candidate.excess() > w.excess()) {
+                    worst = Some(candidate);
+                }
+            }
+            if let Some(violation) = worst {
+                violations.push(violation);
+            }
+        }
+    }
+    violations
+}
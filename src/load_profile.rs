@@ -0,0 +1,513 @@
+//!
+//! # Load profile registry
+//!
+//! A load profile gives the active and reactive power of some of the loads
+//! of a model over time. It is either a single CSV file, or a zip of CSV
+//! files as read by DPsim's CSVReader, one per load and named after it. The
+//! first column of every CSV file is the time, as a number of seconds, a
+//! time of day ("14:30:00") or a date and time ("2022-04-27 14:30:00").
+//!
+//! | Format     | Load ids                                                    |
+//! |------------|-------------------------------------------------------------|
+//! | Zip        | the name of each CSV file, without the extension            |
+//! | Single CSV | the other columns, without a `_p` or `_q` suffix            |
+//!
+//! When a profile is registered, its format, time resolution, covered time
+//! range and load ids are detected and stored with it. A simulation that
+//! refers to a registered profile is checked against it: every load id of
+//! the profile must be a load of the model, by name or mRID, and the
+//! profile must cover the simulation up to its `finaltime`.
+//!
+//! A simulation without a load profile has no `load_profile_id`. Records
+//! and requests from before it was optional use the string "None", or an
+//! empty string, instead; both read back as no load profile, and records
+//! are written back without them the next time they change.
+//!
+
+use std::collections::BTreeSet;
+use std::io::{Cursor, Read};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{ Serialize, Deserialize, Deserializer };
+use schemars::JsonSchema;
+use rocket::http::Status;
+use rocket::data::{self, Data, FromData, Limits};
+use rocket::request::Request;
+use rocket_okapi::gen::OpenApiGenerator;
+use rocket_okapi::request::OpenApiFromData;
+use okapi::openapi3::RequestBody;
+use chrono::{DateTime, NaiveDateTime, NaiveTime, Timelike};
+use crate::cim::{self, CimLoad, Severity};
+use crate::db;
+use crate::file_service;
+use crate::model;
+use crate::routes::SimulationError;
+use crate::simulation_time::SimulationTime;
+
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const DATE_TIME_FORMATS: [&str; 4] = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M", "%d.%m.%Y %H:%M:%S"];
+const LOAD_SUFFIXES: [&str; 4] = ["_p", "_q", "_P", "_Q"];
+const DEFAULT_PREVIEW_ROWS: usize = 10;
+const MAX_PREVIEW_ROWS: usize = 1000;
+// A few kilobytes of zip can decompress to gigabytes, so the CSV files are read up to these sizes
+pub const MAX_UNCOMPRESSED_FILE: u64 = 64 * 1024 * 1024;
+pub const MAX_UNCOMPRESSED_TOTAL: u64 = 256 * 1024 * 1024;
+
+#[doc = "How the files of a load profile are packed"]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum LoadProfileFormat {
+    Csv,
+    Zip
+}
+
+#[doc = "How the times of a load profile are written"]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum TimeFormat {
+    #[doc = "A number of seconds, e.g. 900"]
+    Seconds,
+    #[doc = "A time of day, e.g. 14:30:00"]
+    TimeOfDay,
+    #[doc = "A date and time, e.g. 2022-04-27 14:30:00"]
+    DateTime
+}
+
+/// # Form for registering a load profile that is in the file service
+///
+/// ## Parameters:
+/// * name
+///   - String, must not be empty
+/// * description
+///   - String, optional
+/// * file_id
+///   - the id of the CSV or zip file in the sogno file service
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct LoadProfileForm {
+    pub name:        String,
+    #[serde(default)]
+    pub description: String,
+    pub file_id:     String
+}
+
+#[doc = "What was detected in the file of a load profile"]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct LoadProfileAnalysis {
+    pub format:      LoadProfileFormat,
+    pub time_format: TimeFormat,
+    #[doc = "The CSV files in a zip, empty for a single CSV file"]
+    pub files:       Vec<String>,
+    pub load_ids:    Vec<String>,
+    #[doc = "The largest time between two samples"]
+    pub resolution:  SimulationTime,
+    #[doc = "The first time, as written in the file"]
+    pub start:       String,
+    #[doc = "The last time, as written in the file"]
+    pub end:         String,
+    #[doc = "The time covered by every load of the profile"]
+    pub duration:    SimulationTime,
+    #[doc = "The number of samples of the longest file"]
+    pub rows:        usize
+}
+
+#[doc = "A registered load profile"]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct LoadProfile {
+    pub load_profile_id: u64,
+    pub name:            String,
+    #[serde(default)]
+    pub description:     String,
+    pub file_id:         String,
+    pub analysis:        LoadProfileAnalysis,
+    pub created_by:      String,
+    #[doc = "Seconds since the Unix epoch"]
+    pub created_at:      u64
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[doc = "Struct for encapsulation a list of LoadProfiles"]
+pub struct LoadProfileArray {
+    pub load_profiles: Vec<LoadProfile>
+}
+
+#[doc = "The first rows of one CSV file of a load profile"]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct PreviewTable {
+    #[doc = "The name of the CSV file in a zip, empty for a single CSV file"]
+    pub file:    String,
+    pub columns: Vec<String>,
+    pub rows:    Vec<Vec<String>>
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[doc = "The first rows of every CSV file of a load profile"]
+pub struct LoadProfilePreview {
+    pub tables: Vec<PreviewTable>
+}
+
+#[doc = "A problem with using a load profile for a simulation"]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct LoadProfileProblem {
+    pub severity: Severity,
+    pub message:  String
+}
+
+#[doc = "The result of checking a load profile against a model and a final time"]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct LoadProfileCheck {
+    #[doc = "true if no problem is an error"]
+    pub consistent: bool,
+    pub problems:   Vec<LoadProfileProblem>
+}
+
+impl LoadProfileCheck {
+    #[doc = "The errors, one per line, for an error response"]
+    pub fn errors(&self) -> String {
+        self.problems.iter()
+            .filter(|problem| problem.severity == Severity::Error)
+            .map(|problem| problem.message.clone())
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+#[doc = "The load profile file of a simulation"]
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadProfileFile {
+    #[doc = "The registered load profile, if the simulation refers to one"]
+    pub load_profile: Option<LoadProfile>,
+    pub file_id:      Option<String>
+}
+
+#[doc = "The body of a load profile upload, read up to the \"file\" limit of Rocket.toml rather than the 8KiB \"bytes\" limit"]
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadProfileUpload(pub Vec<u8>);
+
+#[rocket::async_trait]
+impl<'r> FromData<'r> for LoadProfileUpload {
+    type Error = String;
+
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let limit = request.limits().get("file").unwrap_or(Limits::FILE);
+        match data.open(limit).into_bytes().await {
+            Ok(bytes) if bytes.is_complete() => data::Outcome::Success(LoadProfileUpload(bytes.into_inner())),
+            Ok(_) => data::Outcome::Failure((Status::PayloadTooLarge,
+                                             format!("Load profile is larger than {}", limit))),
+            Err(e) => data::Outcome::Failure((Status::BadRequest, format!("Could not read load profile: {}", e)))
+        }
+    }
+}
+
+impl<'r> OpenApiFromData<'r> for LoadProfileUpload {
+    fn request_body(gen: &mut OpenApiGenerator) -> rocket_okapi::Result<RequestBody> {
+        Vec::<u8>::request_body(gen)
+    }
+}
+
+#[doc = "Read a load_profile_id, taking \"None\" and empty strings, as stored before it was optional, as no id"]
+pub fn deserialize_file_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    let file_id = Option::<String>::deserialize(deserializer)?;
+    Ok(file_id.filter(|file_id| !["", "None"].contains(&file_id.trim())))
+}
+
+#[doc = "One CSV file of a load profile, split into fields"]
+struct Table {
+    file:    String,
+    columns: Vec<String>,
+    rows:    Vec<Vec<String>>
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[doc = "Split a CSV file into its header and rows, with ',' or ';' between fields"]
+fn split_csv(file: &str, text: &str) -> Result<Table, String> {
+    let mut lines = text.lines().map(str::trim).filter(|line| !line.is_empty());
+    let header = match lines.next() {
+        Some(header) => header,
+        None => return Err(format!("{} is empty", describe(file)))
+    };
+    let delimiter = if header.contains(';') && !header.contains(',') { ';' } else { ',' };
+    let columns: Vec<String> = header.split(delimiter).map(|column| column.trim().to_string()).collect();
+    if columns.len() < 2 {
+        return Err(format!("{} has no columns besides the time: {}", describe(file), header))
+    }
+    let mut rows = Vec::new();
+    for (index, line) in lines.enumerate() {
+        let fields: Vec<String> = line.split(delimiter).map(|field| field.trim().to_string()).collect();
+        if fields.len() != columns.len() {
+            return Err(format!("Line {} of {} has {} columns, expected {}", index + 2, describe(file), fields.len(), columns.len()))
+        }
+        rows.push(fields);
+    }
+    Ok(Table { file: file.into(), columns, rows })
+}
+
+fn describe(file: &str) -> String {
+    if file.is_empty() { "the load profile".into() } else { file.into() }
+}
+
+#[doc = "The CSV files of a load profile, from a single CSV file or a zip of them"]
+fn read_tables(data: &[u8]) -> Result<(LoadProfileFormat, Vec<Table>), String> {
+    if !data.starts_with(ZIP_MAGIC) {
+        let text = std::str::from_utf8(data).map_err(|_| "Load profile is neither a zip nor a UTF-8 CSV file".to_string())?;
+        return Ok((LoadProfileFormat::Csv, vec![split_csv("", text)?]))
+    }
+    let mut archive = zip::ZipArchive::new(Cursor::new(data)).map_err(|e| format!("Could not read zip: {}", e))?;
+    let mut tables = Vec::new();
+    let mut total = 0;
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index).map_err(|e| format!("Could not read zip: {}", e))?;
+        let name = entry.name().to_string();
+        if entry.is_dir() || name.starts_with("__MACOSX/") || !name.to_lowercase().ends_with(".csv") {
+            continue
+        }
+        if entry.size() > MAX_UNCOMPRESSED_FILE {
+            return Err(format!("{} is larger than {} bytes uncompressed", name, MAX_UNCOMPRESSED_FILE))
+        }
+        // The sizes in the zip are not to be trusted, so read at most one byte past the limits
+        let limit = MAX_UNCOMPRESSED_FILE.min(MAX_UNCOMPRESSED_TOTAL - total);
+        let mut text = String::new();
+        entry.by_ref().take(limit + 1).read_to_string(&mut text)
+            .map_err(|e| format!("Could not read {} from zip: {}", name, e))?;
+        if text.len() as u64 > limit {
+            return Err(format!("{} is larger than {} bytes uncompressed, or the zip is larger than {} bytes in total",
+                               name, MAX_UNCOMPRESSED_FILE, MAX_UNCOMPRESSED_TOTAL))
+        }
+        total += text.len() as u64;
+        tables.push(split_csv(&name, &text)?);
+    }
+    if tables.is_empty() {
+        return Err("The zip holds no CSV files".into())
+    }
+    Ok((LoadProfileFormat::Zip, tables))
+}
+
+#[doc = "A time of a load profile in seconds, and how it is written"]
+fn parse_time(value: &str) -> Option<(f64, TimeFormat)> {
+    if let Ok(seconds) = value.parse::<f64>() {
+        return Some((seconds, TimeFormat::Seconds))
+    }
+    if let Ok(time) = NaiveTime::parse_from_str(value, "%H:%M:%S") {
+        return Some((time.num_seconds_from_midnight() as f64, TimeFormat::TimeOfDay))
+    }
+    if let Ok(date_time) = DateTime::parse_from_rfc3339(value) {
+        return Some((date_time.timestamp() as f64, TimeFormat::DateTime))
+    }
+    DATE_TIME_FORMATS.iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+        .map(|date_time| (date_time.and_utc().timestamp() as f64, TimeFormat::DateTime))
+}
+
+fn to_simulation_time(seconds: f64) -> SimulationTime {
+    SimulationTime::from_nanos((seconds * 1e9).round() as u64)
+}
+
+#[doc = "The load id of a column of a single CSV file"]
+fn column_load_id(column: &str) -> &str {
+    LOAD_SUFFIXES.iter()
+        .find_map(|suffix| column.strip_suffix(suffix))
+        .unwrap_or(column)
+}
+
+#[doc = "The load id of a CSV file in a zip"]
+fn file_load_id(file: &str) -> &str {
+    let name = file.rsplit('/').next().unwrap_or(file);
+    &name[..name.len() - ".csv".len()]
+}
+
+#[doc = "Detect the format, times and loads of the contents of a load profile file"]
+pub fn analyse(data: &[u8]) -> Result<LoadProfileAnalysis, String> {
+    let (format, tables) = read_tables(data)?;
+    let mut time_format = None;
+    let mut resolution = 0.0_f64;
+    let mut duration = f64::INFINITY;
+    let mut start = String::new();
+    let mut end = String::new();
+    let mut rows = 0;
+    let mut load_ids = BTreeSet::new();
+    for table in &tables {
+        if table.rows.len() < 2 {
+            return Err(format!("{} needs at least two rows", describe(&table.file)))
+        }
+        let mut times = Vec::with_capacity(table.rows.len());
+        for (index, row) in table.rows.iter().enumerate() {
+            let (time, row_format) = match parse_time(&row[0]) {
+                Some(time) => time,
+                None => return Err(format!("Line {} of {}: could not read the time '{}'", index + 2, describe(&table.file), row[0]))
+            };
+            if *time_format.get_or_insert(row_format) != row_format {
+                return Err(format!("Line {} of {}: the time '{}' is not written like the others", index + 2, describe(&table.file), row[0]))
+            }
+            if let Some(field) = row[1..].iter().find(|field| field.parse::<f64>().is_err()) {
+                return Err(format!("Line {} of {}: '{}' is not a number", index + 2, describe(&table.file), field))
+            }
+            times.push(time);
+        }
+        for (index, pair) in times.windows(2).enumerate() {
+            if pair[1] <= pair[0] {
+                return Err(format!("Line {} of {}: the times must increase", index + 3, describe(&table.file)))
+            }
+            resolution = resolution.max(pair[1] - pair[0]);
+        }
+        duration = duration.min(times[times.len() - 1] - times[0]);
+        if start.is_empty() {
+            start = table.rows[0][0].clone();
+            end = table.rows[table.rows.len() - 1][0].clone();
+        }
+        rows = rows.max(table.rows.len());
+        match format {
+            LoadProfileFormat::Zip => { load_ids.insert(file_load_id(&table.file).to_string()); },
+            LoadProfileFormat::Csv => load_ids.extend(table.columns[1..].iter().map(|column| column_load_id(column).to_string()))
+        }
+    }
+    Ok(LoadProfileAnalysis {
+        format,
+        time_format: time_format.unwrap_or(TimeFormat::Seconds),
+        files:       match format {
+            LoadProfileFormat::Zip => tables.iter().map(|table| table.file.clone()).collect(),
+            LoadProfileFormat::Csv => Vec::new()
+        },
+        load_ids:    load_ids.into_iter().collect(),
+        resolution:  to_simulation_time(resolution),
+        start,
+        end,
+        duration:    to_simulation_time(duration),
+        rows
+    })
+}
+
+#[doc = "The first rows of every CSV file of a load profile"]
+pub fn preview(data: &[u8], rows: Option<usize>) -> Result<LoadProfilePreview, String> {
+    let rows = rows.unwrap_or(DEFAULT_PREVIEW_ROWS);
+    if rows > MAX_PREVIEW_ROWS {
+        return Err(format!("rows must not be more than {}", MAX_PREVIEW_ROWS))
+    }
+    let (_, tables) = read_tables(data)?;
+    Ok(LoadProfilePreview {
+        tables: tables.into_iter().map(|table| PreviewTable {
+            file:    table.file,
+            columns: table.columns,
+            rows:    table.rows.into_iter().take(rows).collect()
+        }).collect()
+    })
+}
+
+#[doc = "Check a load profile against the loads of a model and the final time of a simulation"]
+pub fn check(analysis: &LoadProfileAnalysis, loads: &[CimLoad], finaltime: Option<SimulationTime>) -> LoadProfileCheck {
+    let mut problems = Vec::new();
+    for load_id in &analysis.load_ids {
+        if !loads.iter().any(|load| load.is(load_id)) {
+            problems.push(LoadProfileProblem {
+                severity: Severity::Error,
+                message:  format!("Load {} of the profile is not a load of the model", load_id)
+            });
+        }
+    }
+    if let Some(finaltime) = finaltime {
+        if analysis.duration < finaltime {
+            problems.push(LoadProfileProblem {
+                severity: Severity::Error,
+                message:  format!("The profile covers {}, less than the finaltime {}", analysis.duration, finaltime)
+            });
+        }
+    }
+    for load in loads {
+        if !analysis.load_ids.iter().any(|load_id| load.is(load_id)) {
+            problems.push(LoadProfileProblem {
+                severity: Severity::Warning,
+                message:  format!("Load {} of the model has no profile", load.name.as_deref().unwrap_or(&load.mrid))
+            });
+        }
+    }
+    LoadProfileCheck {
+        consistent: problems.iter().all(|problem| problem.severity == Severity::Warning),
+        problems
+    }
+}
+
+#[doc = "Read the contents of a load profile file from the file service"]
+pub async fn read_data(file_id: &str) -> Result<Vec<u8>, SimulationError> {
+    let url = file_service::convert_id_to_url(file_id).await?;
+    match file_service::get_data_from_url(&url).await {
+        Ok(data) => Ok(data.to_vec()),
+        Err(e) => Err(SimulationError {
+            err: format!("Could not read load profile file {}: {}", file_id, e),
+            http_status_code: Status::BadGateway
+        })
+    }
+}
+
+#[doc = "Register a load profile, from its file id and contents"]
+pub fn register(form: &LoadProfileForm, data: &[u8], created_by: &str) -> Result<LoadProfile, SimulationError> {
+    if form.name.trim().is_empty() {
+        return Err(SimulationError { err: "name must not be empty".into(), http_status_code: Status::BadRequest })
+    }
+    let analysis = match analyse(data) {
+        Ok(analysis) => analysis,
+        Err(e) => return Err(SimulationError { err: e, http_status_code: Status::BadRequest })
+    };
+    let load_profile_id = match db::get_new_load_profile_id() {
+        Ok(id) => id,
+        Err(e) => return Err(SimulationError {
+            err: format!("Failed to obtain new load profile id: {}", e),
+            http_status_code: Status::BadGateway
+        })
+    };
+    let load_profile = LoadProfile {
+        load_profile_id,
+        name:        form.name.clone(),
+        description: form.description.clone(),
+        file_id:     form.file_id.clone(),
+        analysis,
+        created_by:  created_by.into(),
+        created_at:  now()
+    };
+    match db::write_load_profile(&load_profile) {
+        Ok(()) => Ok(load_profile),
+        Err(e) => Err(SimulationError { err: format!("Could not write to db: {}", e), http_status_code: Status::BadGateway })
+    }
+}
+
+#[doc = "Read a registered load profile"]
+pub fn read(id: u64) -> Result<LoadProfile, SimulationError> {
+    match db::read_load_profile(id) {
+        Ok(Some(load_profile)) => Ok(load_profile),
+        Ok(None) => Err(SimulationError { err: format!("Load profile {} does not exist", id), http_status_code: Status::NotFound }),
+        Err(e) => Err(SimulationError { err: e.to_string(), http_status_code: Status::UnprocessableEntity })
+    }
+}
+
+#[doc = "Read all registered load profiles"]
+pub fn read_all() -> Result<Vec<LoadProfile>, SimulationError> {
+    let result = db::get_number_of_load_profiles().and_then(|number| {
+        let mut load_profiles = Vec::new();
+        for id in 1..=number {
+            load_profiles.extend(db::read_load_profile(id)?);
+        }
+        Ok(load_profiles)
+    });
+    result.map_err(|e| SimulationError { err: e.to_string(), http_status_code: Status::UnprocessableEntity })
+}
+
+#[doc = "The load profile file a simulation refers to, by load_profile_id or by load_profile"]
+pub fn resolve(load_profile_id: Option<&str>, load_profile: Option<u64>) -> Result<LoadProfileFile, SimulationError> {
+    let id = match load_profile {
+        Some(id) => id,
+        None => return Ok(LoadProfileFile { load_profile: None, file_id: load_profile_id.map(String::from) })
+    };
+    let load_profile = read(id)?;
+    // A rerun gives both, the file id being that of the registered profile
+    if load_profile_id.is_some_and(|file_id| file_id != load_profile.file_id) {
+        return Err(SimulationError {
+            err: "A simulation needs either a load_profile_id or a load_profile, and not both".into(),
+            http_status_code: Status::BadRequest
+        })
+    }
+    Ok(LoadProfileFile { file_id: Some(load_profile.file_id.clone()), load_profile: Some(load_profile) })
+}
+
+#[doc = "Check a load profile against the model files and final time of a simulation"]
+pub async fn check_model(load_profile: &LoadProfile, model_file_ids: &[String], finaltime: Option<SimulationTime>)
+    -> Result<LoadProfileCheck, SimulationError> {
+    let files = model::read_files(model_file_ids).await?;
+    Ok(check(&load_profile.analysis, &cim::loads(&files), finaltime))
+}
@@ -0,0 +1,260 @@
+//!
+//! # Logging
+//!
+//! [`init`] installs the logger, before Rocket would install its own. It is
+//! configured from the environment:
+//!
+//! | Variable     | Values                                  | Default |
+//! |--------------|-----------------------------------------|---------|
+//! | `RUST_LOG`   | off, error, warn, info, debug, trace    | info    |
+//! | `LOG_FORMAT` | text, json (one object per line)        | text    |
+//!
+//! Below debug, only warnings and errors are logged from the libraries
+//! that talk to the other services, such as hyper and lapin.
+//!
+//! Every request has an id, taken from its `X-Request-Id` header or else
+//! generated, and sent back in the `X-Request-Id` header of the response.
+//! While a request is handled, every log line carries its id, and so do
+//! the requests made to the sogno file service and the jobs published to
+//! the AMQP broker. Log lines about a simulation, whether from a
+//! `/simulation/<id>` route or while one is being created, also carry its
+//! `simulation_id`.
+//!
+
+use std::cell::Cell;
+use std::future::Future;
+use std::io::Write;
+use std::str::FromStr;
+use chrono::{SecondsFormat, Utc};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use rocket::{Data, Request, Response, Route};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::route::{Handler, Outcome};
+use serde_json::json;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+// Targets that are logged at every level; those of other crates only from debug
+const OWN_TARGETS: [&str; 3] = ["dpsim_api", "rocket", "_"];
+
+#[doc = "How log lines are written"]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json
+}
+
+#[doc = "What is known about the request being handled"]
+#[derive(Debug)]
+pub struct LogContext {
+    request_id:    String,
+    simulation_id: Cell<Option<u64>>
+}
+
+rocket::tokio::task_local! {
+    static CONTEXT: LogContext;
+}
+
+impl LogContext {
+    pub fn new(request_id: String, simulation_id: Option<u64>) -> LogContext {
+        LogContext { request_id, simulation_id: Cell::new(simulation_id) }
+    }
+}
+
+#[doc = "Run a future with a log context"]
+pub async fn in_context<F: Future>(context: LogContext, future: F) -> F::Output {
+    CONTEXT.scope(context, future).await
+}
+
+#[doc = "Wrap a future, such as one to be spawned, so that it keeps the current log context"]
+pub async fn in_current_context<F: Future>(future: F) -> F::Output {
+    match CONTEXT.try_with(|context| LogContext::new(context.request_id.clone(), context.simulation_id.get())) {
+        Ok(context) => in_context(context, future).await,
+        Err(_) => future.await
+    }
+}
+
+#[doc = "The id of the request being handled"]
+pub fn request_id() -> Option<String> {
+    CONTEXT.try_with(|context| context.request_id.clone()).ok()
+}
+
+#[doc = "The simulation that the request being handled is about"]
+pub fn simulation_id() -> Option<u64> {
+    CONTEXT.try_with(|context| context.simulation_id.get()).ok().flatten()
+}
+
+#[doc = "Attach a simulation to the log lines of the request being handled"]
+pub fn set_simulation_id(id: u64) {
+    let _ = CONTEXT.try_with(|context| context.simulation_id.set(Some(id)));
+}
+
+#[doc = "A new request id"]
+pub fn generate_request_id() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+fn valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LENGTH && id.chars().all(|c| c.is_ascii_graphic())
+}
+
+// Rocket colours some of its messages
+fn strip_colours(message: &str) -> String {
+    let mut stripped = String::with_capacity(message.len());
+    let mut chars = message.chars();
+    while let Some(c) = chars.next() {
+        if c == '\u{1b}' {
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+#[doc = "A log line, in the given format"]
+pub fn format_line(format: LogFormat, level: Level, target: &str, message: &str,
+                   request_id: Option<&str>, simulation_id: Option<u64>) -> String {
+    let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+    let message = strip_colours(message);
+    match format {
+        LogFormat::Json => {
+            let mut line = json!({
+                "timestamp": timestamp,
+                "level":     level.as_str(),
+                "target":    target,
+                "message":   message.trim()
+            });
+            if let Some(request_id) = request_id {
+                line["request_id"] = json!(request_id);
+            }
+            if let Some(simulation_id) = simulation_id {
+                line["simulation_id"] = json!(simulation_id);
+            }
+            line.to_string()
+        },
+        LogFormat::Text => {
+            let mut line = format!("{} {:<5} {}", timestamp, level, target);
+            if let Some(request_id) = request_id {
+                line.push_str(&format!(" request_id={}", request_id));
+            }
+            if let Some(simulation_id) = simulation_id {
+                line.push_str(&format!(" simulation_id={}", simulation_id));
+            }
+            format!("{}: {}", line, message.trim())
+        }
+    }
+}
+
+struct Logger {
+    format: LogFormat,
+    level:  LevelFilter
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= self.level
+            && (metadata.level() <= Level::Warn || self.level >= LevelFilter::Debug
+                || OWN_TARGETS.iter().any(|target| metadata.target().starts_with(target)))
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // Rocket logs its launch information as warnings
+        let level = match record.level() {
+            Level::Warn if record.target().starts_with("rocket::launch") => Level::Info,
+            level => level
+        };
+        let line = format_line(self.format, level, record.target(), &record.args().to_string(),
+                               request_id().as_deref(), simulation_id());
+        let _ = writeln!(std::io::stdout().lock(), "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stdout().flush();
+    }
+}
+
+#[doc = "Install the logger, configured from RUST_LOG and LOG_FORMAT"]
+pub fn init() {
+    let level = std::env::var("RUST_LOG").ok()
+        .and_then(|level| LevelFilter::from_str(level.trim()).ok())
+        .unwrap_or(LevelFilter::Info);
+    let format = match std::env::var("LOG_FORMAT") {
+        Ok(format) if format.trim().eq_ignore_ascii_case("json") => LogFormat::Json,
+        _ => LogFormat::Text
+    };
+    if log::set_boxed_logger(Box::new(Logger { format, level })).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+#[doc = "The id of a request"]
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    pub fn of(request: &Request<'_>) -> RequestId {
+        request.local_cache(|| RequestId(generate_request_id())).clone()
+    }
+}
+
+#[doc = "Fairing that gives every request an id, and sends it back in the response"]
+pub struct RequestIds;
+
+#[rocket::async_trait]
+impl Fairing for RequestIds {
+    fn info(&self) -> Info {
+        Info { name: "Request ids", kind: Kind::Request | Kind::Response }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let id = request.headers().get_one(REQUEST_ID_HEADER)
+            .filter(|id| valid_request_id(id))
+            .map(String::from)
+            .unwrap_or_else(generate_request_id);
+        request.local_cache(|| RequestId(id));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        response.set_header(Header::new(REQUEST_ID_HEADER, RequestId::of(request).0));
+    }
+}
+
+// The simulation a /simulation/<id>/... request is about
+fn path_simulation_id(request: &Request<'_>) -> Option<u64> {
+    let segments = request.uri().path().segments();
+    match segments.get(0) {
+        Some("simulation") => segments.get(1).and_then(|id| id.parse().ok()),
+        _ => None
+    }
+}
+
+#[doc = "Route handler that handles the request within its log context"]
+#[derive(Clone)]
+struct WithLogContext(Box<dyn Handler>);
+
+#[rocket::async_trait]
+impl Handler for WithLogContext {
+    async fn handle<'r>(&self, request: &'r Request<'_>, data: Data<'r>) -> Outcome<'r> {
+        let context = LogContext::new(RequestId::of(request).0, path_simulation_id(request));
+        in_context(context, self.0.handle(request, data)).await
+    }
+}
+
+#[doc = "Make the given routes handle their requests within a log context"]
+pub fn with_log_context(routes: Vec<Route>) -> Vec<Route> {
+    routes.into_iter()
+        .map(|mut route| {
+            route.handler = Box::new(WithLogContext(route.handler));
+            route
+        })
+        .collect()
+}
@@ -6,15 +6,118 @@
 //!
 //! | Endpoint                    | Method | Description        | Implementation              | Parameters                      | Returns                |
 //! |-----------------------------|--------|--------------------|-----------------------------|---------------------------------|------------------------|
-//! | /simulation                 | POST   | Add a simulation   | [`post_simulation`][post_s] | [`SimulationForm`][s_f_s]       | [`Simulation`][sim]    |
+//! | /simulation                 | POST   | Add a simulation   | [`post_simulation`][post_s] | [`SimulationRequest`][s_req], optional Idempotency-Key header | [`Simulation`][sim]    |
 //! | /simulation                 | GET    | List simulations   | [`get_simulations`][get_s]  | None                            | [ [`Simulation`][sim] ]|
+//! | /simulation/compare         | GET    | Compare simulations | [`get_simulation_comparison`][get_s_cmp] | ids, tolerance    | [`SimulationComparison`][s_cmp] |
+//! | /simulation/search          | GET    | Search simulations | [`get_simulation_search`][get_s_srch] | labels, q             | [ [`SimulationSummary`][s_sum] ] |
 //! | /simulation/ \[id]          | GET    | Simulation details | [`todo!`]                   | None                            | [`Simulation`][sim]    |
-//! | /simulation/ \[id] /results | GET    | Simulation results | [`todo!`]                   | None                            | plain text             |
+//! | /simulation/ \[id]          | PATCH  | Name and label     | [`patch_simulation`][patch_s] | [`MetadataPatch`][m_p]        | [`Simulation`][sim]    |
+//! | /simulation/ \[id] /rerun   | POST   | Run again          | [`post_simulation_rerun`][post_s_rr] | optional merge patch of [`SimulationParameters`][s_p] | [`Simulation`][sim] |
+//! | /simulation/ \[id] /results | GET    | Simulation results | [`get_simulation_results`][get_s_r] | format, columns     | csv, json, ndjson or parquet |
 //! | /simulation/ \[id] /logs    | GET    | Simulation logs    | [`todo!`]                   | None                            | plain text             |
+//! | /simulation/ \[id] /results/series | GET | Selected result signals | [`get_simulation_results_series`][get_s_r_s] | signals, from, to, max_points | [`ResultsSeries`][r_s] |
+//! | /simulation/ \[id] /report  | GET    | Statistics and limit violations | [`get_simulation_report`][get_s_rep] | None | [`SimulationReport`][s_r] |
+//! | /simulation/ \[id] /provenance | GET | Inputs, worker messages and versions | [`get_simulation_provenance`][get_s_p] | None | [`ProvenanceReport`][p_r] |
+//! | /simulation/ \[id] /contingencies | GET | Contingency report | [`get_simulation_contingencies`][get_s_c] | None  | [`ContingencyReport`][c_r] |
+//! | /simulation/ \[id] /status  | PUT    | Report status      | [`put_simulation_status`][put_s_s] | [`StatusUpdate`][s_u] | [`Simulation`][sim] |
+//! | /simulation/ \[id] /events  | GET    | Status stream (SSE) | [`get_simulation_events`][get_s_e] | None               | [`StatusEvent`][s_e] stream |
+//! | /events                     | GET    | All status changes (SSE) | [`get_events`][get_e]  | None                          | [`StatusEvent`][s_e] stream |
+//! | /webhook                    | POST   | Register a webhook | [`post_webhook`][post_w]    | [`WebhookForm`][w_f]            | [`Webhook`][w]         |
+//! | /webhook                    | GET    | List webhooks      | [`get_webhooks`][get_w]     | None                            | [`WebhookArray`][w_a]  |
+//! | /webhook/ \[id]             | DELETE | Remove a webhook   | [`delete_webhook`][del_w]   | None                            | [`Webhook`][w]         |
+//! | /webhook/ \[id] /deliveries | GET    | Delivery log       | [`get_webhook_deliveries`][get_w_d] | None                    | [`DeliveryArray`][d_a] |
+//! | /webhook/ \[id] /test       | POST   | Send a sample payload | [`post_webhook_test`][post_w_t] | None                    | [`Delivery`][d]        |
+//! | /template                   | POST   | Save a template    | [`post_template`][post_t]   | [`TemplateForm`][t_f]           | [`SimulationTemplate`][t] |
+//! | /template                   | GET    | List templates     | [`get_templates`][get_ts]   | None                            | [`TemplateArray`][t_a] |
+//! | /template/ \[id]            | GET    | Template details   | [`get_template`][get_t]     | None                            | [`SimulationTemplate`][t] |
+//! | /template/ \[id]            | PUT    | Replace a template | [`put_template`][put_t]     | [`TemplateForm`][t_f]           | [`SimulationTemplate`][t] |
+//! | /template/ \[id]            | DELETE | Remove a template  | [`delete_template`][del_t]  | None                            | [`SimulationTemplate`][t] |
+//! | /model                      | POST   | Register a model version | [`post_model`][post_mo] | [`ModelForm`][mo_f]        | [`Model`][mo]          |
+//! | /load_profile               | POST   | Register a load profile | [`post_load_profile`][post_lp] | [`LoadProfileForm`][lp_f] | [`LoadProfile`][lp] |
+//! | /load_profile/upload        | POST   | Upload a load profile | [`post_load_profile_upload`][post_lp_u] | name, description, CSV or zip body | [`LoadProfile`][lp] |
+//! | /load_profile               | GET    | List load profiles | [`get_load_profiles`][get_lps] | None                       | [`LoadProfileArray`][lp_a] |
+//! | /load_profile/ \[id]        | GET    | Load profile details | [`get_load_profile`][get_lp] | None                       | [`LoadProfile`][lp]    |
+//! | /load_profile/ \[id] /preview | GET  | First rows         | [`get_load_profile_preview`][get_lp_p] | rows             | [`LoadProfilePreview`][lp_p] |
+//! | /load_profile/ \[id] /check | GET    | Check against a model | [`get_load_profile_check`][get_lp_c] | model_id or model, version, finaltime | [`LoadProfileCheck`][lp_c] |
+//! | /model/validate             | POST   | Check CIM files    | [`post_model_validate`][post_mo_v] | [`ValidationForm`][v_f] | [`ModelValidation`][mo_v] |
+//! | /model                      | GET    | Latest model versions | [`get_models`][get_mos] | None                         | [`ModelArray`][mo_a]   |
+//! | /model/ \[name]             | GET    | All versions of a model | [`get_model_versions`][get_mo_v] | None           | [`ModelArray`][mo_a]   |
+//! | /model/ \[name] /\[version] | GET    | Model version      | [`get_model`][get_mo]       | None                            | [`Model`][mo]          |
+//! | /metrics                    | GET    | Prometheus metrics | [`get_metrics`][get_m]      | None                            | text                   |
+//! | /healthz                    | GET    | Liveness probe     | [`get_healthz`][get_hz]     | None                            | JSON                   |
+//! | /readyz                     | GET    | Readiness probe    | [`get_readyz`][get_rz]      | None                            | JSON, 503 if not ready |
+//! | /audit                      | GET    | Read audit log     | [`get_audit`][get_au]       | None                            | [`AuditArray`][au_a]   |
 //! | /debug                      | GET    | DPsim-api debug    | [`todo!`]                   | None                            | plain text             |
 //!
 //! [post_s]: routes::post_simulation()
 //! [get_s]: routes::get_simulations()
+//! [get_s_c]: routes::get_simulation_contingencies()
+//! [get_s_p]: routes::get_simulation_provenance()
+//! [p_r]: provenance::ProvenanceReport
+//! [c_r]: contingency::ContingencyReport
+//! [get_s_r]: routes::get_simulation_results()
+//! [get_s_r_s]: routes::get_simulation_results_series()
+//! [r_s]: results::ResultsSeries
+//! [get_s_rep]: routes::get_simulation_report()
+//! [s_r]: report::SimulationReport
+//! [get_s_cmp]: routes::get_simulation_comparison()
+//! [s_cmp]: compare::SimulationComparison
+//! [put_s_s]: routes::put_simulation_status()
+//! [get_s_e]: routes::get_simulation_events()
+//! [get_e]: routes::get_events()
+//! [s_u]: status::StatusUpdate
+//! [s_e]: status::StatusEvent
+//! [post_w]: routes::post_webhook()
+//! [get_w]: routes::get_webhooks()
+//! [del_w]: routes::delete_webhook()
+//! [get_w_d]: routes::get_webhook_deliveries()
+//! [post_w_t]: routes::post_webhook_test()
+//! [w_f]: webhook::WebhookForm
+//! [w]: webhook::Webhook
+//! [w_a]: webhook::WebhookArray
+//! [d_a]: webhook::DeliveryArray
+//! [d]: webhook::Delivery
+//! [s_req]: template::SimulationRequest
+//! [post_s_rr]: routes::post_simulation_rerun()
+//! [s_p]: template::SimulationParameters
+//! [get_s_srch]: routes::get_simulation_search()
+//! [s_sum]: routes::SimulationSummary
+//! [patch_s]: routes::patch_simulation()
+//! [m_p]: search::MetadataPatch
+//! [get_m]: routes::get_metrics()
+//! [get_hz]: routes::get_healthz()
+//! [get_rz]: routes::get_readyz()
+//! [get_au]: routes::get_audit()
+//! [au_a]: audit::AuditArray
+//! [post_mo]: routes::post_model()
+//! [post_lp]: routes::post_load_profile()
+//! [post_lp_u]: routes::post_load_profile_upload()
+//! [get_lps]: routes::get_load_profiles()
+//! [get_lp]: routes::get_load_profile()
+//! [get_lp_p]: routes::get_load_profile_preview()
+//! [get_lp_c]: routes::get_load_profile_check()
+//! [lp_f]: load_profile::LoadProfileForm
+//! [lp]: load_profile::LoadProfile
+//! [lp_a]: load_profile::LoadProfileArray
+//! [lp_p]: load_profile::LoadProfilePreview
+//! [lp_c]: load_profile::LoadProfileCheck
+//! [get_mos]: routes::get_models()
+//! [post_mo_v]: routes::post_model_validate()
+//! [v_f]: cim::ValidationForm
+//! [mo_v]: cim::ModelValidation
+//! [get_mo_v]: routes::get_model_versions()
+//! [get_mo]: routes::get_model()
+//! [mo_f]: model::ModelForm
+//! [mo]: model::Model
+//! [mo_a]: model::ModelArray
+//! [post_t]: routes::post_template()
+//! [get_ts]: routes::get_templates()
+//! [get_t]: routes::get_template()
+//! [put_t]: routes::put_template()
+//! [del_t]: routes::delete_template()
+//! [t_f]: template::TemplateForm
+//! [t]: template::SimulationTemplate
+//! [t_a]: template::TemplateArray
 //! [s_f_s]: routes::SimulationForm
 //! [sim]: routes::Simulation
 
@@ -29,6 +132,30 @@ extern crate rocket;
 mod routes;
 mod file_service;
 mod amqp;
+mod audit;
+mod cim;
+mod compare;
+mod contingency;
+mod creation;
+mod events;
+mod export;
+mod health;
+mod limits;
+mod load_profile;
+mod logging;
+mod metrics;
+mod model;
+mod provenance;
+mod realtime;
+mod report;
+mod results;
+mod search;
+mod simulation_time;
+mod status;
+mod telemetry;
+mod template;
+mod idempotency;
+mod webhook;
 #[cfg(not(test))] mod db;
 use rocket_dyn_templates::Template;
 use rocket_okapi::swagger_ui::{make_swagger_ui, SwaggerUIConfig};
@@ -44,13 +171,19 @@ fn get_docs() -> SwaggerUIConfig {
 #[doc = "The main entry point for Rocket" ]
 async fn main() -> Result <(), rocket::Error> {
 
-    rocket::build()
+    logging::init();
+    telemetry::init();
+    let launched = rocket::build()
         .register("/", catchers![routes::incomplete_form])
         .mount("/", routes::get_routes())
         .mount("/swagger", make_swagger_ui(&get_docs()))
         .attach(Template::fairing())
+        .attach(metrics::RequestMetrics)
+        .attach(logging::RequestIds)
         .launch()
-        .await
+        .await;
+    telemetry::shutdown();
+    launched
 }
 
 #[cfg(test)]
@@ -58,6 +191,17 @@ mod db {
     use redis::RedisResult;
     use crate::routes::{Simulation, SimulationType};
     use crate::routes::{DomainType, SolverType};
+    use crate::simulation_time::SimulationTime;
+    use crate::status::SimulationStatus;
+    use crate::webhook::{Delivery, Webhook};
+    use crate::idempotency::IdempotencyRecord;
+    use crate::audit::AuditEntry;
+    use crate::provenance::Provenance;
+    use crate::model::Model;
+    use crate::load_profile::{LoadProfile, LoadProfileAnalysis, LoadProfileFormat, TimeFormat};
+    use crate::template::{SimulationParameters, SimulationTemplate};
+    use std::collections::HashMap;
+    use std::sync::{LazyLock, Mutex};
     pub fn get_number_of_simulations() -> RedisResult<u64> {
         Ok(10)
     }
@@ -67,10 +211,208 @@ mod db {
     pub fn write_simulation(_key: &String, _value: &Simulation) -> redis::RedisResult<()> {
         Ok(())
     }
+    static PROVENANCES: LazyLock<Mutex<Vec<(u64, Provenance)>>> = LazyLock::new(Default::default);
+    pub fn write_provenance(simulation_id: u64, value: &Provenance) -> RedisResult<()> {
+        PROVENANCES.lock().unwrap().push((simulation_id, value.clone()));
+        Ok(())
+    }
+    pub fn read_provenance(simulation_id: u64) -> RedisResult<Option<Provenance>> {
+        Ok(PROVENANCES.lock().unwrap().iter().rev()
+            .find(|(id, _)| *id == simulation_id)
+            .map(|(_, provenance)| provenance.clone()))
+    }
+    pub fn update_provenance(simulation_id: u64, mut change: impl FnMut(&mut Provenance)) -> RedisResult<()> {
+        let mut provenances = PROVENANCES.lock().unwrap();
+        if let Some((_, provenance)) = provenances.iter_mut().rev().find(|(id, _)| *id == simulation_id) {
+            change(provenance);
+        }
+        Ok(())
+    }
+    pub fn written_provenances() -> Vec<Provenance> {
+        PROVENANCES.lock().unwrap().iter().map(|(_, provenance)| provenance.clone()).collect()
+    }
+    pub fn get_new_webhook_id() -> RedisResult<u64> {
+        Ok(1)
+    }
+    pub fn get_number_of_webhooks() -> RedisResult<u64> {
+        Ok(1)
+    }
+    pub fn write_webhook(_value: &Webhook) -> RedisResult<()> {
+        Ok(())
+    }
+    pub fn read_webhook(id: u64) -> RedisResult<Option<Webhook>> {
+        if id != 1 {
+            return Ok(None)
+        }
+        Ok(Some(Webhook {
+               webhook_id:    1,
+               url:           "http://integration-service/hook".to_string(),
+               events:        vec![SimulationStatus::Succeeded, SimulationStatus::Failed],
+               secret:        "secret".to_string(),
+               simulation_id: None
+        }))
+    }
+    pub fn delete_webhook(_id: u64) -> RedisResult<()> {
+        Ok(())
+    }
+    pub fn add_webhook_delivery(_delivery: &Delivery) -> RedisResult<()> {
+        Ok(())
+    }
+    pub fn read_webhook_deliveries(_id: u64) -> RedisResult<Vec<Delivery>> {
+        Ok(vec![])
+    }
+    pub fn add_model_version(value: &Model) -> RedisResult<Model> {
+        Ok(Model { version: 2, ..value.clone() })
+    }
+    pub fn get_latest_model_version(name: &str) -> RedisResult<Option<u32>> {
+        Ok(if name == "cigre-mv" { Some(1) } else { None })
+    }
+    pub fn read_model(name: &str, version: u32) -> RedisResult<Option<Model>> {
+        if name != "cigre-mv" || version != 1 {
+            return Ok(None)
+        }
+        Ok(Some(Model {
+            name:        name.into(),
+            version,
+            description: "CIGRE medium voltage benchmark grid".into(),
+            file_ids:    ["EQ", "TP", "SV", "SSH"].iter().map(|profile| format!("cigre-mv-{}", profile)).collect(),
+            checksum:    "0".repeat(64),
+            created_by:  "anonymous".into(),
+            created_at:  0
+        }))
+    }
+    pub fn read_model_names() -> RedisResult<Vec<String>> {
+        Ok(vec!["cigre-mv".into()])
+    }
+    pub fn get_new_load_profile_id() -> RedisResult<u64> {
+        Ok(2)
+    }
+    pub fn get_number_of_load_profiles() -> RedisResult<u64> {
+        Ok(1)
+    }
+    pub fn write_load_profile(_value: &LoadProfile) -> RedisResult<()> {
+        Ok(())
+    }
+    pub fn read_load_profile(id: u64) -> RedisResult<Option<LoadProfile>> {
+        if id != 1 {
+            return Ok(None)
+        }
+        Ok(Some(LoadProfile {
+            load_profile_id: 1,
+            name:            "cigre-mv-10min".into(),
+            description:     "".into(),
+            file_id:         "cigre-mv-profile.zip".into(),
+            analysis:        LoadProfileAnalysis {
+                format:      LoadProfileFormat::Zip,
+                time_format: TimeFormat::Seconds,
+                files:       vec!["Load2.csv".into()],
+                load_ids:    vec!["Load2".into()],
+                resolution:  SimulationTime::from_secs(60),
+                start:       "0".into(),
+                end:         "600".into(),
+                duration:    SimulationTime::from_secs(600),
+                rows:        11
+            },
+            created_by:      "anonymous".into(),
+            created_at:      0
+        }))
+    }
+    static AUDIT_LOG: LazyLock<Mutex<Vec<AuditEntry>>> = LazyLock::new(Default::default);
+    pub fn get_new_audit_id() -> RedisResult<u64> {
+        Ok(AUDIT_LOG.lock().unwrap().len() as u64 + 1)
+    }
+    pub fn append_audit_entry(entry: &AuditEntry) -> RedisResult<()> {
+        AUDIT_LOG.lock().unwrap().push(entry.clone());
+        Ok(())
+    }
+    pub fn read_audit_entries(simulation_id: Option<u64>) -> RedisResult<Vec<AuditEntry>> {
+        Ok(AUDIT_LOG.lock().unwrap().iter()
+            .filter(|entry| simulation_id.is_none_or(|id| entry.simulation_id == id))
+            .cloned()
+            .collect())
+    }
+    static IDEMPOTENCY_RECORDS: LazyLock<Mutex<HashMap<String, (IdempotencyRecord, usize)>>> = LazyLock::new(Default::default);
+    pub fn reserve_idempotency_key(key: &str, value: &IdempotencyRecord, ttl_seconds: usize) -> RedisResult<bool> {
+        let mut records = IDEMPOTENCY_RECORDS.lock().unwrap();
+        if records.contains_key(key) {
+            return Ok(false)
+        }
+        records.insert(key.into(), (value.clone(), ttl_seconds));
+        Ok(true)
+    }
+    pub fn write_idempotency_record(key: &str, value: &IdempotencyRecord, ttl_seconds: usize) -> RedisResult<()> {
+        IDEMPOTENCY_RECORDS.lock().unwrap().insert(key.into(), (value.clone(), ttl_seconds));
+        Ok(())
+    }
+    pub fn read_idempotency_record(key: &str) -> RedisResult<Option<IdempotencyRecord>> {
+        Ok(IDEMPOTENCY_RECORDS.lock().unwrap().get(key).map(|(record, _)| record.clone()))
+    }
+    pub fn idempotency_ttl(key: &str) -> Option<usize> {
+        IDEMPOTENCY_RECORDS.lock().unwrap().get(key).map(|(_, ttl_seconds)| *ttl_seconds)
+    }
+    pub fn delete_idempotency_record(key: &str) -> RedisResult<()> {
+        IDEMPOTENCY_RECORDS.lock().unwrap().remove(key);
+        Ok(())
+    }
+    pub fn get_new_template_id() -> RedisResult<u64> {
+        Ok(1)
+    }
+    pub fn get_number_of_templates() -> RedisResult<u64> {
+        Ok(1)
+    }
+    pub fn write_template(_value: &SimulationTemplate) -> RedisResult<()> {
+        Ok(())
+    }
+    pub fn read_template(id: u64) -> RedisResult<Option<SimulationTemplate>> {
+        if id != 1 {
+            return Ok(None)
+        }
+        Ok(Some(SimulationTemplate {
+               template_id: 1,
+               name:        "Standard powerflow".to_string(),
+               description: "".to_string(),
+               parameters:  SimulationParameters {
+                   simulation_type:   Some(SimulationType::Powerflow),
+                   domain:            Some(DomainType::SP),
+                   solver:            Some(SolverType::NRP),
+                   timestep:          Some(SimulationTime::from_secs(1)),
+                   finaltime:         Some(SimulationTime::from_secs(360)),
+                   worker_parameters: Some([("executable".to_string(), "SLEW_Shmem_CIGRE_MV_PowerFlow".to_string())].into()),
+                   ..Default::default()
+               }
+        }))
+    }
+    pub fn delete_template(_id: u64) -> RedisResult<()> {
+        Ok(())
+    }
+    pub fn update_search_index(_simulation_id: u64, _removed: &[String], _added: &[String]) -> RedisResult<()> {
+        Ok(())
+    }
+    pub fn ping(_timeout: std::time::Duration) -> RedisResult<()> {
+        Ok(())
+    }
+    pub fn count_index(_key: &str) -> RedisResult<u64> {
+        Ok(0)
+    }
+    pub fn search_index(keys: &[String]) -> RedisResult<Vec<u64>> {
+        if keys.iter().any(|key| key.starts_with("index:label:study=")) {
+            Ok(vec![1])
+        } else {
+            Ok(vec![])
+        }
+    }
+    pub fn update_simulation<E>(key: u64, mut change: impl FnMut(&mut Simulation) -> Result<(), E>)
+        -> RedisResult<Result<(Simulation, Simulation), E>> {
+        let before = read_simulation(key)?;
+        let mut after = before.clone();
+        Ok(change(&mut after).map(|()| (before, after)))
+    }
     pub fn read_simulation(_key: u64) -> redis::RedisResult<Simulation> {
         Ok(Simulation {
                error:           "".to_owned(),
-               load_profile_id: "".into(),
+               status:          Default::default(),
+               progress:        0,
+               load_profile_id: None,
                model_id:        "1".to_string(),
                results_id:      "1".to_string(),
                results_data:    "1".to_string(),
@@ -78,8 +420,24 @@ mod db {
                simulation_type: SimulationType::Powerflow,
                domain:          DomainType::SP,
                solver:          SolverType::NRP,
-               timestep:        1,
-               finaltime:       360
+               timestep:        SimulationTime::from_secs(1),
+               finaltime:       SimulationTime::from_secs(360),
+               contingencies:   vec![],
+               all_n_minus_1:   false,
+               contingency_jobs: vec![],
+               events:          vec![],
+               realtime:        None,
+               limits:          Default::default(),
+               report:          None,
+               worker_parameters: Default::default(),
+               template_id:     None,
+               rerun_of:        None,
+               name:            "".to_string(),
+               description:     "".to_string(),
+               labels:          Default::default(),
+               model:           None,
+               model_files:     vec!["1".to_string()],
+               load_profile:    None
         })
     }
 }
@@ -0,0 +1,204 @@
+//!
+//! # Prometheus metrics
+//!
+//! `GET /metrics` returns the metrics below in the Prometheus text format.
+//! Requests are counted and timed by the [`RequestMetrics`] fairing,
+//! labelled with the name of the route that handled them, as listed by
+//! `routes::get_routes`, or "unmatched". The calls to Redis, the AMQP
+//! broker and the sogno file service are timed where they are made, and
+//! traced in spans of their own, as described in `telemetry`.
+//!
+//! | Metric                                          | Labels                           |
+//! |-------------------------------------------------|----------------------------------|
+//! | dpsim_api_http_requests_total                   | route, method, status            |
+//! | dpsim_api_http_request_duration_seconds         | route, method                    |
+//! | dpsim_api_simulations_created_total             | simulation_type, domain, solver  |
+//! | dpsim_api_simulations                           | status (Queued, Running)         |
+//! | dpsim_api_amqp_publish_total                    | result (success, failure)        |
+//! | dpsim_api_amqp_publish_duration_seconds         |                                  |
+//! | dpsim_api_redis_request_duration_seconds        | operation                        |
+//! | dpsim_api_redis_errors_total                    | operation                        |
+//! | dpsim_api_file_service_request_duration_seconds | operation                        |
+//! | dpsim_api_file_service_errors_total             | operation                        |
+//!
+//! The simulations gauge is read from the status sets of the search index
+//! when the metrics are scraped, so it only counts simulations that were
+//! created or changed since the index was introduced. The Redis and file
+//! service metrics are left out of test builds, where both are mocked.
+//!
+
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::LazyLock;
+use std::time::Instant;
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder};
+use rocket::{Data, Request, Response};
+use rocket::fairing::{Fairing, Info, Kind};
+use log::info;
+use opentelemetry::KeyValue;
+use opentelemetry::trace::SpanKind;
+use crate::db;
+use crate::routes::Simulation;
+use crate::search;
+use crate::status::SimulationStatus;
+use crate::telemetry;
+
+// The statuses that the simulations gauge reports; the finished ones only ever grow
+const GAUGED_STATUSES: [SimulationStatus; 2] = [SimulationStatus::Queued, SimulationStatus::Running];
+
+#[doc = "The metrics of this process, and the registry they are exported from"]
+pub struct Metrics {
+    registry:                      Registry,
+    http_requests:                 IntCounterVec,
+    http_request_duration:         HistogramVec,
+    simulations_created:           IntCounterVec,
+    simulations:                   IntGaugeVec,
+    amqp_publish:                  IntCounterVec,
+    amqp_publish_duration:         HistogramVec,
+    #[cfg(not(test))]
+    redis_request_duration:        HistogramVec,
+    #[cfg(not(test))]
+    redis_errors:                  IntCounterVec,
+    #[cfg(not(test))]
+    file_service_request_duration: HistogramVec,
+    #[cfg(not(test))]
+    file_service_errors:           IntCounterVec
+}
+
+fn counter(registry: &Registry, name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+    let counter = IntCounterVec::new(Opts::new(name, help), labels).unwrap();
+    registry.register(Box::new(counter.clone())).unwrap();
+    counter
+}
+
+fn histogram(registry: &Registry, name: &str, help: &str, labels: &[&str]) -> HistogramVec {
+    let histogram = HistogramVec::new(HistogramOpts::new(name, help), labels).unwrap();
+    registry.register(Box::new(histogram.clone())).unwrap();
+    histogram
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        let registry = Registry::new();
+        let simulations = IntGaugeVec::new(
+            Opts::new("dpsim_api_simulations", "Simulations that have not finished, by status"), &["status"]).unwrap();
+        registry.register(Box::new(simulations.clone())).unwrap();
+        Metrics {
+            http_requests:                 counter(&registry, "dpsim_api_http_requests_total",
+                                                   "HTTP requests handled", &["route", "method", "status"]),
+            http_request_duration:         histogram(&registry, "dpsim_api_http_request_duration_seconds",
+                                                     "Time taken to handle HTTP requests", &["route", "method"]),
+            simulations_created:           counter(&registry, "dpsim_api_simulations_created_total",
+                                                   "Simulations created", &["simulation_type", "domain", "solver"]),
+            simulations,
+            amqp_publish:                  counter(&registry, "dpsim_api_amqp_publish_total",
+                                                   "Jobs published to the AMQP broker", &["result"]),
+            amqp_publish_duration:         histogram(&registry, "dpsim_api_amqp_publish_duration_seconds",
+                                                     "Time taken to publish jobs to the AMQP broker", &[]),
+            #[cfg(not(test))]
+            redis_request_duration:        histogram(&registry, "dpsim_api_redis_request_duration_seconds",
+                                                     "Time taken by Redis operations", &["operation"]),
+            #[cfg(not(test))]
+            redis_errors:                  counter(&registry, "dpsim_api_redis_errors_total",
+                                                   "Redis operations that failed", &["operation"]),
+            #[cfg(not(test))]
+            file_service_request_duration: histogram(&registry, "dpsim_api_file_service_request_duration_seconds",
+                                                     "Time taken by sogno file service requests", &["operation"]),
+            #[cfg(not(test))]
+            file_service_errors:           counter(&registry, "dpsim_api_file_service_errors_total",
+                                                   "sogno file service requests that failed", &["operation"]),
+            registry
+        }
+    }
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+#[doc = "Count a newly created simulation"]
+pub fn simulation_created(sim: &Simulation) {
+    METRICS.simulations_created
+        .with_label_values(&[&format!("{:?}", sim.simulation_type), &format!("{:?}", sim.domain), &format!("{:?}", sim.solver)])
+        .inc();
+}
+
+#[doc = "Time a job being published to the AMQP broker, in a span of the current trace"]
+pub async fn observe_amqp_publish<T, E: Display, F: Future<Output = Result<T, E>>>(publish: F) -> Result<T, E> {
+    let start = Instant::now();
+    let result = telemetry::in_span_async("amqp publish", SpanKind::Producer, vec![
+        KeyValue::new("messaging.system", "rabbitmq"),
+        KeyValue::new("messaging.destination", "dpsim-worker-queue")
+    ], publish).await;
+    METRICS.amqp_publish_duration.with_label_values(&[]).observe(start.elapsed().as_secs_f64());
+    METRICS.amqp_publish.with_label_values(&[if result.is_ok() { "success" } else { "failure" }]).inc();
+    result
+}
+
+#[cfg(not(test))]
+#[doc = "Time a Redis operation, in a span of the current trace, counting it if it fails"]
+pub fn observe_redis<T, E: Display>(operation: &str, call: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+    let start = Instant::now();
+    let result = telemetry::in_span(&format!("redis {}", operation), SpanKind::Client, vec![
+        KeyValue::new("db.system", "redis"),
+        KeyValue::new("db.operation", operation.to_string())
+    ], call);
+    METRICS.redis_request_duration.with_label_values(&[operation]).observe(start.elapsed().as_secs_f64());
+    if result.is_err() {
+        METRICS.redis_errors.with_label_values(&[operation]).inc();
+    }
+    result
+}
+
+#[cfg(not(test))]
+#[doc = "Time a request to the sogno file service, in a span of the current trace, counting it if it fails"]
+pub async fn observe_file_service<T, E: Display, F: Future<Output = Result<T, E>>>(operation: &str, call: F) -> Result<T, E> {
+    let start = Instant::now();
+    let result = telemetry::in_span_async(&format!("file_service {}", operation), SpanKind::Client, vec![
+        KeyValue::new("peer.service", "sogno-file-service"),
+        KeyValue::new("file_service.operation", operation.to_string())
+    ], call).await;
+    METRICS.file_service_request_duration.with_label_values(&[operation]).observe(start.elapsed().as_secs_f64());
+    if result.is_err() {
+        METRICS.file_service_errors.with_label_values(&[operation]).inc();
+    }
+    result
+}
+
+#[doc = "All the metrics, in the Prometheus text format"]
+pub fn render() -> String {
+    for status in GAUGED_STATUSES {
+        let label = format!("{:?}", status);
+        match db::count_index(&search::status_key(status)) {
+            Ok(count) => METRICS.simulations.with_label_values(&[&label]).set(count as i64),
+            Err(e) => info!("Could not count {} simulations: {}", label, e)
+        }
+    }
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&METRICS.registry.gather(), &mut buffer) {
+        info!("Could not encode metrics: {}", e);
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+struct RequestStart(Instant);
+
+#[doc = "Fairing that counts and times every request"]
+pub struct RequestMetrics;
+
+#[rocket::async_trait]
+impl Fairing for RequestMetrics {
+    fn info(&self) -> Info {
+        Info { name: "Prometheus request metrics", kind: Kind::Request | Kind::Response }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(|| RequestStart(Instant::now()));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let start = request.local_cache(|| RequestStart(Instant::now()));
+        let route = request.route().and_then(|route| route.name.as_deref()).unwrap_or("unmatched");
+        let method = request.method().as_str();
+        METRICS.http_requests.with_label_values(&[route, method, &response.status().code.to_string()]).inc();
+        METRICS.http_request_duration.with_label_values(&[route, method]).observe(start.0.elapsed().as_secs_f64());
+    }
+}
@@ -0,0 +1,226 @@
+//!
+//! # Model registry
+//!
+//! A grid model is registered under a name, with the ids of its CIM XML
+//! files in the sogno file service, e.g. one file per EQ, TP, SV and SSH
+//! profile. Registering a model under a name that is already in use adds
+//! a new version of it; versions are never changed or removed, so a
+//! simulation can always show which files it was run with.
+//!
+//! The checksum of a version is the SHA-256 digest of the SHA-256 digests
+//! of its files, in order, as read from the file service at registration.
+//! The files are validated as a CIM model first, see [`crate::cim`], and a
+//! model with errors is not registered.
+//!
+//! A simulation refers to a model either by `model_id`, the id of a single
+//! file, or by `model`, a [`ModelRef`]. Without a version, a [`ModelRef`]
+//! is resolved to the latest version when the simulation is created, and
+//! the simulation records that version.
+//!
+
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{ Serialize, Deserialize };
+use schemars::JsonSchema;
+use rocket::http::Status;
+use sha2::{Digest, Sha256};
+use crate::cim;
+use crate::db;
+use crate::file_service;
+use crate::routes::SimulationError;
+
+const MAX_NAME_LENGTH: usize = 128;
+
+/// # Form for registering a model, or a new version of it
+///
+/// ## Parameters:
+/// * name
+///   - String of letters, digits, '-', '_' and '.'
+/// * description
+///   - String, optional
+/// * file_ids
+///   - list of the ids of the CIM XML files of the model in the sogno file service
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ModelForm {
+    pub name:        String,
+    #[serde(default)]
+    pub description: String,
+    pub file_ids:    Vec<String>
+}
+
+#[doc = "A version of a registered grid model"]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Model {
+    pub name:        String,
+    pub version:     u32,
+    #[serde(default)]
+    pub description: String,
+    pub file_ids:    Vec<String>,
+    pub checksum:    String,
+    pub created_by:  String,
+    #[doc = "Seconds since the Unix epoch"]
+    pub created_at:  u64
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[doc = "Struct for encapsulation a list of Models"]
+pub struct ModelArray {
+    pub models: Vec<Model>
+}
+
+#[doc = "A reference to a registered model, the latest version if none is given"]
+#[derive(FromForm, Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ModelRef {
+    pub name:    String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>
+}
+
+#[doc = "The files of the model of a simulation"]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelFiles {
+    #[doc = "The registered model, with its version, if the simulation refers to one"]
+    pub model:    Option<ModelRef>,
+    pub file_ids: Vec<String>
+}
+
+#[doc = "Check a model form"]
+pub fn validate_form(form: &ModelForm) -> Result<(), String> {
+    if form.name.is_empty() || form.name.len() > MAX_NAME_LENGTH {
+        return Err(format!("name must be between 1 and {} characters long", MAX_NAME_LENGTH))
+    }
+    if !form.name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)) {
+        return Err("name must only contain letters, digits, '-', '_' and '.'".into())
+    }
+    if form.file_ids.is_empty() {
+        return Err("A model needs at least one file".into())
+    }
+    if form.file_ids.iter().any(|file_id| file_id.trim().is_empty()) {
+        return Err("file_ids must not be empty".into())
+    }
+    Ok(())
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[doc = "Read the files of a model from the file service"]
+pub async fn read_files(file_ids: &[String]) -> Result<Vec<(String, Vec<u8>)>, SimulationError> {
+    let mut files = Vec::with_capacity(file_ids.len());
+    for file_id in file_ids {
+        let url = file_service::convert_id_to_url(file_id).await?;
+        match file_service::get_data_from_url(&url).await {
+            Ok(data) => files.push((file_id.clone(), data.to_vec())),
+            Err(e) => return Err(SimulationError {
+                err: format!("Could not read model file {}: {}", file_id, e),
+                http_status_code: Status::BadGateway
+            })
+        }
+    }
+    Ok(files)
+}
+
+#[doc = "The checksum of the files of a model"]
+pub fn checksum(files: &[(String, Vec<u8>)]) -> String {
+    let mut digests = Sha256::new();
+    for (_, data) in files {
+        digests.update(Sha256::digest(data));
+    }
+    hex::encode(digests.finalize())
+}
+
+#[doc = "Register a model, or a new version of it, if its files are a valid CIM model"]
+pub async fn register(form: &ModelForm, created_by: &str) -> Result<Model, SimulationError> {
+    if let Err(e) = validate_form(form) {
+        return Err(SimulationError { err: e, http_status_code: Status::BadRequest })
+    }
+    let files = read_files(&form.file_ids).await?;
+    let validation = cim::validate(&files);
+    if !validation.valid {
+        return Err(SimulationError {
+            err: format!("Model {} is not a valid CIM model:\n{}", form.name, validation.errors()),
+            http_status_code: Status::BadRequest
+        })
+    }
+    let checksum = checksum(&files);
+    let model = Model {
+        name:        form.name.clone(),
+        version:     0,
+        description: form.description.clone(),
+        file_ids:    form.file_ids.clone(),
+        checksum,
+        created_by:  created_by.into(),
+        created_at:  now()
+    };
+    match db::add_model_version(&model) {
+        Ok(model) => Ok(model),
+        Err(e) => Err(SimulationError {
+            err: format!("Could not write new version of model {} to db: {}", form.name, e),
+            http_status_code: Status::BadGateway
+        })
+    }
+}
+
+#[doc = "Read a version of a model, or its latest version"]
+pub fn read(name: &str, version: Option<u32>) -> Result<Model, SimulationError> {
+    let version = match version {
+        Some(version) => Some(version),
+        None => match db::get_latest_model_version(name) {
+            Ok(version) => version,
+            Err(e) => return Err(SimulationError { err: e.to_string(), http_status_code: Status::UnprocessableEntity })
+        }
+    };
+    let model = match version {
+        Some(version) => db::read_model(name, version),
+        None => Ok(None)
+    };
+    match model {
+        Ok(Some(model)) => Ok(model),
+        Ok(None) => Err(SimulationError {
+            err: match version {
+                Some(version) => format!("Version {} of model {} does not exist", version, name),
+                None => format!("Model {} does not exist", name)
+            },
+            http_status_code: Status::NotFound
+        }),
+        Err(e) => Err(SimulationError { err: e.to_string(), http_status_code: Status::UnprocessableEntity })
+    }
+}
+
+#[doc = "Read every version of a model, oldest first"]
+pub fn read_versions(name: &str) -> Result<Vec<Model>, SimulationError> {
+    let latest = read(name, None)?;
+    let mut models = Vec::new();
+    for version in 1..latest.version {
+        models.push(read(name, Some(version))?);
+    }
+    models.push(latest);
+    Ok(models)
+}
+
+#[doc = "Read the latest version of every model"]
+pub fn read_latest() -> Result<Vec<Model>, SimulationError> {
+    let names = match db::read_model_names() {
+        Ok(names) => names,
+        Err(e) => return Err(SimulationError { err: e.to_string(), http_status_code: Status::UnprocessableEntity })
+    };
+    names.iter().map(|name| read(name, None)).collect()
+}
+
+#[doc = "The files of the model a simulation refers to, by model_id or by model"]
+pub fn resolve(model_id: &str, model: Option<&ModelRef>) -> Result<ModelFiles, SimulationError> {
+    match (model_id.is_empty(), model) {
+        (false, None) => Ok(ModelFiles { model: None, file_ids: vec![model_id.into()] }),
+        (true, Some(model)) => {
+            let model = read(&model.name, model.version)?;
+            Ok(ModelFiles {
+                model:    Some(ModelRef { name: model.name, version: Some(model.version) }),
+                file_ids: model.file_ids
+            })
+        },
+        _ => Err(SimulationError {
+            err: "A simulation needs either a model_id or a model, and not both".into(),
+            http_status_code: Status::BadRequest
+        })
+    }
+}
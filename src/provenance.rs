@@ -0,0 +1,175 @@
+//!
+//! # Provenance of a simulation
+//!
+//! When a simulation is created, the SHA-256 digest of each of its input
+//! files is taken from the file service, and the worker messages are
+//! recorded exactly as they are sent to `dpsim-worker-queue`. The worker
+//! reports its own version and that of DPsim with its status updates.
+//!
+//! The provenance is stored apart from the simulation record, as the worker
+//! messages hold the URLs of the input files, and is only returned by
+//! `GET /simulation/<id>/provenance`.
+//!
+//! The reproducibility hash is the SHA-256 digest of the input digests, the
+//! parameters that decide the results and the reported versions. File ids,
+//! file URLs and results file ids are left out, so two simulations of the
+//! same files with the same parameters and versions have the same hash.
+//! It is only final once the worker has reported its versions.
+//!
+
+use std::collections::BTreeMap;
+use serde::{ Serialize, Deserialize };
+use serde_json::Value;
+use schemars::JsonSchema;
+use sha2::{Digest, Sha256};
+use crate::contingency::Contingency;
+use crate::events::Event;
+use crate::load_profile;
+use crate::model;
+use crate::realtime::RealTimeConfig;
+use crate::routes::{DomainType, Simulation, SimulationError, SimulationType, SolverType};
+use crate::simulation_time::SimulationTime;
+use crate::status::StatusUpdate;
+
+#[doc = "What an input file of a simulation is"]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum InputRole {
+    Model,
+    LoadProfile
+}
+
+#[doc = "An input file of a simulation, as read from the file service"]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct InputFile {
+    pub role:    InputRole,
+    pub file_id: String,
+    pub sha256:  String,
+    #[doc = "Size in bytes"]
+    pub size:    u64
+}
+
+impl InputFile {
+    fn new(role: InputRole, file_id: &str, data: &[u8]) -> InputFile {
+        InputFile {
+            role,
+            file_id: file_id.into(),
+            sha256:  hex::encode(Sha256::digest(data)),
+            size:    data.len() as u64
+        }
+    }
+}
+
+#[doc = "What a simulation was run from, as recorded when it was created"]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Provenance {
+    pub inputs:          Vec<InputFile>,
+    #[doc = "The messages sent to the worker, one per job"]
+    pub worker_messages: Vec<Value>,
+    #[serde(default)]
+    pub worker_version:  Option<String>,
+    #[serde(default)]
+    pub dpsim_version:   Option<String>
+}
+
+#[doc = "The parameters of a simulation that decide its results"]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ReproducibleParameters {
+    pub simulation_type:   SimulationType,
+    pub domain:            DomainType,
+    pub solver:            SolverType,
+    pub timestep:          SimulationTime,
+    pub finaltime:         SimulationTime,
+    pub contingencies:     Vec<Contingency>,
+    pub all_n_minus_1:     bool,
+    pub events:            Vec<Event>,
+    pub realtime:          Option<RealTimeConfig>,
+    pub worker_parameters: BTreeMap<String, String>
+}
+
+impl ReproducibleParameters {
+    pub fn from_simulation(sim: &Simulation) -> ReproducibleParameters {
+        ReproducibleParameters {
+            simulation_type:   sim.simulation_type,
+            domain:            sim.domain,
+            solver:            sim.solver,
+            timestep:          sim.timestep,
+            finaltime:         sim.finaltime,
+            contingencies:     sim.contingencies.clone(),
+            all_n_minus_1:     sim.all_n_minus_1,
+            events:            sim.events.clone(),
+            realtime:          sim.realtime.clone(),
+            worker_parameters: sim.worker_parameters.clone()
+        }
+    }
+}
+
+/// # The provenance of a simulation, as returned by `GET /simulation/<id>/provenance`
+///
+/// ## Parameters:
+/// * provenance
+///   - the input files, worker messages and versions recorded with the simulation
+/// * parameters
+///   - the parameters that go into the reproducibility hash
+/// * reproducibility_hash
+///   - hex SHA-256 digest of the input digests, parameters and versions
+/// * complete
+///   - true once the worker has reported both of its versions
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ProvenanceReport {
+    pub simulation_id:        u64,
+    pub provenance:           Provenance,
+    pub parameters:           ReproducibleParameters,
+    pub reproducibility_hash: String,
+    pub complete:             bool
+}
+
+#[doc = "What goes into the reproducibility hash, in a fixed order"]
+#[derive(Serialize)]
+struct HashedContent<'a> {
+    inputs:         Vec<(InputRole, &'a str)>,
+    parameters:     &'a ReproducibleParameters,
+    worker_version: &'a Option<String>,
+    dpsim_version:  &'a Option<String>
+}
+
+#[doc = "Read the input files of a simulation from the file service and take their digests"]
+pub async fn hash_inputs(model_files: &[String], load_profile_id: Option<&str>) -> Result<Vec<InputFile>, SimulationError> {
+    let mut inputs = Vec::with_capacity(model_files.len() + 1);
+    for (file_id, data) in model::read_files(model_files).await? {
+        inputs.push(InputFile::new(InputRole::Model, &file_id, &data));
+    }
+    if let Some(file_id) = load_profile_id {
+        let data = load_profile::read_data(file_id).await?;
+        inputs.push(InputFile::new(InputRole::LoadProfile, file_id, &data));
+    }
+    Ok(inputs)
+}
+
+#[doc = "Record the versions reported in a status update"]
+pub fn apply_update(provenance: &mut Provenance, update: &StatusUpdate) {
+    if update.worker_version.is_some() {
+        provenance.worker_version = update.worker_version.clone();
+    }
+    if update.dpsim_version.is_some() {
+        provenance.dpsim_version = update.dpsim_version.clone();
+    }
+}
+
+#[doc = "The provenance of a simulation and its reproducibility hash"]
+pub fn report(sim: &Simulation, provenance: Provenance) -> ProvenanceReport {
+    let parameters = ReproducibleParameters::from_simulation(sim);
+    let content = HashedContent {
+        inputs:         provenance.inputs.iter().map(|input| (input.role, input.sha256.as_str())).collect(),
+        parameters:     &parameters,
+        worker_version: &provenance.worker_version,
+        dpsim_version:  &provenance.dpsim_version
+    };
+    let reproducibility_hash = hex::encode(Sha256::digest(serde_json::to_vec(&content).unwrap()));
+    ProvenanceReport {
+        simulation_id: sim.simulation_id,
+        complete:      provenance.worker_version.is_some() && provenance.dpsim_version.is_some(),
+        provenance,
+        parameters,
+        reproducibility_hash
+    }
+}
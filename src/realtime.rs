@@ -0,0 +1,137 @@
+//!
+//! # Real-time and co-simulation runs
+//!
+//! A simulation can be run in real time, coupled to other tools through
+//! one of DPsim's interfaces. The interface exports DPsim attributes to,
+//! and imports them from, the other side; each signal has a position in
+//! the samples that are exchanged.
+//!
+//! | Interface | Required settings                     |
+//! |-----------|---------------------------------------|
+//! | Shmem     | shmem_out, shmem_in                   |
+//! | Mqtt      | mqtt_broker, mqtt_publish_topic and/or mqtt_subscribe_topic |
+//! | Villas    | villas_config, a VILLASnode configuration as a JSON string |
+//!
+
+use serde::{ Serialize, Deserialize };
+use serde_json::{json, Value};
+use schemars::JsonSchema;
+use std::collections::HashSet;
+
+#[doc = "Enum for the various interfaces a real-time simulation can use"]
+#[derive(JsonSchema, FromFormField, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum InterfaceType {
+    Shmem,
+    Mqtt,
+    Villas
+}
+
+#[doc = "A DPsim attribute exchanged through the interface, and its position in a sample"]
+#[derive(FromForm, Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct InterfaceSignal {
+    pub attribute: String,
+    pub index:     u32
+}
+
+#[doc = "Settings for running a simulation in real time through an interface"]
+#[derive(FromForm, Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct RealTimeConfig {
+    pub interface_type:       InterfaceType,
+    #[serde(default)]
+    pub exports:              Vec<InterfaceSignal>,
+    #[serde(default)]
+    pub imports:              Vec<InterfaceSignal>,
+    #[doc = "Keep each timestep in step with the wall clock"]
+    #[serde(default)]
+    pub pace:                 bool,
+    #[doc = "Stop the simulation if a timestep takes longer than real time"]
+    #[serde(default)]
+    pub fail_on_overrun:      bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shmem_out:            Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shmem_in:             Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mqtt_broker:          Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mqtt_publish_topic:   Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mqtt_subscribe_topic: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub villas_config:        Option<String>
+}
+
+fn is_set(setting: &Option<String>) -> bool {
+    setting.as_ref().is_some_and(|value| !value.trim().is_empty())
+}
+
+fn validate_signals(direction: &str, signals: &[InterfaceSignal]) -> Result<(), String> {
+    let mut indices = HashSet::new();
+    for signal in signals {
+        if signal.attribute.trim().is_empty() {
+            return Err(format!("An {} signal has an empty attribute", direction))
+        }
+        if !indices.insert(signal.index) {
+            return Err(format!("Index {} is used by more than one {} signal", signal.index, direction))
+        }
+    }
+    Ok(())
+}
+
+#[doc = "Check that a real-time configuration has the settings its interface needs"]
+pub fn validate_config(config: &RealTimeConfig) -> Result<(), String> {
+    validate_signals("export", &config.exports)?;
+    validate_signals("import", &config.imports)?;
+    let shmem = is_set(&config.shmem_out) || is_set(&config.shmem_in);
+    let mqtt = is_set(&config.mqtt_broker) || is_set(&config.mqtt_publish_topic) || is_set(&config.mqtt_subscribe_topic);
+    let villas = is_set(&config.villas_config);
+    match config.interface_type {
+        InterfaceType::Shmem => {
+            if mqtt || villas {
+                return Err("Only shmem_out and shmem_in can be set for a Shmem interface".into())
+            }
+            if !is_set(&config.shmem_out) || !is_set(&config.shmem_in) {
+                return Err("A Shmem interface needs shmem_out and shmem_in".into())
+            }
+        },
+        InterfaceType::Mqtt => {
+            if shmem || villas {
+                return Err("Only the mqtt settings can be set for an Mqtt interface".into())
+            }
+            if !is_set(&config.mqtt_broker) {
+                return Err("An Mqtt interface needs mqtt_broker".into())
+            }
+            if !config.exports.is_empty() && !is_set(&config.mqtt_publish_topic) {
+                return Err("An Mqtt interface with exports needs mqtt_publish_topic".into())
+            }
+            if !config.imports.is_empty() && !is_set(&config.mqtt_subscribe_topic) {
+                return Err("An Mqtt interface with imports needs mqtt_subscribe_topic".into())
+            }
+        },
+        InterfaceType::Villas => {
+            if shmem || mqtt {
+                return Err("Only villas_config can be set for a Villas interface".into())
+            }
+            let villas_config = match &config.villas_config {
+                Some(villas_config) if villas => villas_config,
+                _ => return Err("A Villas interface needs villas_config".into())
+            };
+            match serde_json::from_str::<Value>(villas_config) {
+                Ok(Value::Object(_)) => (),
+                Ok(_) => return Err("villas_config must be a JSON object".into()),
+                Err(e) => return Err(format!("villas_config is not valid JSON: {}", e))
+            }
+        }
+    }
+    Ok(())
+}
+
+#[doc = "The real-time configuration as it is sent to the worker"]
+pub fn to_worker_parameters(config: &RealTimeConfig) -> Value {
+    let mut parameters = json!(config);
+    if let Some(villas_config) = &config.villas_config {
+        // validate_config has already checked that this parses
+        parameters["villas_config"] = serde_json::from_str(villas_config).unwrap_or(Value::Null);
+    }
+    parameters
+}
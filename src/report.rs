@@ -0,0 +1,76 @@
+//!
+//! # Summary report of a finished simulation
+//!
+//! The report holds statistics for every signal in the results and the
+//! violations of the simulation's [`Limits`]. It is generated the first
+//! time it is requested and then kept on the stored simulation.
+//!
+
+use serde::{ Serialize, Deserialize };
+use schemars::JsonSchema;
+use crate::limits::{find_violations, Limits, Violation};
+use crate::results::{ResultsTable, Signal};
+
+#[doc = "Statistics for a single signal"]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct SignalStatistics {
+    pub name:        String,
+    pub min:         f64,
+    pub time_of_min: f64,
+    pub max:         f64,
+    pub time_of_max: f64,
+    pub mean:        f64,
+    pub rms:         f64
+}
+
+#[doc = "Statistics and limit violations for the results of a simulation"]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct SimulationReport {
+    pub simulation_id: u64,
+    pub results_id:    String,
+    pub limits:        Limits,
+    pub statistics:    Vec<SignalStatistics>,
+    pub violations:    Vec<Violation>
+}
+
+#[doc = "Statistics for one signal, or None if it has no samples"]
+pub fn signal_statistics(time: &[f64], signal: &Signal) -> Option<SignalStatistics> {
+    if signal.values.is_empty() {
+        return None
+    }
+    let mut min = (signal.values[0], time[0]);
+    let mut max = (signal.values[0], time[0]);
+    let mut sum = 0.0;
+    let mut sum_of_squares = 0.0;
+    for (value, t) in signal.values.iter().zip(time.iter()) {
+        if *value < min.0 {
+            min = (*value, *t);
+        }
+        if *value > max.0 {
+            max = (*value, *t);
+        }
+        sum += value;
+        sum_of_squares += value * value;
+    }
+    let count = signal.values.len() as f64;
+    Some(SignalStatistics {
+        name:        signal.name.clone(),
+        min:         min.0,
+        time_of_min: min.1,
+        max:         max.0,
+        time_of_max: max.1,
+        mean:        sum / count,
+        rms:         (sum_of_squares / count).sqrt()
+    })
+}
+
+#[doc = "Build the report for a simulation from its results"]
+pub fn generate(simulation_id: u64, results_id: &str, table: &ResultsTable, limits: &Limits) -> SimulationReport {
+    SimulationReport {
+        simulation_id,
+        results_id: results_id.to_string(),
+        limits:     limits.clone(),
+        statistics: table.signals.iter().filter_map(|signal| signal_statistics(&table.time, signal)).collect(),
+        violations: find_violations(table, limits)
+    }
+}
@@ -0,0 +1,227 @@
+//!
+//! # Parsing of the result files written by DPsim
+//!
+//! DPsim's data logger writes one CSV file per simulation. The first
+//! line holds the column names, the first of which is the simulation
+//! time, and every following line holds one sample per column.
+//!
+
+use serde::{ Serialize, Deserialize };
+use schemars::JsonSchema;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, LazyLock, Mutex};
+
+#[derive(Debug, Clone, PartialEq)]
+#[doc = "The columns of a DPsim results file"]
+pub struct ResultsTable {
+    pub time:    Vec<f64>,
+    pub signals: Vec<Signal>
+}
+
+impl ResultsTable {
+    #[doc = "A copy of the table holding only the named signals, in the order given"]
+    pub fn select(&self, names: &[&str]) -> Result<ResultsTable, String> {
+        let mut signals = Vec::with_capacity(names.len());
+        for name in names {
+            match self.signals.iter().find(|signal| signal.name == *name) {
+                Some(signal) => signals.push(signal.clone()),
+                None => return Err(format!("No signal named '{}' in the results", name))
+            }
+        }
+        Ok(ResultsTable { time: self.time.clone(), signals })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[doc = "A single logged signal and its samples"]
+pub struct Signal {
+    pub name:   String,
+    pub values: Vec<f64>
+}
+
+#[doc = "Parse the contents of a DPsim CSV results file"]
+pub fn parse_csv(data: &str) -> Result<ResultsTable, String> {
+    let mut lines = data.lines().filter(|line| !line.trim().is_empty());
+    let header = match lines.next() {
+        Some(header) => header,
+        None => return Err("Results file is empty".into())
+    };
+    let names: Vec<String> = header.split(',').map(|name| name.trim().to_string()).collect();
+    if names.len() < 2 {
+        return Err(format!("Results file has no signal columns: {}", header))
+    }
+    let mut time = Vec::new();
+    let mut signals: Vec<Signal> = names[1..].iter()
+        .map(|name| Signal { name: name.clone(), values: Vec::new() })
+        .collect();
+    for (index, line) in lines.enumerate() {
+        let fields: Vec<&str> = line.split(',').collect();
+        if fields.len() != names.len() {
+            return Err(format!("Line {} has {} columns, expected {}", index + 2, fields.len(), names.len()))
+        }
+        let mut row = Vec::with_capacity(fields.len());
+        for field in fields {
+            match field.trim().parse::<f64>() {
+                Ok(value) => row.push(value),
+                Err(e) => return Err(format!("Line {}: could not parse '{}': {}", index + 2, field.trim(), e))
+            }
+        }
+        time.push(row[0]);
+        for (signal, value) in signals.iter_mut().zip(row[1..].iter()) {
+            signal.values.push(*value);
+        }
+    }
+    Ok(ResultsTable { time, signals })
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[doc = "The samples of one signal, as returned for plotting"]
+pub struct Series {
+    pub name:   String,
+    pub time:   Vec<f64>,
+    pub values: Vec<f64>
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[doc = "Selected signals from the results of a simulation"]
+pub struct ResultsSeries {
+    pub simulation_id: u64,
+    pub series:        Vec<Series>
+}
+
+#[doc = "Select signals from a results table, limited to a time window and downsampled to at most max_points"]
+pub fn select_series(table: &ResultsTable, names: Option<&[&str]>, from: Option<f64>, to: Option<f64>,
+                     max_points: Option<usize>) -> Result<Vec<Series>, String> {
+    if let Some(max_points) = max_points {
+        if max_points < 3 {
+            return Err(format!("max_points must be at least 3, not {}", max_points))
+        }
+    }
+    let selected: Vec<&Signal> = match names {
+        Some(names) => {
+            let mut selected = Vec::new();
+            for name in names {
+                match table.signals.iter().find(|signal| signal.name == *name) {
+                    Some(signal) => selected.push(signal),
+                    None => return Err(format!("No signal named '{}' in the results", name))
+                }
+            }
+            selected
+        },
+        None => table.signals.iter().collect()
+    };
+    let in_window: Vec<usize> = table.time.iter().enumerate()
+        .filter(|(_, t)| from.is_none_or(|from| **t >= from) && to.is_none_or(|to| **t <= to))
+        .map(|(index, _)| index)
+        .collect();
+    let time: Vec<f64> = in_window.iter().map(|index| table.time[*index]).collect();
+    let mut series = Vec::with_capacity(selected.len());
+    for signal in selected {
+        let values: Vec<f64> = in_window.iter().map(|index| signal.values[*index]).collect();
+        let (time, values) = match max_points {
+            Some(max_points) => lttb(&time, &values, max_points),
+            None => (time.clone(), values)
+        };
+        series.push(Series { name: signal.name.clone(), time, values });
+    }
+    Ok(series)
+}
+
+#[doc = "Downsample a signal with the Largest-Triangle-Three-Buckets algorithm"]
+pub fn lttb(time: &[f64], values: &[f64], threshold: usize) -> (Vec<f64>, Vec<f64>) {
+    let length = time.len();
+    if threshold >= length || threshold < 3 {
+        return (time.to_vec(), values.to_vec())
+    }
+    let mut sampled_time = Vec::with_capacity(threshold);
+    let mut sampled_values = Vec::with_capacity(threshold);
+    sampled_time.push(time[0]);
+    sampled_values.push(values[0]);
+
+    // The first and last points are always kept, the rest are split into buckets
+    let every = (length - 2) as f64 / (threshold - 2) as f64;
+    let mut previous = 0;
+    for bucket in 0..threshold - 2 {
+        let next_start = ((bucket + 1) as f64 * every) as usize + 1;
+        let next_end = (((bucket + 2) as f64 * every) as usize + 1).min(length);
+        let next_count = (next_end - next_start) as f64;
+        let average_time = time[next_start..next_end].iter().sum::<f64>() / next_count;
+        let average_value = values[next_start..next_end].iter().sum::<f64>() / next_count;
+
+        let start = (bucket as f64 * every) as usize + 1;
+        let end = next_start;
+        let mut largest_area = -1.0;
+        let mut chosen = start;
+        for index in start..end {
+            let area = ((time[previous] - average_time) * (values[index] - values[previous])
+                      - (time[previous] - time[index]) * (average_value - values[previous])).abs();
+            if area > largest_area {
+                largest_area = area;
+                chosen = index;
+            }
+        }
+        sampled_time.push(time[chosen]);
+        sampled_values.push(values[chosen]);
+        previous = chosen;
+    }
+
+    sampled_time.push(time[length - 1]);
+    sampled_values.push(values[length - 1]);
+    (sampled_time, sampled_values)
+}
+
+// Parsed results are kept so that repeated chart requests don't download
+// and parse the file again. Only files that parse as CSV are cached, and
+// the worker replaces the placeholder results file once, when it finishes,
+// so a cached table never goes stale. A long simulation with many signals
+// parses to hundreds of megabytes, so the cache is bounded by the size of
+// the tables as well as their number, and tables over a share of that
+// bound are not cached at all.
+const CACHE_CAPACITY: usize = 32;
+pub const CACHE_MAX_BYTES: usize = 256 * 1024 * 1024;
+pub const CACHE_MAX_TABLE_BYTES: usize = CACHE_MAX_BYTES / 8;
+
+#[derive(Default)]
+struct ResultsCache {
+    order:  VecDeque<String>,
+    tables: HashMap<String, Arc<ResultsTable>>,
+    bytes:  usize
+}
+
+static CACHE: LazyLock<Mutex<ResultsCache>> = LazyLock::new(|| Mutex::new(ResultsCache::default()));
+
+#[doc = "The approximate size of a parsed results file in memory"]
+pub fn table_bytes(table: &ResultsTable) -> usize {
+    let samples = table.time.len() + table.signals.iter().map(|signal| signal.values.len()).sum::<usize>();
+    let names: usize = table.signals.iter().map(|signal| signal.name.len()).sum();
+    samples * std::mem::size_of::<f64>() + names
+}
+
+#[doc = "Look up a parsed results file in the cache"]
+pub fn cached_table(results_id: &str) -> Option<Arc<ResultsTable>> {
+    let cache = CACHE.lock().unwrap();
+    cache.tables.get(results_id).cloned()
+}
+
+#[doc = "Add a parsed results file to the cache, dropping the oldest entries when it is full"]
+pub fn cache_table(results_id: &str, table: ResultsTable) -> Arc<ResultsTable> {
+    let table = Arc::new(table);
+    let bytes = table_bytes(&table);
+    if bytes > CACHE_MAX_TABLE_BYTES {
+        return table
+    }
+    let mut cache = CACHE.lock().unwrap();
+    match cache.tables.insert(results_id.to_string(), table.clone()) {
+        Some(replaced) => cache.bytes -= table_bytes(&replaced),
+        None => cache.order.push_back(results_id.to_string())
+    }
+    cache.bytes += bytes;
+    while cache.order.len() > CACHE_CAPACITY || cache.bytes > CACHE_MAX_BYTES {
+        if let Some(oldest) = cache.order.pop_front() {
+            if let Some(removed) = cache.tables.remove(&oldest) {
+                cache.bytes -= table_bytes(&removed);
+            }
+        }
+    }
+    table
+}
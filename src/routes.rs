@@ -1,28 +1,81 @@
 use rocket::response::{self, Redirect, Responder, Response};
-use rocket::serde::json::{Json};
+use rocket::serde::json::{self, Json};
 use async_global_executor::block_on;
 use crate::db;
 use crate::amqp;
 use crate::amqp::AMQPSimulation;
 use rocket_dyn_templates::{Template};
-use std::{ str, fmt };
+use std::{ str, fmt, convert::Infallible };
 use rocket_okapi::{ openapi, OpenApiError,
                     response::OpenApiResponderInner,
                     gen::OpenApiGenerator };
 use okapi::openapi3::Responses;
 use serde::{ Serialize, Deserialize };
 use rocket::http::{ContentType, Status};
-use rocket::{Request};
+use rocket::{Request, Shutdown};
+use rocket::response::stream::{Event as StreamEvent, EventStream};
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::error::RecvError;
 use schemars::JsonSchema;
 use crate::file_service;
+use crate::file_service::FileServiceError;
 use http::uri::InvalidUri as InvalidUri;
 use log::info;
+use crate::compare;
+use crate::compare::SimulationComparison;
+use crate::contingency;
+use crate::contingency::{Contingency, ContingencyJob, ContingencyReport};
+use crate::limits;
+use crate::limits::Limits;
+use crate::report;
+use crate::report::SimulationReport;
+use crate::events;
+use crate::export;
+use crate::export::{ExportedResults, ExportFormat, ExportMetadata};
+use crate::events::Event;
+use crate::realtime;
+use crate::realtime::RealTimeConfig;
+use crate::results;
+use crate::results::{ResultsSeries, ResultsTable};
+use std::sync::Arc;
+use crate::simulation_time;
+use crate::simulation_time::SimulationTime;
+use crate::status;
+use crate::status::{SimulationStatus, StatusEvent, StatusUpdate};
+use crate::webhook;
+use crate::idempotency;
+use crate::creation::CreationSaga;
+use crate::template;
+use crate::search;
+use crate::metrics;
+use crate::logging;
+use crate::cim::{self, ModelValidation, ValidationForm};
+use crate::load_profile::{self, LoadProfile, LoadProfileArray, LoadProfileCheck, LoadProfileFile, LoadProfileForm,
+                          LoadProfileFormat, LoadProfilePreview, LoadProfileUpload};
+use crate::model;
+use crate::provenance::{self, Provenance, ProvenanceReport};
+use crate::model::{Model, ModelArray, ModelFiles, ModelForm, ModelRef};
+use crate::audit;
+use crate::audit::{AuditArray, AuditContext, AuditOperation};
+use crate::telemetry;
+use crate::health;
+use crate::health::{Health, Readiness};
+use crate::search::MetadataPatch;
+use crate::template::{SimulationParameters, SimulationRequest, SimulationTemplate, TemplateArray, TemplateForm};
+use std::collections::BTreeMap;
+use crate::idempotency::{CreationResponse, IdempotencyKey};
+use crate::webhook::{Delivery, DeliveryArray, Webhook, WebhookArray, WebhookForm};
 
-#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[doc = "Struct for encapsulation Simulation details"]
 pub struct Simulation {
     pub error: String,
-    pub load_profile_id:   String,
+    #[serde(default)]
+    pub status:            SimulationStatus,
+    #[serde(default)]
+    pub progress:          u8,
+    #[serde(default, deserialize_with = "load_profile::deserialize_file_id")]
+    pub load_profile_id:   Option<String>,
     pub model_id:          String,
     pub results_id:        String,
     pub results_data:      String,
@@ -30,8 +83,40 @@ pub struct Simulation {
     pub simulation_type:   SimulationType,
     pub domain:            DomainType,
     pub solver:            SolverType,
-    pub timestep:          u64,
-    pub finaltime:         u64
+    pub timestep:          SimulationTime,
+    pub finaltime:         SimulationTime,
+    #[serde(default)]
+    pub contingencies:     Vec<Contingency>,
+    #[serde(default)]
+    pub all_n_minus_1:     bool,
+    #[serde(default)]
+    pub contingency_jobs:  Vec<ContingencyJob>,
+    #[serde(default)]
+    pub events:            Vec<Event>,
+    #[serde(default)]
+    pub realtime:          Option<RealTimeConfig>,
+    #[serde(default)]
+    pub limits:            Limits,
+    #[serde(default)]
+    pub report:            Option<SimulationReport>,
+    #[serde(default)]
+    pub worker_parameters: BTreeMap<String, String>,
+    #[serde(default)]
+    pub template_id:       Option<u64>,
+    #[serde(default)]
+    pub rerun_of:          Option<u64>,
+    #[serde(default)]
+    pub name:              String,
+    #[serde(default)]
+    pub description:       String,
+    #[serde(default)]
+    pub labels:            BTreeMap<String, String>,
+    #[serde(default)]
+    pub model:             Option<ModelRef>,
+    #[serde(default)]
+    pub model_files:       Vec<String>,
+    #[serde(default)]
+    pub load_profile:      Option<u64>
 }
 
 impl fmt::Display for Simulation {
@@ -51,6 +136,22 @@ pub struct SimulationSummary {
     pub simulation_id:     u64,
     pub model_id:          String,
     pub simulation_type:   SimulationType,
+    #[serde(default)]
+    pub name:              String,
+    #[serde(default)]
+    pub labels:            BTreeMap<String, String>
+}
+
+impl SimulationSummary {
+    pub fn from_simulation(sim: Simulation) -> SimulationSummary {
+        SimulationSummary {
+            simulation_id:     sim.simulation_id,
+            model_id:          sim.model_id,
+            simulation_type:   sim.simulation_type,
+            name:              sim.name,
+            labels:            sim.labels
+        }
+    }
 }
 
 #[doc = "Enum for the various Simulation types"]
@@ -105,55 +206,201 @@ impl SimulationType {
 ///   - String
 ///   - must be one of "Powerflow", "Outage"
 /// * load_profile_id
-///   - String
+///   - optional String, no load profile is used without one
 ///   - must be a valid id that exists in the associated sogno file service
+/// * load_profile
+///   - instead of load_profile_id, the id of a registered [`LoadProfile`], which is
+///     checked against the loads of the model and the finaltime
 /// * model_id
 ///   - String
 ///   - must be a valid id that exists in the associated sogno file service
+/// * model
+///   - instead of model_id, a [`ModelRef`] to a registered model, e.g. `{ "name": "cigre-mv", "version": 2 }`
+///   - the latest version when no version is given
+/// * timestep, finaltime
+///   - seconds as a number, or a string with a unit: "50us", "20ms", "0.1s"
+///   - see [`SimulationTime`]
+/// * contingencies
+///   - list of `{ "element_type": "Line" | "Transformer" | "Generator", "mrid": String }`
+///   - only for "Outage", one worker job is queued per contingency
+/// * all_n_minus_1
+///   - bool
+///   - only for "Outage", instead of contingencies: every line, transformer and generator of the model
+///     is taken out in turn, one worker job each
+/// * events
+///   - list of [`Event`], e.g. `{ "kind": "Fault", "time": 0.1, "clear_time": 0.2, "component": "bus5" }`
+///   - times are in seconds and must not be after finaltime
+/// * realtime
+///   - optional [`RealTimeConfig`], runs the simulation in real time through a Shmem, Mqtt or Villas interface
+/// * limits
+///   - optional [`Limits`], the voltage band, loading and per component limits used for
+///     the report and the contingency analysis. Defaults to 0.9 - 1.1 pu and 100% loading
+/// * worker_parameters
+///   - optional map of strings added to the parameters sent to the worker, e.g. `{ "executable": "..." }`
+/// * name, description
+///   - optional String, for finding the simulation again with `GET /simulation/search`
+/// * labels
+///   - optional map of label to String, e.g. `{ "study": "aachen-feeder" }`
 #[derive(FromForm, Debug, Serialize, Deserialize, JsonSchema)]
 pub struct SimulationForm {
     pub simulation_type:   SimulationType,
+    #[serde(default)]
+    #[field(default_with = Some(String::new()))]
     pub model_id:          String,
-    pub load_profile_id:   String,
+    #[serde(default, deserialize_with = "load_profile::deserialize_file_id")]
+    pub load_profile_id:   Option<String>,
     #[field(default = DomainType::SP)]
     pub domain:            DomainType,
     #[field(default = SolverType::NRP)]
     pub solver:            SolverType,
-    #[field(default = 1)]
-    pub timestep:          u64,
-    #[field(default = 30)]
-    pub finaltime:         u64
+    #[field(default_with = Some(SimulationTime::from_secs(1)))]
+    pub timestep:          SimulationTime,
+    #[field(default_with = Some(SimulationTime::from_secs(30)))]
+    pub finaltime:         SimulationTime,
+    #[serde(default)]
+    pub contingencies:     Vec<Contingency>,
+    #[serde(default)]
+    pub all_n_minus_1:     bool,
+    #[serde(default)]
+    pub events:            Vec<Event>,
+    #[serde(default)]
+    pub realtime:          Option<RealTimeConfig>,
+    #[serde(default)]
+    pub limits:            Limits,
+    #[serde(default)]
+    pub worker_parameters: BTreeMap<String, String>,
+    #[serde(default)]
+    pub name:              String,
+    #[serde(default)]
+    pub description:       String,
+    #[serde(default)]
+    pub labels:            BTreeMap<String, String>,
+    #[serde(default)]
+    pub model:             Option<ModelRef>,
+    #[serde(default)]
+    pub load_profile:      Option<u64>
 }
 
-async fn parse_simulation_form(form: Json<SimulationForm>) -> Result<Json<Simulation>, SimulationError>{
-    let simulation_id = match db::get_new_simulation_id() {
-        Ok(id) => id,
-        Err(e) => return Err(SimulationError {
-                             err: format!("Failed to obtain new simulation id: {}", e),
-                             http_status_code: Status::BadGateway
-                         })
+#[doc = "Check a simulation form, returning its events in time order"]
+fn validate_simulation_form(form: &SimulationForm) -> Result<Vec<Event>, SimulationError> {
+    if let Err(e) = simulation_time::validate_time_parameters(form.timestep, form.finaltime) {
+        return Err(SimulationError { err: e, http_status_code: Status::BadRequest })
+    }
+    if let Err(e) = contingency::validate_form(form) {
+        return Err(SimulationError { err: e, http_status_code: Status::BadRequest })
+    }
+    let events = match events::validate_events(&form.events, form.finaltime) {
+        Ok(events) => events,
+        Err(e) => return Err(SimulationError { err: e, http_status_code: Status::BadRequest })
     };
-    let results_file     = file_service::create_results_file().await?;
+    if let Err(e) = limits::validate_limits(&form.limits) {
+        return Err(SimulationError { err: e, http_status_code: Status::BadRequest })
+    }
+    if let Some(config) = &form.realtime {
+        if let Err(e) = realtime::validate_config(config) {
+            return Err(SimulationError { err: e, http_status_code: Status::BadRequest })
+        }
+    }
+    if let Err(e) = template::validate_worker_parameters(&form.worker_parameters) {
+        return Err(SimulationError { err: e, http_status_code: Status::BadRequest })
+    }
+    if let Err(e) = search::validate_labels(&form.labels) {
+        return Err(SimulationError { err: e, http_status_code: Status::BadRequest })
+    }
+    Ok(events)
+}
+
+#[doc = "The steps of creating a simulation, recorded in the saga so that they can be undone"]
+async fn run_creation_steps(saga: &mut CreationSaga, form: &SimulationForm, events: Vec<Event>, model_files: ModelFiles,
+                            load_profile: LoadProfileFile, template_id: Option<u64>, rerun_of: Option<u64>) -> Result<Simulation, SimulationError> {
+    let contingencies    = telemetry::step_async("simulation.resolve_contingencies",
+                                                 contingency::contingencies(form, &model_files.file_ids)).await?;
+    let simulation_id    = telemetry::step("simulation.reserve_id", || saga.reserve_id())?;
+    let (results_file, contingency_jobs) = telemetry::step_async("simulation.create_results_files", async {
+        let results_file = saga.create_results_file().await?;
+        let mut contingency_jobs = Vec::new();
+        for contingency in contingencies {
+            contingency_jobs.push(ContingencyJob::new(contingency, saga.create_results_file().await?));
+        }
+        Ok::<_, SimulationError>((results_file, contingency_jobs))
+    }).await?;
     let simulation = Simulation {
         error:           "".to_string(),
-        load_profile_id: form.load_profile_id.clone(),
+        status:          SimulationStatus::Queued,
+        progress:        0,
+        load_profile_id: load_profile.file_id,
         model_id:        form.model_id.clone(),
         results_id:      results_file,
         results_data:    "".into(),
-        simulation_id:   simulation_id,
+        simulation_id,
         simulation_type: form.simulation_type,
         domain:          form.domain,
         solver:          form.solver,
         timestep:        form.timestep,
-        finaltime:       form.finaltime
+        finaltime:       form.finaltime,
+        contingencies:   form.contingencies.clone(),
+        all_n_minus_1:   form.all_n_minus_1,
+        contingency_jobs,
+        events,
+        realtime:        form.realtime.clone(),
+        limits:          form.limits.clone(),
+        report:          None,
+        worker_parameters: form.worker_parameters.clone(),
+        template_id,
+        rerun_of,
+        name:            form.name.clone(),
+        description:     form.description.clone(),
+        labels:          form.labels.clone(),
+        model:           model_files.model,
+        model_files:     model_files.file_ids,
+        load_profile:    load_profile.load_profile.map(|load_profile| load_profile.load_profile_id)
     };
-    match db::write_simulation(&simulation_id.to_string(), &simulation) {
-        Ok(()) => Ok(Json(simulation)),
-        Err(e) => Err(SimulationError {
-                      err: format!("Could not write to db: {}", e.to_string()),
-                      http_status_code: Status::BadGateway
-                  })
-    }
+
+    let (model_urls, load_profile_url) = telemetry::step_async("simulation.resolve_urls", async {
+        let mut model_urls   = Vec::with_capacity(simulation.model_files.len());
+        for file_id in &simulation.model_files {
+            model_urls.push(file_service::convert_id_to_url(file_id).await?);
+        }
+        let load_profile_url = match &simulation.load_profile_id {
+            Some(load_profile_id) => {
+                info!("Converting load profile {} to url", load_profile_id);
+                Some(file_service::convert_id_to_url(load_profile_id).await?)
+            },
+            None => None
+        };
+        Ok::<_, SimulationError>((model_urls, load_profile_url))
+    }).await?;
+    let amqp_sim         = AMQPSimulation::from_simulation(&simulation, model_urls, load_profile_url);
+    let amqp_jobs: Vec<AMQPSimulation> = if simulation.contingency_jobs.is_empty() {
+        vec![amqp_sim]
+    } else {
+        simulation.contingency_jobs.iter().map(|job| amqp_sim.for_contingency(job)).collect()
+    };
+    let inputs = telemetry::step_async("simulation.hash_inputs",
+                                       provenance::hash_inputs(&simulation.model_files, simulation.load_profile_id.as_deref())).await?;
+    let provenance = Provenance {
+        inputs,
+        worker_messages: amqp_jobs.iter().map(amqp::worker_message).collect(),
+        ..Default::default()
+    };
+    telemetry::step("simulation.write_record", || saga.write_record(simulation.clone()))?;
+    telemetry::step("simulation.write_provenance", || {
+        db::write_provenance(simulation_id, &provenance).map_err(|e| SimulationError {
+            err: format!("Could not write provenance to db: {}", e),
+            http_status_code: Status::BadGateway
+        })
+    })?;
+
+    telemetry::step("simulation.publish_jobs", || {
+        match block_on(amqp::request_simulations(&provenance.worker_messages)) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(SimulationError {
+                err: format!("Could not publish to amqp server: {}", e),
+                http_status_code: Status::BadGateway
+            })
+        }
+    })?;
+    Ok(simulation)
 }
 
 #[derive(Debug, Default, serde::Serialize, schemars::JsonSchema)]
@@ -163,12 +410,24 @@ pub struct SimulationError {
     pub http_status_code: rocket::http::Status,
 }
 
+impl fmt::Display for SimulationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.err, self.http_status_code)
+    }
+}
+
 impl From<hyper::Error> for SimulationError {
     fn from(input: hyper::Error) -> Self {
         return SimulationError { err: format!("Error converting url: {}", input), http_status_code: rocket::http::Status{ code: 500 } }
     }
 }
 
+impl From<FileServiceError> for SimulationError {
+    fn from(input: FileServiceError) -> Self {
+        SimulationError { err: input.to_string(), http_status_code: Status::BadGateway }
+    }
+}
+
 impl From<InvalidUri> for SimulationError {
     fn from(input: InvalidUri) -> Self {
         return SimulationError { err: format!("Error converting uri: {}", input), http_status_code: rocket::http::Status{ code: 500 } }
@@ -246,36 +505,307 @@ pub async fn get_root() -> Redirect {
     Redirect::to(uri!(get_api))
 }
 
+#[doc = "Fetch the contents of a results file from the sogno file service"]
+async fn fetch_results(results_id: &str) -> Result<String, SimulationError> {
+    let uri: String = match file_service::convert_id_to_url(results_id).await {
+        Ok(url) => url,
+        Err(e) => return Err( SimulationError {
+                                  err: format!("Could not read convert results id to url. Results id:{} Error: {}", results_id, e),
+                                  http_status_code: Status::Unauthorized
+                              })
+    };
+    let data = file_service::get_data_from_url(&uri).await;
+    match data {
+        Ok(boxed_data) => {
+            Ok(std::str::from_utf8(&boxed_data).unwrap().into())
+        },
+        Err(e) => Err( SimulationError {
+                           err: format!("Could not read results from url. Results id:{} Error: {}", results_id, e),
+                           http_status_code: Status::Unauthorized
+                       })
+    }
+}
+
+#[doc = "Fetch a results file and parse it as a DPsim CSV file, using the cache when possible"]
+async fn fetch_results_table(results_id: &str) -> Result<Arc<ResultsTable>, SimulationError> {
+    if let Some(table) = results::cached_table(results_id) {
+        return Ok(table)
+    }
+    let data = fetch_results(results_id).await?;
+    match results::parse_csv(&data) {
+        Ok(table) => Ok(results::cache_table(results_id, table)),
+        Err(e) => Err(SimulationError {
+            err: format!("Could not parse results. Results id:{} Error: {}", results_id, e),
+            http_status_code: Status::UnprocessableEntity
+        })
+    }
+}
+
 #[doc = "Get the details for a simulation"]
 #[openapi]
 #[get("/simulation/<id>", format="application/json")]
 pub async fn get_simulation_id(id: u64) -> SimulationResult {
-    match db::read_simulation(id) {
+    match telemetry::step("simulation.read", || db::read_simulation(id)) {
         Ok(mut sim) => {
-            let uri: String = match file_service::convert_id_to_url(&sim.results_id).await {
-                Ok(url) => url,
-                Err(e) => return Err( SimulationError {
-                                          err: format!("Could not read convert results id to url. Results id:{} Error: {}", sim.results_id, e),
-                                          http_status_code: Status::Unauthorized
-                                      })
-            };
-            let data = file_service::get_data_from_url(&uri).await;
-            let results: String = match data {
-                Ok(boxed_data) => {
-                    std::str::from_utf8(&boxed_data).unwrap().into()
-                },
-                Err(e) => return Err( SimulationError {
-                                          err: format!("Could not read results from url. Results id:{} Error: {}", sim.results_id, e),
-                                          http_status_code: Status::Unauthorized
-                                      })
-            };
-            sim.results_data = results;
+            // A simulation that could not be started has no results file
+            if !sim.results_id.is_empty() {
+                sim.results_data = telemetry::step_async("simulation.fetch_results", fetch_results(&sim.results_id)).await?;
+            }
             Ok(Json(sim))
         },
         Err(e) =>  Err( SimulationError { err: e.to_string(), http_status_code: Status::UnprocessableEntity } )
     }
 }
 
+/// # Export the results of a simulation
+///
+/// ## Parameters:
+/// * format
+///   - one of "Csv", "Json", "Ndjson", "Parquet". Csv when not given
+/// * columns
+///   - comma separated signal names, e.g. "n1.v,n2.v". All signals when not given
+///
+/// The simulation id, model id, solver, domain and timestep are returned
+/// as X-Simulation-Id, X-Model-Id, X-Solver, X-Domain and X-Timestep headers.
+#[openapi]
+#[get("/simulation/<id>/results?<format>&<columns>")]
+pub async fn get_simulation_results(id: u64, format: Option<ExportFormat>, columns: Option<String>)
+                                    -> Result<ExportedResults, SimulationError> {
+    let sim = match db::read_simulation(id) {
+        Ok(sim) => sim,
+        Err(e) => return Err( SimulationError { err: e.to_string(), http_status_code: Status::UnprocessableEntity } )
+    };
+    let table = fetch_results_table(&sim.results_id).await?;
+    let selected = match &columns {
+        Some(columns) => {
+            let names: Vec<&str> = columns.split(',').map(|name| name.trim()).filter(|name| !name.is_empty()).collect();
+            match table.select(&names) {
+                Ok(selected) => selected,
+                Err(e) => return Err( SimulationError { err: e, http_status_code: Status::BadRequest } )
+            }
+        },
+        None => (*table).clone()
+    };
+    match export::export(&selected, ExportMetadata::from_simulation(&sim), format.unwrap_or_default()) {
+        Ok(exported) => Ok(exported),
+        Err(e) => Err( SimulationError { err: e, http_status_code: Status::InternalServerError } )
+    }
+}
+
+/// # Get selected signals from the results of a simulation
+///
+/// ## Parameters:
+/// * signals
+///   - comma separated signal names, e.g. "n1.v,n2.v". All signals when not given
+/// * from, to
+///   - optional time window, as for [`SimulationTime`]
+/// * max_points
+///   - optional, downsamples each signal to at most this many points (at least 3)
+#[openapi]
+#[get("/simulation/<id>/results/series?<signals>&<from>&<to>&<max_points>", format="application/json")]
+pub async fn get_simulation_results_series(id: u64, signals: Option<String>, from: Option<SimulationTime>,
+                                           to: Option<SimulationTime>, max_points: Option<usize>)
+                                           -> Result<Json<ResultsSeries>, SimulationError> {
+    let sim = match db::read_simulation(id) {
+        Ok(sim) => sim,
+        Err(e) => return Err( SimulationError { err: e.to_string(), http_status_code: Status::UnprocessableEntity } )
+    };
+    let table = fetch_results_table(&sim.results_id).await?;
+    let names: Option<Vec<&str>> = signals.as_ref().map(|signals| {
+        signals.split(',').map(|name| name.trim()).filter(|name| !name.is_empty()).collect()
+    });
+    match results::select_series(&table, names.as_deref(), from.map(|t| t.as_secs_f64()),
+                                 to.map(|t| t.as_secs_f64()), max_points) {
+        Ok(series) => Ok(Json(ResultsSeries { simulation_id: id, series })),
+        Err(e) => Err( SimulationError { err: e, http_status_code: Status::BadRequest } )
+    }
+}
+
+#[doc = "Get statistics and limit violations for the results of a simulation, generated on the first request"]
+#[openapi]
+#[get("/simulation/<id>/report", format="application/json")]
+pub async fn get_simulation_report(id: u64) -> Result<Json<SimulationReport>, SimulationError> {
+    let sim = match db::read_simulation(id) {
+        Ok(sim) => sim,
+        Err(e) => return Err( SimulationError { err: e.to_string(), http_status_code: Status::UnprocessableEntity } )
+    };
+    if let Some(report) = sim.report {
+        return Ok(Json(report))
+    }
+    let table = fetch_results_table(&sim.results_id).await?;
+    let report = report::generate(id, &sim.results_id, &table, &sim.limits);
+    // Only the report is written, and only if no other request has stored one
+    // for the same results in the meantime, so that other changes are kept
+    let stored = db::update_simulation(id, |sim| {
+        if sim.report.is_none() && sim.results_id == report.results_id {
+            sim.report = Some(report.clone());
+        }
+        Ok::<(), Infallible>(())
+    });
+    match stored {
+        Ok(Ok((_, sim))) => Ok(Json(sim.report.unwrap_or(report))),
+        Ok(Err(never)) => match never {},
+        Err(e) => Err(SimulationError {
+                      err: format!("Could not write to db: {}", e),
+                      http_status_code: Status::BadGateway
+                  })
+    }
+}
+
+/// # Compare the parameters and results of two or more simulations
+///
+/// ## Parameters:
+/// * ids
+///   - comma separated simulation ids, e.g. "1,2,3". The first is the base case
+/// * tolerance
+///   - optional, the largest absolute error for the results of a simulation to pass
+#[openapi]
+#[get("/simulation/compare?<ids>&<tolerance>", format="application/json")]
+pub async fn get_simulation_comparison(ids: String, tolerance: Option<f64>) -> Result<Json<SimulationComparison>, SimulationError> {
+    let ids = match compare::parse_ids(&ids) {
+        Ok(ids) => ids,
+        Err(e) => return Err( SimulationError { err: e, http_status_code: Status::BadRequest } )
+    };
+    let mut simulations = Vec::with_capacity(ids.len());
+    for id in &ids {
+        match db::read_simulation(*id) {
+            Ok(sim) => simulations.push(sim),
+            Err(e) => return Err( SimulationError {
+                                      err: format!("Could not read simulation {} from redis DB: {}", id, e),
+                                      http_status_code: Status::UnprocessableEntity
+                                  })
+        }
+    }
+    let base = fetch_results_table(&simulations[0].results_id).await?;
+    let mut results = Vec::with_capacity(ids.len() - 1);
+    for sim in &simulations[1..] {
+        let comparison = match fetch_results_table(&sim.results_id).await {
+            Ok(table) => compare::compare_results(sim.simulation_id, &base, &table, tolerance),
+            Err(e) => compare::failed_results_comparison(sim.simulation_id, tolerance, e.err)
+        };
+        results.push(comparison);
+    }
+    Ok(Json(SimulationComparison {
+        base_id:               ids[0],
+        parameter_differences: compare::compare_parameters(&simulations),
+        ids,
+        results
+    }))
+}
+
+#[doc = "Report a change in the status or progress of a simulation, as the worker does"]
+#[openapi]
+#[put("/simulation/<id>/status", format = "application/json", data = "<update>")]
+pub async fn put_simulation_status(id: u64, update: Json<StatusUpdate>, audit_context: AuditContext) -> SimulationResult {
+    if let Err(e) = status::validate_update(&update) {
+        return Err( SimulationError { err: e, http_status_code: Status::BadRequest } )
+    }
+    let (before, sim) = match db::update_simulation(id, |sim| status::apply_update(sim, &update)) {
+        Ok(Ok(changed)) => changed,
+        Ok(Err(e)) => return Err( SimulationError { err: e, http_status_code: Status::Conflict } ),
+        Err(e) => return Err(SimulationError {
+                      err: format!("Could not update simulation in db: {}", e),
+                      http_status_code: Status::BadGateway
+                  })
+    };
+    audit::record(&audit_context, audit::status_operation(&sim), Some(&before), &sim);
+    if update.worker_version.is_some() || update.dpsim_version.is_some() {
+        if let Err(e) = db::update_provenance(id, |provenance| provenance::apply_update(provenance, &update)) {
+            info!("Could not record the versions of the worker for simulation {}: {}", id, e);
+        }
+    }
+    let event = StatusEvent::from_simulation(&sim);
+    webhook::notify(&event);
+    status::publish(event);
+    Ok(Json(sim))
+}
+
+#[doc = "Stream the status changes of every simulation as Server-Sent Events"]
+#[openapi(skip)]
+#[get("/events")]
+pub async fn get_events(mut shutdown: Shutdown) -> EventStream![] {
+    let mut receiver = status::subscribe();
+    EventStream! {
+        loop {
+            let event = select! {
+                event = receiver.recv() => match event {
+                    Ok(event) => event,
+                    Err(RecvError::Closed) => break,
+                    Err(RecvError::Lagged(_)) => continue
+                },
+                _ = &mut shutdown => break
+            };
+            yield StreamEvent::json(&event).event("status");
+        }
+    }
+}
+
+#[doc = "Stream the status changes of a simulation as Server-Sent Events, starting with its current status"]
+#[openapi(skip)]
+#[get("/simulation/<id>/events")]
+pub async fn get_simulation_events(id: u64, mut shutdown: Shutdown) -> Result<EventStream![], SimulationError> {
+    // Subscribe before reading the current status so that no change is missed
+    let mut receiver = status::subscribe();
+    let current = match db::read_simulation(id) {
+        Ok(sim) => StatusEvent::from_simulation(&sim),
+        Err(e) => return Err( SimulationError { err: e.to_string(), http_status_code: Status::UnprocessableEntity } )
+    };
+    Ok(EventStream! {
+        let mut finished = current.status.is_finished();
+        yield StreamEvent::json(&current).event("status");
+        while !finished {
+            let event = select! {
+                event = receiver.recv() => match event {
+                    Ok(event) => event,
+                    Err(RecvError::Closed) => break,
+                    Err(RecvError::Lagged(_)) => continue
+                },
+                _ = &mut shutdown => break
+            };
+            if event.simulation_id != id {
+                continue
+            }
+            finished = event.status.is_finished();
+            yield StreamEvent::json(&event).event("status");
+        }
+    })
+}
+
+#[doc = "Get the contingency report for an Outage simulation, ranked by the severity of the violations"]
+#[openapi]
+#[get("/simulation/<id>/contingencies", format="application/json")]
+pub async fn get_simulation_contingencies(id: u64) -> Result<Json<ContingencyReport>, SimulationError> {
+    let sim = match db::read_simulation(id) {
+        Ok(sim) => sim,
+        Err(e) => return Err( SimulationError { err: e.to_string(), http_status_code: Status::UnprocessableEntity } )
+    };
+    if !matches!(sim.simulation_type, SimulationType::Outage) {
+        return Err( SimulationError {
+                        err: format!("Simulation {} is not an Outage simulation", id),
+                        http_status_code: Status::BadRequest
+                    })
+    }
+    let limits = &sim.limits;
+    let mut outages = Vec::new();
+    if sim.all_n_minus_1 && sim.contingency_jobs.is_empty() {
+        let table = fetch_results_table(&sim.results_id).await?;
+        for (mrid, outage_table) in contingency::split_by_outage(&table) {
+            outages.push(contingency::outage_report(&mrid, None, &sim.results_id, &outage_table, limits));
+        }
+    }
+    for job in &sim.contingency_jobs {
+        let mrid = &job.contingency.mrid;
+        let element_type = Some(job.contingency.element_type);
+        let outage = match fetch_results_table(&job.results_id).await {
+            Ok(table) => contingency::outage_report(mrid, element_type, &job.results_id, &table, limits),
+            Err(e) => contingency::failed_outage_report(mrid, element_type, &job.results_id, e.err)
+        };
+        outages.push(outage);
+    }
+    contingency::rank_outages(&mut outages);
+    Ok(Json(ContingencyReport { simulation_id: id, outages }))
+}
+
 #[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[doc = "Struct for encapsulation Simulation details"]
 pub struct SimulationArray {
@@ -294,14 +824,7 @@ pub async fn get_simulations() -> Result<Json<SimulationArray>, SimulationError>
             let last_plus_one = number_of_simulations+1;
             for n in 1..last_plus_one {
                 match db::read_simulation(n) {
-                    Ok(sim) => {
-                        let sim_summary = SimulationSummary {
-                            simulation_id:     sim.simulation_id,
-                            model_id:          sim.model_id,
-                            simulation_type:   sim.simulation_type,
-                        };
-                        simvec.push(sim_summary);
-                    }
+                    Ok(sim) => simvec.push(SimulationSummary::from_simulation(sim)),
                     Err(e) => return Err( SimulationError { err: format!("Could not read simulation {} from redis DB: {}", n, e), http_status_code: Status::UnprocessableEntity} )
                 }
             }
@@ -314,35 +837,317 @@ pub async fn get_simulations() -> Result<Json<SimulationArray>, SimulationError>
     }
 }
 
-#[doc = "Create a new simulation"]
+/// # Search the simulations
+///
+/// ## Parameters:
+/// * labels
+///   - optional comma separated label selectors, "key=value" or "key", e.g. "study=aachen-feeder,reviewed"
+/// * q
+///   - optional words that must all be in the name or description
+///
+/// At least one of them must be given.
 #[openapi]
-#[post("/simulation", format = "application/json", data = "<form>")]
-pub async fn post_simulation(form: Json<SimulationForm > ) -> SimulationResult {
-    match parse_simulation_form(form).await {
-        Ok(simulation) => {
-            let model_id         = &simulation.model_id;
-            let load_profile_id  = &simulation.load_profile_id;
-            let model_url        = file_service::convert_id_to_url(model_id).await?;
-            let mut load_profile_url = "".into();
-            let none_string: String = "None".into();
-            info!("load_profile_id: {}", load_profile_id);
-            if simulation.load_profile_id != none_string {
-                info!("Converting {} to url", simulation.load_profile_id);
-                load_profile_url = file_service::convert_id_to_url(load_profile_id).await?;
-            }
-            let amqp_sim         = AMQPSimulation::from_simulation(&simulation, model_url, load_profile_url);
-            match block_on(amqp::request_simulation(&amqp_sim)) {
-                Ok(()) => Ok(simulation),
-                Err(e) => Err(SimulationError {
-                    err: format!("Could not publish to amqp server: {}", e),
-                    http_status_code: Status::BadGateway
-                })
+#[get("/simulation/search?<labels>&<q>", format="application/json")]
+pub async fn get_simulation_search(labels: Option<String>, q: Option<String>) -> Result<Json<SimulationArray>, SimulationError> {
+    let keys = match search::query_keys(labels.as_deref(), q.as_deref()) {
+        Ok(keys) => keys,
+        Err(e) => return Err( SimulationError { err: e, http_status_code: Status::BadRequest } )
+    };
+    let ids = match db::search_index(&keys) {
+        Ok(ids) => ids,
+        Err(e) => return Err( SimulationError { err: format!("Could not search redis DB: {}", e), http_status_code: Status::UnprocessableEntity } )
+    };
+    let mut simulations = Vec::new();
+    for id in ids {
+        match db::read_simulation(id) {
+            Ok(sim) => simulations.push(SimulationSummary::from_simulation(sim)),
+            Err(e) => return Err( SimulationError { err: format!("Could not read simulation {} from redis DB: {}", id, e), http_status_code: Status::UnprocessableEntity } )
+        }
+    }
+    Ok(Json(SimulationArray { simulations }))
+}
+
+#[doc = "Change the name, description or labels of a simulation"]
+#[openapi]
+#[patch("/simulation/<id>", format = "application/json", data = "<patch>")]
+pub async fn patch_simulation(id: u64, patch: Json<MetadataPatch>, audit_context: AuditContext) -> SimulationResult {
+    let (before, sim) = match db::update_simulation(id, |sim| search::apply_patch(sim, &patch)) {
+        Ok(Ok(changed)) => changed,
+        Ok(Err(e)) => return Err( SimulationError { err: e, http_status_code: Status::BadRequest } ),
+        Err(e) => return Err(SimulationError {
+            err: format!("Could not update simulation in db: {}", e),
+            http_status_code: Status::BadGateway
+        })
+    };
+    audit::record(&audit_context, AuditOperation::Patch, Some(&before), &sim);
+    Ok(Json(sim))
+}
+
+#[doc = "Create a new simulation, optionally from a template. A repeated request with the same Idempotency-Key gets the original response"]
+#[openapi]
+#[post("/simulation", format = "application/json", data = "<request>")]
+pub async fn post_simulation(request: Json<SimulationRequest>, idempotency_key: IdempotencyKey,
+                             audit_context: AuditContext) -> Result<CreationResponse, SimulationError> {
+    let result = match idempotency_key.0 {
+        Some(key) => {
+            let request_hash = idempotency::request_hash(&request);
+            if let Some(response) = telemetry::step("simulation.idempotency_key", || idempotency::begin(&key, &request_hash))? {
+                info!("Idempotency-Key '{}' has already been used, returning its response", key);
+                return Ok(CreationResponse::Repeated(response))
             }
+            let result = create_simulation(&request, audit_context).await;
+            idempotency::finish(&key, &request_hash, result.as_ref().ok().map(|sim| &sim.0));
+            result
+        },
+        None => create_simulation(&request, audit_context).await
+    };
+    Ok(CreationResponse::Created(Box::new(result?.into_inner())))
+}
+
+#[doc = "Create a simulation from a request and send its jobs to the worker"]
+async fn create_simulation(request: &SimulationRequest, audit_context: AuditContext) -> SimulationResult {
+    let template = match request.template_id {
+        Some(id) => Some(read_template(id)?),
+        None => None
+    };
+    let form = telemetry::step("simulation.resolve_template", || {
+        template::resolve(template.as_ref().map(|template| &template.parameters), &request.parameters)
+    });
+    let form = match form {
+        Ok(form) => form,
+        Err(e) => return Err(SimulationError { err: e, http_status_code: Status::BadRequest })
+    };
+    start_simulation(&form, request.template_id, None, audit_context).await
+}
+
+#[doc = "Validate a complete simulation form, then create the simulation, send its jobs to the worker and audit it, \
+         as a rerun if there is an original simulation"]
+async fn start_simulation(form: &SimulationForm, template_id: Option<u64>, rerun_of: Option<u64>,
+                          audit_context: AuditContext) -> SimulationResult {
+    let events = telemetry::step("simulation.validate", || validate_simulation_form(form))?;
+    let model_files = telemetry::step("simulation.resolve_model", || model::resolve(&form.model_id, form.model.as_ref()))?;
+    let load_profile = telemetry::step("simulation.resolve_load_profile", || {
+        load_profile::resolve(form.load_profile_id.as_deref(), form.load_profile)
+    })?;
+    if let Some(registered) = &load_profile.load_profile {
+        let check = telemetry::step_async("simulation.check_load_profile",
+                                          load_profile::check_model(registered, &model_files.file_ids, Some(form.finaltime))).await?;
+        if !check.consistent {
+            return Err(SimulationError {
+                err: format!("Load profile {} does not fit the simulation:\n{}", registered.load_profile_id, check.errors()),
+                http_status_code: Status::BadRequest
+            })
+        }
+    }
+    let operation = if rerun_of.is_some() { AuditOperation::Rerun } else { AuditOperation::Create };
+    let mut saga = CreationSaga::new(audit_context.clone(), operation);
+    match run_creation_steps(&mut saga, form, events, model_files, load_profile, template_id, rerun_of).await {
+        Ok(simulation) => {
+            audit::record(&audit_context, operation, None, &simulation);
+            metrics::simulation_created(&simulation);
+            Ok(Json(simulation))
         },
-        Err(e) => Err(e)
+        Err(e) => Err(saga.abort(e).await)
     }
 }
 
+/// # Run an existing simulation again
+///
+/// The new simulation has the parameters of the original one, with its own
+/// results file, and records the id of the original in `rerun_of`.
+///
+/// ## Parameters:
+/// * the body is optional: a JSON merge patch (RFC 7396) of the [`SimulationParameters`]
+///   of the original simulation. Each parameter given in it replaces the one of the
+///   original, and null removes it, e.g. `{ "model_id": "2", "model": null }` runs the
+///   simulation with a model file instead of a registered model
+#[openapi]
+#[post("/simulation/<id>/rerun", data = "<overrides>")]
+pub async fn post_simulation_rerun(id: u64, overrides: Result<Json<serde_json::Value>, json::Error<'_>>,
+                                   audit_context: AuditContext) -> SimulationResult {
+    let overrides = match overrides {
+        Ok(overrides) => overrides.into_inner(),
+        Err(json::Error::Parse(body, _)) if body.trim().is_empty() => serde_json::Value::Object(Default::default()),
+        Err(json::Error::Parse(_, e)) => return Err(SimulationError { err: format!("Invalid overrides: {}", e), http_status_code: Status::BadRequest }),
+        Err(json::Error::Io(e)) => return Err(SimulationError { err: format!("Could not read overrides: {}", e), http_status_code: Status::BadRequest })
+    };
+    let original = match db::read_simulation(id) {
+        Ok(sim) => sim,
+        Err(e) => return Err( SimulationError { err: e.to_string(), http_status_code: Status::UnprocessableEntity } )
+    };
+    let form = match template::resolve_rerun(&SimulationParameters::from_simulation(&original), &overrides) {
+        Ok(form) => form,
+        Err(e) => return Err(SimulationError { err: e, http_status_code: Status::BadRequest })
+    };
+    start_simulation(&form, original.template_id, Some(id), audit_context).await
+}
+
+#[doc = "Save a new simulation template"]
+#[openapi]
+#[post("/template", format = "application/json", data = "<form>")]
+pub async fn post_template(form: Json<TemplateForm>) -> Result<Json<SimulationTemplate>, SimulationError> {
+    if let Err(e) = template::validate_form(&form) {
+        return Err( SimulationError { err: e, http_status_code: Status::BadRequest } )
+    }
+    let template_id = match db::get_new_template_id() {
+        Ok(id) => id,
+        Err(e) => return Err(SimulationError {
+                             err: format!("Failed to obtain new template id: {}", e),
+                             http_status_code: Status::BadGateway
+                         })
+    };
+    let template = SimulationTemplate::from_form(template_id, &form);
+    match db::write_template(&template) {
+        Ok(()) => Ok(Json(template)),
+        Err(e) => Err(SimulationError {
+                      err: format!("Could not write to db: {}", e),
+                      http_status_code: Status::BadGateway
+                  })
+    }
+}
+
+#[doc = "List the simulation templates"]
+#[openapi]
+#[get("/template", format = "application/json")]
+pub async fn get_templates() -> Result<Json<TemplateArray>, SimulationError> {
+    match template::read_templates() {
+        Ok(templates) => Ok(Json(TemplateArray { templates })),
+        Err(e) => Err( SimulationError { err: format!("Could not read templates from redis DB: {}", e), http_status_code: Status::UnprocessableEntity } )
+    }
+}
+
+#[doc = "Read a template, or a 404 SimulationError if it does not exist"]
+fn read_template(id: u64) -> Result<SimulationTemplate, SimulationError> {
+    match db::read_template(id) {
+        Ok(Some(template)) => Ok(template),
+        Ok(None) => Err( SimulationError { err: format!("Template {} does not exist", id), http_status_code: Status::NotFound } ),
+        Err(e) => Err( SimulationError { err: e.to_string(), http_status_code: Status::UnprocessableEntity } )
+    }
+}
+
+#[doc = "Show a simulation template"]
+#[openapi]
+#[get("/template/<id>", format = "application/json")]
+pub async fn get_template(id: u64) -> Result<Json<SimulationTemplate>, SimulationError> {
+    Ok(Json(read_template(id)?))
+}
+
+#[doc = "Replace the name, description and parameters of a simulation template"]
+#[openapi]
+#[put("/template/<id>", format = "application/json", data = "<form>")]
+pub async fn put_template(id: u64, form: Json<TemplateForm>) -> Result<Json<SimulationTemplate>, SimulationError> {
+    read_template(id)?;
+    if let Err(e) = template::validate_form(&form) {
+        return Err( SimulationError { err: e, http_status_code: Status::BadRequest } )
+    }
+    let template = SimulationTemplate::from_form(id, &form);
+    match db::write_template(&template) {
+        Ok(()) => Ok(Json(template)),
+        Err(e) => Err(SimulationError {
+                      err: format!("Could not write to db: {}", e),
+                      http_status_code: Status::BadGateway
+                  })
+    }
+}
+
+#[doc = "Remove a simulation template. Simulations created from it are not changed"]
+#[openapi]
+#[delete("/template/<id>", format = "application/json")]
+pub async fn delete_template(id: u64) -> Result<Json<SimulationTemplate>, SimulationError> {
+    let template = read_template(id)?;
+    match db::delete_template(id) {
+        Ok(()) => Ok(Json(template)),
+        Err(e) => Err(SimulationError {
+                      err: format!("Could not delete from db: {}", e),
+                      http_status_code: Status::BadGateway
+                  })
+    }
+}
+
+#[doc = "Register a webhook that is called when simulations finish"]
+#[openapi]
+#[post("/webhook", format = "application/json", data = "<form>")]
+pub async fn post_webhook(form: Json<WebhookForm>) -> Result<Json<Webhook>, SimulationError> {
+    if let Err(e) = webhook::validate_form(&form) {
+        return Err( SimulationError { err: e, http_status_code: Status::BadRequest } )
+    }
+    let webhook_id = match db::get_new_webhook_id() {
+        Ok(id) => id,
+        Err(e) => return Err(SimulationError {
+                             err: format!("Failed to obtain new webhook id: {}", e),
+                             http_status_code: Status::BadGateway
+                         })
+    };
+    let webhook = Webhook::from_form(webhook_id, &form);
+    match db::write_webhook(&webhook) {
+        Ok(()) => Ok(Json(webhook.without_secret())),
+        Err(e) => Err(SimulationError {
+                      err: format!("Could not write to db: {}", e),
+                      http_status_code: Status::BadGateway
+                  })
+    }
+}
+
+#[doc = "List the webhooks"]
+#[openapi]
+#[get("/webhook", format = "application/json")]
+pub async fn get_webhooks() -> Result<Json<WebhookArray>, SimulationError> {
+    match webhook::read_webhooks() {
+        Ok(webhooks) => Ok(Json(WebhookArray {
+            webhooks: webhooks.iter().map(|webhook| webhook.without_secret()).collect()
+        })),
+        Err(e) => Err( SimulationError { err: format!("Could not read webhooks from redis DB: {}", e), http_status_code: Status::UnprocessableEntity } )
+    }
+}
+
+#[doc = "Read a webhook, or a 404 SimulationError if it does not exist"]
+fn read_webhook(id: u64) -> Result<Webhook, SimulationError> {
+    match db::read_webhook(id) {
+        Ok(Some(webhook)) => Ok(webhook),
+        Ok(None) => Err( SimulationError { err: format!("Webhook {} does not exist", id), http_status_code: Status::NotFound } ),
+        Err(e) => Err( SimulationError { err: e.to_string(), http_status_code: Status::UnprocessableEntity } )
+    }
+}
+
+#[doc = "Remove a webhook and its delivery log"]
+#[openapi]
+#[delete("/webhook/<id>", format = "application/json")]
+pub async fn delete_webhook(id: u64) -> Result<Json<Webhook>, SimulationError> {
+    let webhook = read_webhook(id)?;
+    match db::delete_webhook(id) {
+        Ok(()) => Ok(Json(webhook.without_secret())),
+        Err(e) => Err(SimulationError {
+                      err: format!("Could not delete from db: {}", e),
+                      http_status_code: Status::BadGateway
+                  })
+    }
+}
+
+#[doc = "List the deliveries of a webhook, most recent first"]
+#[openapi]
+#[get("/webhook/<id>/deliveries", format = "application/json")]
+pub async fn get_webhook_deliveries(id: u64) -> Result<Json<DeliveryArray>, SimulationError> {
+    read_webhook(id)?;
+    match db::read_webhook_deliveries(id) {
+        Ok(deliveries) => Ok(Json(DeliveryArray { deliveries })),
+        Err(e) => Err( SimulationError { err: e.to_string(), http_status_code: Status::UnprocessableEntity } )
+    }
+}
+
+#[doc = "Send a sample payload to a webhook, once and without retries"]
+#[openapi]
+#[post("/webhook/<id>/test", format = "application/json")]
+pub async fn post_webhook_test(id: u64) -> Result<Json<Delivery>, SimulationError> {
+    let webhook = read_webhook(id)?;
+    let event = StatusEvent {
+        simulation_id: webhook.simulation_id.unwrap_or(0),
+        status:        webhook.events.first().copied().unwrap_or(SimulationStatus::Succeeded),
+        progress:      100,
+        error:         "".into()
+    };
+    Ok(Json(webhook::deliver(&webhook, &webhook::payload(&event, true), 1).await))
+}
+
 #[doc = "Create a link to the documentation page for the given function"]
 fn document_link(fn_name: &str) -> String {
     format!("https://sogno-platform.github.io/dpsim-api/dpsim_api/routes/fn.{}{}", fn_name, ".html")
@@ -355,7 +1160,218 @@ pub async fn incomplete_form(form: &rocket::Request<'_>) -> String {
     format!("Incomplete form.{}", form)
 }
 
+#[doc = "Register a grid model, or a new version of a model with the same name"]
+#[openapi]
+#[post("/model", format = "application/json", data = "<form>")]
+pub async fn post_model(form: Json<ModelForm>, audit_context: AuditContext) -> Result<Json<Model>, SimulationError> {
+    Ok(Json(model::register(&form, &audit_context.actor).await?))
+}
+
+/// # Where the results of a simulation came from
+///
+/// The digests of its input files, the messages sent to the worker, the
+/// versions the worker reported and a reproducibility hash of them all.
+#[openapi]
+#[get("/simulation/<id>/provenance", format = "application/json")]
+pub async fn get_simulation_provenance(id: u64) -> Result<Json<ProvenanceReport>, SimulationError> {
+    let sim = match db::read_simulation(id) {
+        Ok(sim) => sim,
+        Err(e) => return Err( SimulationError { err: e.to_string(), http_status_code: Status::UnprocessableEntity } )
+    };
+    match db::read_provenance(id) {
+        Ok(Some(provenance)) => Ok(Json(provenance::report(&sim, provenance))),
+        Ok(None) => Err(SimulationError {
+            err: format!("Simulation {} was created before provenance was recorded", id),
+            http_status_code: Status::NotFound
+        }),
+        Err(e) => Err( SimulationError { err: e.to_string(), http_status_code: Status::UnprocessableEntity } )
+    }
+}
+
+#[doc = "Register a load profile that is in the file service"]
+#[openapi]
+#[post("/load_profile", format = "application/json", data = "<form>")]
+pub async fn post_load_profile(form: Json<LoadProfileForm>, audit_context: AuditContext) -> Result<Json<LoadProfile>, SimulationError> {
+    let data = load_profile::read_data(&form.file_id).await?;
+    Ok(Json(load_profile::register(&form, &data, &audit_context.actor)?))
+}
+
+/// # Upload a load profile to the file service and register it
+///
+/// ## Parameters:
+/// * the body is the CSV file, or a zip of CSV files, up to the "file" limit of Rocket.toml
+/// * name
+///   - String, must not be empty
+/// * description
+///   - String, optional
+#[openapi]
+#[post("/load_profile/upload?<name>&<description>", data = "<data>")]
+pub async fn post_load_profile_upload(name: String, description: Option<String>, data: LoadProfileUpload,
+                                      audit_context: AuditContext) -> Result<Json<LoadProfile>, SimulationError> {
+    let data = data.0;
+    // Check the file before anything is stored
+    let analysis = match load_profile::analyse(&data) {
+        Ok(analysis) => analysis,
+        Err(e) => return Err(SimulationError { err: e, http_status_code: Status::BadRequest })
+    };
+    let file_name = match analysis.format {
+        LoadProfileFormat::Csv => format!("{}.csv", name),
+        LoadProfileFormat::Zip => format!("{}.zip", name)
+    };
+    let file_id = match file_service::upload_file(&file_name, data.clone()).await {
+        Ok(file_id) => file_id,
+        Err(e) => return Err(SimulationError { err: format!("Could not upload load profile: {}", e), http_status_code: Status::BadGateway })
+    };
+    let form = LoadProfileForm { name, description: description.unwrap_or_default(), file_id };
+    Ok(Json(load_profile::register(&form, &data, &audit_context.actor)?))
+}
+
+#[doc = "List the registered load profiles"]
+#[openapi]
+#[get("/load_profile", format = "application/json")]
+pub async fn get_load_profiles() -> Result<Json<LoadProfileArray>, SimulationError> {
+    Ok(Json(LoadProfileArray { load_profiles: load_profile::read_all()? }))
+}
+
+#[doc = "Show a registered load profile"]
+#[openapi]
+#[get("/load_profile/<id>", format = "application/json")]
+pub async fn get_load_profile(id: u64) -> Result<Json<LoadProfile>, SimulationError> {
+    Ok(Json(load_profile::read(id)?))
+}
+
+/// # The first rows of a registered load profile
+///
+/// ## Parameters:
+/// * rows
+///   - optional, the number of rows of each CSV file, 10 by default and at most 1000
+#[openapi]
+#[get("/load_profile/<id>/preview?<rows>", format = "application/json")]
+pub async fn get_load_profile_preview(id: u64, rows: Option<usize>) -> Result<Json<LoadProfilePreview>, SimulationError> {
+    let registered = load_profile::read(id)?;
+    let data = load_profile::read_data(&registered.file_id).await?;
+    match load_profile::preview(&data, rows) {
+        Ok(preview) => Ok(Json(preview)),
+        Err(e) => Err(SimulationError { err: e, http_status_code: Status::BadRequest })
+    }
+}
+
+/// # Check a registered load profile against a model and a final time
+///
+/// ## Parameters:
+/// * model_id, or model and optional version
+///   - the model, as in a [`SimulationForm`]
+/// * finaltime
+///   - optional, the finaltime of the simulation
+#[openapi]
+#[get("/load_profile/<id>/check?<model_id>&<model>&<version>&<finaltime>", format = "application/json")]
+pub async fn get_load_profile_check(id: u64, model_id: Option<String>, model: Option<String>, version: Option<u32>,
+                                    finaltime: Option<SimulationTime>) -> Result<Json<LoadProfileCheck>, SimulationError> {
+    let registered = load_profile::read(id)?;
+    let model = model.map(|name| ModelRef { name, version });
+    let model_files = model::resolve(model_id.as_deref().unwrap_or(""), model.as_ref())?;
+    Ok(Json(load_profile::check_model(&registered, &model_files.file_ids, finaltime).await?))
+}
+
+#[doc = "Check the CIM files of a model, without registering it"]
+#[openapi]
+#[post("/model/validate", format = "application/json", data = "<form>")]
+pub async fn post_model_validate(form: Json<ValidationForm>) -> Result<Json<ModelValidation>, SimulationError> {
+    if form.file_ids.is_empty() {
+        return Err(SimulationError { err: "A model needs at least one file".into(), http_status_code: Status::BadRequest })
+    }
+    let files = model::read_files(&form.file_ids).await?;
+    Ok(Json(cim::validate(&files)))
+}
+
+#[doc = "List the latest version of every registered model"]
+#[openapi]
+#[get("/model", format = "application/json")]
+pub async fn get_models() -> Result<Json<ModelArray>, SimulationError> {
+    Ok(Json(ModelArray { models: model::read_latest()? }))
+}
+
+#[doc = "List every version of a registered model"]
+#[openapi]
+#[get("/model/<name>", format = "application/json")]
+pub async fn get_model_versions(name: String) -> Result<Json<ModelArray>, SimulationError> {
+    Ok(Json(ModelArray { models: model::read_versions(&name)? }))
+}
+
+#[doc = "Show a version of a registered model"]
+#[openapi]
+#[get("/model/<name>/<version>", format = "application/json")]
+pub async fn get_model(name: String, version: u32) -> Result<Json<Model>, SimulationError> {
+    Ok(Json(model::read(&name, Some(version))?))
+}
+
+/// # Read the audit log
+///
+/// ## Parameters:
+/// * simulation_id
+///   - optional, only the entries for this simulation
+/// * actor
+///   - optional, only the entries by this actor
+/// * from, to
+///   - optional, seconds since the Unix epoch, only the entries within this range
+#[openapi]
+#[get("/audit?<simulation_id>&<actor>&<from>&<to>", format="application/json")]
+pub async fn get_audit(simulation_id: Option<u64>, actor: Option<String>, from: Option<u64>, to: Option<u64>)
+    -> Result<Json<AuditArray>, SimulationError> {
+    if let (Some(from), Some(to)) = (from, to) {
+        if from > to {
+            return Err(SimulationError { err: "from must not be after to".into(), http_status_code: Status::BadRequest })
+        }
+    }
+    match audit::query(simulation_id, actor.as_deref(), from, to) {
+        Ok(entries) => Ok(Json(AuditArray { entries })),
+        Err(e) => Err(SimulationError {
+            err: format!("Could not read audit log from redis DB: {}", e),
+            http_status_code: Status::UnprocessableEntity
+        })
+    }
+}
+
+#[doc = "Metrics in the Prometheus text format"]
+#[openapi(skip)]
+#[get("/metrics")]
+pub async fn get_metrics() -> (ContentType, String) {
+    (ContentType::with_params("text", "plain", [("version", "0.0.4"), ("charset", "utf-8")]), metrics::render())
+}
+
+#[doc = "Liveness probe: answers as long as the process is serving requests"]
+#[openapi]
+#[get("/healthz")]
+pub async fn get_healthz() -> Json<Health> {
+    Json(Health::ok())
+}
+
+#[doc = "Readiness probe: checks Redis, the AMQP broker and sogno-file-service"]
+#[openapi]
+#[get("/readyz")]
+pub async fn get_readyz() -> (Status, Json<Readiness>) {
+    let readiness = health::readiness().await;
+    if !readiness.ready {
+        info!("Not ready: {:?}", readiness.dependencies);
+    }
+    let status = if readiness.ready { Status::Ok } else { Status::ServiceUnavailable };
+    (status, Json(readiness))
+}
+
 #[doc = "Returns the list of routes that we have defined"]
 pub fn get_routes() -> Vec<rocket::Route>{
-    return rocket_okapi::openapi_get_routes![ get_root, get_api, get_simulations, post_simulation, get_simulation_id]
+    let routes = rocket_okapi::openapi_get_routes![ get_root, get_api, get_simulations, post_simulation, get_simulation_id,
+                                              post_simulation_rerun, get_simulation_search, patch_simulation,
+                                              get_simulation_contingencies, get_simulation_results,
+                                              get_simulation_results_series, get_simulation_report,
+                                              get_simulation_comparison, put_simulation_status, get_events,
+                                              get_simulation_events, post_webhook, get_webhooks, delete_webhook,
+                                              get_webhook_deliveries, post_webhook_test, post_template, get_templates,
+                                              get_template, put_template, delete_template, get_metrics,
+                                              get_healthz, get_readyz, get_audit, post_model, get_models,
+                                              get_model_versions, get_model, post_model_validate,
+                                              post_load_profile, post_load_profile_upload, get_load_profiles,
+                                              get_load_profile, get_load_profile_preview, get_load_profile_check,
+                                              get_simulation_provenance];
+    telemetry::with_tracing(logging::with_log_context(routes))
 }
@@ -0,0 +1,145 @@
+//!
+//! # Searching simulations
+//!
+//! Simulations can be given a name, a description and key/value labels,
+//! and searched by label selector and by the words of their name and
+//! description. The search does not read every simulation: each one is
+//! listed in a set in the db for every label it has and every word in its
+//! name and description, and a search is the intersection of the sets for
+//! its terms.
+//!
+//! | Term        | Matches simulations                             |
+//! |-------------|-------------------------------------------------|
+//! | `key=value` | with the label `key` set to `value`             |
+//! | `key`       | with the label `key`, whatever its value        |
+//! | word of `q` | with the word in their name or description      |
+//!
+//! Words are matched whole and without regard to case.
+//!
+//! Every simulation is also listed in the set for its status, which is
+//! what the simulations gauge of `/metrics` counts.
+//!
+
+use std::collections::{BTreeMap, BTreeSet};
+use serde::{ Serialize, Deserialize };
+use schemars::JsonSchema;
+use crate::routes::Simulation;
+use crate::status::SimulationStatus;
+
+const MAX_LABELS: usize = 64;
+const MAX_LABEL_LENGTH: usize = 128;
+
+/// # Changes to the name, description and labels of a simulation
+///
+/// ## Parameters:
+/// * name, description
+///   - optional String, replaces the current one
+/// * labels
+///   - optional map of label to String or null. A String sets the label, null removes it.
+///     Labels that are not given are left as they are
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct MetadataPatch {
+    #[serde(default)]
+    pub name:        Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub labels:      BTreeMap<String, Option<String>>
+}
+
+#[doc = "Check that labels can be stored and searched for"]
+pub fn validate_labels(labels: &BTreeMap<String, String>) -> Result<(), String> {
+    if labels.len() > MAX_LABELS {
+        return Err(format!("A simulation can have at most {} labels", MAX_LABELS))
+    }
+    for (key, value) in labels {
+        if key.trim().is_empty() {
+            return Err("Label keys must not be empty".into())
+        }
+        if key.contains(['=', ',']) || value.contains(',') {
+            return Err(format!("Label '{}' must not contain ',', and its key must not contain '='", key))
+        }
+        if key.len() > MAX_LABEL_LENGTH || value.len() > MAX_LABEL_LENGTH {
+            return Err(format!("Label '{}' is longer than {} characters", key, MAX_LABEL_LENGTH))
+        }
+    }
+    Ok(())
+}
+
+#[doc = "Apply a metadata patch to a simulation"]
+pub fn apply_patch(sim: &mut Simulation, patch: &MetadataPatch) -> Result<(), String> {
+    if let Some(name) = &patch.name {
+        sim.name = name.clone();
+    }
+    if let Some(description) = &patch.description {
+        sim.description = description.clone();
+    }
+    for (key, value) in &patch.labels {
+        match value {
+            Some(value) => sim.labels.insert(key.clone(), value.clone()),
+            None => sim.labels.remove(key)
+        };
+    }
+    validate_labels(&sim.labels)
+}
+
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+}
+
+fn label_key(key: &str, value: Option<&str>) -> String {
+    match value {
+        Some(value) => format!("index:label:{}={}", key, value),
+        None => format!("index:label:{}", key)
+    }
+}
+
+fn word_key(word: &str) -> String {
+    format!("index:word:{}", word)
+}
+
+#[doc = "The key of the index set for the simulations with a status"]
+pub fn status_key(status: SimulationStatus) -> String {
+    format!("index:status:{:?}", status)
+}
+
+#[doc = "The keys of the index sets that a simulation belongs in"]
+pub fn index_keys(sim: &Simulation) -> BTreeSet<String> {
+    let mut keys = BTreeSet::new();
+    keys.insert(status_key(sim.status));
+    for (key, value) in &sim.labels {
+        keys.insert(label_key(key, Some(value)));
+        keys.insert(label_key(key, None));
+    }
+    for word in words(&sim.name).chain(words(&sim.description)) {
+        keys.insert(word_key(&word));
+    }
+    keys
+}
+
+#[doc = "The keys of the index sets to intersect for a search"]
+pub fn query_keys(labels: Option<&str>, q: Option<&str>) -> Result<Vec<String>, String> {
+    let mut keys = BTreeSet::new();
+    for selector in labels.unwrap_or("").split(',').map(|selector| selector.trim()).filter(|selector| !selector.is_empty()) {
+        match selector.split_once('=') {
+            Some(("", _)) => return Err(format!("Label selector '{}' has no key", selector)),
+            Some((key, value)) => keys.insert(label_key(key, Some(value))),
+            None => keys.insert(label_key(selector, None))
+        };
+    }
+    for word in words(q.unwrap_or("")) {
+        keys.insert(word_key(&word));
+    }
+    if keys.is_empty() {
+        return Err("A search needs a label selector or some words to match".into())
+    }
+    Ok(keys.into_iter().collect())
+}
+
+#[doc = "The changes to the index sets for a simulation: the keys to remove it from, and those to add it to"]
+pub fn index_changes(old_keys: &BTreeSet<String>, sim: &Simulation) -> (Vec<String>, Vec<String>) {
+    let new_keys = index_keys(sim);
+    (old_keys.difference(&new_keys).cloned().collect(), new_keys.difference(old_keys).cloned().collect())
+}
@@ -0,0 +1,209 @@
+//!
+//! # Time parameters of a simulation
+//!
+//! Times are held as a whole number of nanoseconds so that values such as
+//! a 50 µs timestep are stored exactly. They can be given as:
+//! * a JSON number of seconds, e.g. `0.00005`
+//! * a string holding a decimal number and an optional unit, e.g. `"50us"`,
+//!   `"0.1"`, `"20 ms"` or `"1.5s"`. The units are `s`, `ms`, `us`, `µs` and `ns`.
+//!
+//! They are written out as a JSON number of seconds. Records stored before
+//! this type existed hold whole seconds as integers, which read back unchanged.
+//!
+
+use std::{ fmt, str::FromStr };
+use serde::{ Serialize, Serializer, Deserialize, Deserializer, de };
+use schemars::JsonSchema;
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{Schema, SchemaObject, SubschemaValidation};
+use rocket::form::{self, FromFormField, ValueField};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+#[doc = "A time in a simulation, exact to the nanosecond"]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct SimulationTime {
+    nanoseconds: u64
+}
+
+impl SimulationTime {
+    pub const fn from_secs(seconds: u64) -> SimulationTime {
+        SimulationTime { nanoseconds: seconds * NANOS_PER_SECOND }
+    }
+
+    pub const fn from_nanos(nanoseconds: u64) -> SimulationTime {
+        SimulationTime { nanoseconds }
+    }
+
+    pub fn as_secs_f64(&self) -> f64 {
+        self.nanoseconds as f64 / NANOS_PER_SECOND as f64
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.nanoseconds == 0
+    }
+}
+
+#[doc = "Check that a timestep and final time describe a simulation that can run"]
+pub fn validate_time_parameters(timestep: SimulationTime, finaltime: SimulationTime) -> Result<(), String> {
+    if timestep.is_zero() {
+        return Err("timestep must be greater than zero".into())
+    }
+    if finaltime.is_zero() {
+        return Err("finaltime must be greater than zero".into())
+    }
+    if timestep > finaltime {
+        return Err(format!("timestep {} must not be greater than finaltime {}", timestep, finaltime))
+    }
+    Ok(())
+}
+
+impl fmt::Display for SimulationTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let seconds = self.nanoseconds / NANOS_PER_SECOND;
+        let fraction = self.nanoseconds % NANOS_PER_SECOND;
+        if fraction == 0 {
+            write!(f, "{}s", seconds)
+        } else {
+            let digits = format!("{:09}", fraction);
+            write!(f, "{}.{}s", seconds, digits.trim_end_matches('0'))
+        }
+    }
+}
+
+impl FromStr for SimulationTime {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let trimmed = input.trim();
+        let number_end = trimmed
+            .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == 'e' || c == 'E' || c == '-' || c == '+'))
+            .unwrap_or(trimmed.len());
+        let (number, unit) = trimmed.split_at(number_end);
+        let unit_exponent: i32 = match unit.trim() {
+            "" | "s" => 9,
+            "ms"     => 6,
+            "us" | "µs" => 3,
+            "ns"     => 0,
+            other => return Err(format!("Unknown time unit '{}' in '{}'", other, input))
+        };
+        let (mantissa, exponent) = match number.find(['e', 'E']) {
+            Some(index) => {
+                let exponent = number[index + 1..].parse::<i32>()
+                    .map_err(|_| format!("Invalid exponent in '{}'", input))?;
+                (&number[..index], exponent)
+            },
+            None => (number, 0)
+        };
+        let (whole, fraction) = match mantissa.split_once('.') {
+            Some(parts) => parts,
+            None => (mantissa, "")
+        };
+        if whole.is_empty() && fraction.is_empty() {
+            return Err(format!("No number in '{}'", input))
+        }
+        if !whole.chars().chain(fraction.chars()).all(|c| c.is_ascii_digit()) {
+            return Err(format!("'{}' is not a non-negative decimal number", input))
+        }
+        let digits = format!("{}{}", whole, fraction);
+        let digits = digits.trim_start_matches('0');
+        let mut value: u128 = 0;
+        for digit in digits.chars() {
+            value = value.checked_mul(10)
+                .and_then(|v| v.checked_add(digit.to_digit(10).unwrap() as u128))
+                .ok_or_else(|| format!("'{}' is too large", input))?;
+        }
+        let scale = exponent as i64 + unit_exponent as i64 - fraction.len() as i64;
+        if value != 0 {
+            if scale >= 0 {
+                for _ in 0..scale {
+                    value = value.checked_mul(10).ok_or_else(|| format!("'{}' is too large", input))?;
+                }
+            } else {
+                for _ in 0..-scale {
+                    if !value.is_multiple_of(10) {
+                        return Err(format!("'{}' is finer than one nanosecond", input))
+                    }
+                    value /= 10;
+                }
+            }
+        }
+        if value > u64::MAX as u128 {
+            return Err(format!("'{}' is too large", input))
+        }
+        Ok(SimulationTime::from_nanos(value as u64))
+    }
+}
+
+impl Serialize for SimulationTime {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(self.as_secs_f64())
+    }
+}
+
+struct SimulationTimeVisitor;
+
+impl<'de> de::Visitor<'de> for SimulationTimeVisitor {
+    type Value = SimulationTime;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a number of seconds or a string such as \"50us\"")
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<SimulationTime, E> {
+        value.checked_mul(NANOS_PER_SECOND)
+            .map(SimulationTime::from_nanos)
+            .ok_or_else(|| E::custom(format!("{} seconds is too large", value)))
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<SimulationTime, E> {
+        if value < 0 {
+            return Err(E::custom(format!("time must not be negative: {}", value)))
+        }
+        self.visit_u64(value as u64)
+    }
+
+    fn visit_f64<E: de::Error>(self, value: f64) -> Result<SimulationTime, E> {
+        if !value.is_finite() {
+            return Err(E::custom(format!("time must be finite: {}", value)))
+        }
+        // f64's Display is the shortest decimal that reads back as the
+        // same value, so parsing it gives what the sender wrote.
+        value.to_string().parse().map_err(E::custom)
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<SimulationTime, E> {
+        value.parse().map_err(E::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for SimulationTime {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(SimulationTimeVisitor)
+    }
+}
+
+impl JsonSchema for SimulationTime {
+    fn schema_name() -> String {
+        "SimulationTime".into()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        let mut schema = SchemaObject {
+            subschemas: Some(Box::new(SubschemaValidation {
+                one_of: Some(vec![gen.subschema_for::<f64>(), gen.subschema_for::<String>()]),
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+        schema.metadata().description = Some("Seconds as a number, or a string with a unit such as \"50us\"".into());
+        schema.into()
+    }
+}
+
+#[rocket::async_trait]
+impl<'v> FromFormField<'v> for SimulationTime {
+    fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+        field.value.parse().map_err(|e: String| form::Error::validation(e).into())
+    }
+}
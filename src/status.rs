@@ -0,0 +1,192 @@
+//!
+//! # Status of a simulation
+//!
+//! A simulation starts out Queued. The worker reports its progress
+//! through `PUT /simulation/<id>/status`, moving it to Running and then
+//! to one of the finished states, which are final.
+//!
+//! A simulation with contingency jobs has a status per job, and the worker
+//! names the job it reports for by its `results_id`. The status of the
+//! simulation follows from those of its jobs:
+//!
+//! | Jobs                                | Simulation                     |
+//! |-------------------------------------|--------------------------------|
+//! | all Queued                          | Queued                         |
+//! | some Running or finished            | Running                        |
+//! | all finished, any Failed            | Failed                         |
+//! | all finished, any Cancelled         | Cancelled                      |
+//! | all Succeeded                       | Succeeded                      |
+//!
+//! Its progress is the mean of the progress of its jobs. Cancelling the
+//! simulation, without a `results_id`, cancels every job not yet finished.
+//!
+//! Every change is broadcast to the clients of the `/events` and
+//! `/simulation/<id>/events` streams.
+//!
+
+use std::sync::LazyLock;
+use serde::{ Serialize, Deserialize };
+use schemars::JsonSchema;
+use rocket::tokio::sync::broadcast;
+use crate::contingency::ContingencyJob;
+use crate::routes::Simulation;
+
+// Events for clients that fall this far behind are dropped
+const CHANNEL_CAPACITY: usize = 256;
+
+#[doc = "Enum for the stages of a simulation's life"]
+#[derive(JsonSchema, FromFormField, Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Default)]
+pub enum SimulationStatus {
+    #[default]
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled
+}
+
+impl SimulationStatus {
+    pub fn is_finished(&self) -> bool {
+        matches!(self, SimulationStatus::Succeeded | SimulationStatus::Failed | SimulationStatus::Cancelled)
+    }
+}
+
+#[doc = "A status report, as sent by the worker"]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct StatusUpdate {
+    pub status:   SimulationStatus,
+    #[serde(default)]
+    pub progress: Option<u8>,
+    #[serde(default)]
+    pub error:    Option<String>,
+    #[doc = "The results file of the job the report is for, needed if the simulation has contingency jobs"]
+    #[serde(default)]
+    pub results_id: Option<String>,
+    #[doc = "The version of the worker, recorded in the provenance of the simulation"]
+    #[serde(default)]
+    pub worker_version: Option<String>,
+    #[doc = "The version of DPsim the worker runs, recorded in the provenance of the simulation"]
+    #[serde(default)]
+    pub dpsim_version:  Option<String>
+}
+
+#[doc = "A change of status, as sent to the event stream clients"]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct StatusEvent {
+    pub simulation_id: u64,
+    pub status:        SimulationStatus,
+    pub progress:      u8,
+    pub error:         String
+}
+
+impl StatusEvent {
+    pub fn from_simulation(sim: &Simulation) -> StatusEvent {
+        StatusEvent {
+            simulation_id: sim.simulation_id,
+            status:        sim.status,
+            progress:      sim.progress,
+            error:         sim.error.clone()
+        }
+    }
+}
+
+static CHANNEL: LazyLock<broadcast::Sender<StatusEvent>> = LazyLock::new(|| broadcast::channel(CHANNEL_CAPACITY).0);
+
+#[doc = "Receive every status change from now on"]
+pub fn subscribe() -> broadcast::Receiver<StatusEvent> {
+    CHANNEL.subscribe()
+}
+
+#[doc = "Send a status change to every subscriber"]
+pub fn publish(event: StatusEvent) {
+    // An error only means that nobody is listening
+    let _ = CHANNEL.send(event);
+}
+
+#[doc = "Check a status report on its own, before it is applied to a simulation"]
+pub fn validate_update(update: &StatusUpdate) -> Result<(), String> {
+    match update.progress {
+        Some(progress) if progress > 100 => Err(format!("progress {} is more than 100", progress)),
+        _ => Ok(())
+    }
+}
+
+// Apply a status report to a simulation or to one of its jobs
+fn apply_transition(name: &str, status: &mut SimulationStatus, progress: &mut u8, error: &mut String,
+                    update: &StatusUpdate) -> Result<(), String> {
+    if status.is_finished() {
+        return Err(format!("{} is already {:?}", name, status))
+    }
+    if update.status == SimulationStatus::Queued && *status != SimulationStatus::Queued {
+        return Err(format!("{} cannot go back from {:?} to Queued", name, status))
+    }
+    *progress = match (update.status, update.progress) {
+        (SimulationStatus::Succeeded, _) => 100,
+        (_, Some(progress)) => progress,
+        (_, None) => *progress
+    };
+    *status = update.status;
+    if let Some(update_error) = &update.error {
+        *error = update_error.clone();
+    }
+    Ok(())
+}
+
+#[doc = "The status, progress and error of a simulation, as they follow from those of its jobs"]
+fn combine_jobs(jobs: &[ContingencyJob]) -> (SimulationStatus, u8, String) {
+    let any = |status: SimulationStatus| jobs.iter().any(|job| job.status == status);
+    let status = if !jobs.iter().all(|job| job.status.is_finished()) {
+        if jobs.iter().all(|job| job.status == SimulationStatus::Queued) {
+            SimulationStatus::Queued
+        } else {
+            SimulationStatus::Running
+        }
+    } else if any(SimulationStatus::Failed) {
+        SimulationStatus::Failed
+    } else if any(SimulationStatus::Cancelled) {
+        SimulationStatus::Cancelled
+    } else {
+        SimulationStatus::Succeeded
+    };
+    let progress_sum: usize = jobs.iter()
+        .map(|job| if job.status.is_finished() { 100 } else { job.progress as usize })
+        .sum();
+    let errors: Vec<String> = jobs.iter()
+        .filter(|job| !job.error.is_empty())
+        .map(|job| format!("{}: {}", job.contingency.mrid, job.error))
+        .collect();
+    (status, (progress_sum / jobs.len().max(1)) as u8, errors.join("; "))
+}
+
+#[doc = "Apply a valid status report to a simulation, or to the job it names, if the transition is allowed"]
+pub fn apply_update(sim: &mut Simulation, update: &StatusUpdate) -> Result<(), String> {
+    if sim.contingency_jobs.is_empty() {
+        let name = format!("Simulation {}", sim.simulation_id);
+        return apply_transition(&name, &mut sim.status, &mut sim.progress, &mut sim.error, update)
+    }
+    if sim.status.is_finished() {
+        return Err(format!("Simulation {} is already {:?}", sim.simulation_id, sim.status))
+    }
+    match &update.results_id {
+        Some(results_id) => {
+            let job = match sim.contingency_jobs.iter_mut().find(|job| &job.results_id == results_id) {
+                Some(job) => job,
+                None => return Err(format!("Simulation {} has no job with results file {}", sim.simulation_id, results_id))
+            };
+            let name = format!("Job {} of simulation {}", job.contingency.mrid, sim.simulation_id);
+            apply_transition(&name, &mut job.status, &mut job.progress, &mut job.error, update)?;
+        },
+        None if update.status == SimulationStatus::Cancelled => {
+            for job in sim.contingency_jobs.iter_mut().filter(|job| !job.status.is_finished()) {
+                job.status = SimulationStatus::Cancelled;
+            }
+        },
+        None => return Err(format!("Simulation {} has {} jobs, the update needs the results_id of one of them",
+                                   sim.simulation_id, sim.contingency_jobs.len()))
+    }
+    (sim.status, sim.progress, sim.error) = combine_jobs(&sim.contingency_jobs);
+    if let (None, Some(error)) = (&update.results_id, &update.error) {
+        sim.error = error.clone();
+    }
+    Ok(())
+}
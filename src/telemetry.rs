@@ -0,0 +1,165 @@
+//!
+//! # Tracing
+//!
+//! Every request is handled in an OpenTelemetry span, which continues the
+//! trace of the W3C `traceparent` header of the request, if it has one.
+//! Within it, the steps of creating and reading a simulation and every
+//! call to Redis, the sogno file service and the AMQP broker have spans of
+//! their own. The trace is passed on in the `traceparent` header of the
+//! requests to the sogno file service and in the headers of the jobs
+//! published to the AMQP broker, so that the spans of the worker join it.
+//!
+//! Spans are only exported when an OTLP endpoint is configured, using the
+//! standard OpenTelemetry environment variables:
+//!
+//! | Variable                               | Meaning                                    |
+//! |----------------------------------------|--------------------------------------------|
+//! | `OTEL_EXPORTER_OTLP_ENDPOINT`          | OTLP/HTTP collector, e.g. http://localhost:4318 |
+//! | `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT`   | full URL for traces, instead of the above  |
+//! | `OTEL_EXPORTER_OTLP_HEADERS`           | headers to send to the collector           |
+//! | `OTEL_SERVICE_NAME`                    | defaults to dpsim-api                      |
+//!
+//! Without one, spans are not recorded, but traces are still passed on.
+//!
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::future::Future;
+use opentelemetry::{global, Context, KeyValue};
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry::trace::{FutureExt, SpanKind, Status as SpanStatus, TraceContextExt, Tracer};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::{runtime, trace, Resource};
+use rocket::{Data, Request, Route};
+use rocket::route::{Handler, Outcome};
+use log::{info, warn};
+
+const TRACER_NAME: &str = "dpsim-api";
+const ENDPOINT_VARIABLES: [&str; 2] = ["OTEL_EXPORTER_OTLP_ENDPOINT", "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT"];
+
+#[doc = "Export spans over OTLP, if an endpoint is configured"]
+pub fn init() {
+    if !ENDPOINT_VARIABLES.iter().any(|variable| std::env::var(variable).is_ok()) {
+        return;
+    }
+    let service_name = std::env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| TRACER_NAME.into());
+    let installed = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().http())
+        .with_trace_config(trace::config().with_resource(Resource::new(vec![KeyValue::new("service.name", service_name)])))
+        .install_batch(runtime::Tokio);
+    match installed {
+        Ok(_) => info!("Exporting spans over OTLP"),
+        Err(e) => warn!("Could not set up OTLP span export: {}", e)
+    }
+}
+
+#[doc = "Export the spans that have not been exported yet"]
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+fn start(name: &str, kind: SpanKind, attributes: Vec<KeyValue>, parent: &Context) -> Context {
+    let tracer = global::tracer(TRACER_NAME);
+    let span = tracer.span_builder(name.to_string())
+        .with_kind(kind)
+        .with_attributes(attributes)
+        .start_with_context(&tracer, parent);
+    parent.with_span(span)
+}
+
+fn finish<T, E: Display>(context: &Context, result: &Result<T, E>) {
+    let span = context.span();
+    if let Err(e) = result {
+        span.set_status(SpanStatus::error(e.to_string()));
+    }
+    span.end();
+}
+
+#[doc = "Run a call in a span of the current trace, marking the span if the call fails"]
+pub fn in_span<T, E: Display>(name: &str, kind: SpanKind, attributes: Vec<KeyValue>,
+                              call: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+    let context = start(name, kind, attributes, &Context::current());
+    let result = {
+        let _attached = context.clone().attach();
+        call()
+    };
+    finish(&context, &result);
+    result
+}
+
+#[doc = "Await a future in a span of the current trace, marking the span if it fails"]
+pub async fn in_span_async<T, E: Display, F: Future<Output = Result<T, E>>>(name: &str, kind: SpanKind,
+                                                                           attributes: Vec<KeyValue>, call: F) -> Result<T, E> {
+    let context = start(name, kind, attributes, &Context::current());
+    let result = call.with_context(context.clone()).await;
+    finish(&context, &result);
+    result
+}
+
+#[doc = "Run a step of handling a request in a span of its own"]
+pub fn step<T, E: Display>(name: &str, call: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+    in_span(name, SpanKind::Internal, Vec::new(), call)
+}
+
+#[doc = "Await a step of handling a request in a span of its own"]
+pub async fn step_async<T, E: Display, F: Future<Output = Result<T, E>>>(name: &str, call: F) -> Result<T, E> {
+    in_span_async(name, SpanKind::Internal, Vec::new(), call).await
+}
+
+#[doc = "The headers that pass the current trace on to another service"]
+pub fn trace_headers() -> HashMap<String, String> {
+    let mut headers = HashMap::new();
+    TraceContextPropagator::new().inject_context(&Context::current(), &mut headers);
+    headers
+}
+
+#[doc = "The trace that a request is part of, from its headers"]
+pub fn remote_context(request: &Request<'_>) -> Context {
+    let propagator = TraceContextPropagator::new();
+    let headers: HashMap<String, String> = propagator.fields()
+        .filter_map(|field| request.headers().get_one(field).map(|value| (field.to_string(), value.to_string())))
+        .collect();
+    propagator.extract(&headers)
+}
+
+#[doc = "Route handler that handles the request in a span"]
+#[derive(Clone)]
+struct Traced(Box<dyn Handler>);
+
+#[rocket::async_trait]
+impl Handler for Traced {
+    async fn handle<'r>(&self, request: &'r Request<'_>, data: Data<'r>) -> Outcome<'r> {
+        let route = request.route().and_then(|route| route.name.as_deref()).unwrap_or("unmatched");
+        let context = start(&format!("{} {}", request.method(), route), SpanKind::Server, vec![
+            KeyValue::new("http.method", request.method().as_str()),
+            KeyValue::new("http.route", route.to_string()),
+            KeyValue::new("http.target", request.uri().to_string())
+        ], &remote_context(request));
+        let outcome = self.0.handle(request, data).with_context(context.clone()).await;
+        let status = match &outcome {
+            Outcome::Success(response) => Some(response.status()),
+            Outcome::Failure(status) => Some(*status),
+            Outcome::Forward(_) => None
+        };
+        let span = context.span();
+        if let Some(status) = status {
+            span.set_attribute(KeyValue::new("http.status_code", status.code as i64));
+            if status.code >= 500 {
+                span.set_status(SpanStatus::error(status.to_string()));
+            }
+        }
+        span.end();
+        outcome
+    }
+}
+
+#[doc = "Make the given routes handle their requests in spans"]
+pub fn with_tracing(routes: Vec<Route>) -> Vec<Route> {
+    routes.into_iter()
+        .map(|mut route| {
+            route.handler = Box::new(Traced(route.handler));
+            route
+        })
+        .collect()
+}
@@ -0,0 +1,256 @@
+//!
+//! # Simulation templates
+//!
+//! A template is a named, saved set of simulation parameters, for the
+//! combinations of solver, domain, timestep and worker parameters that
+//! are used again and again for standard studies. Any of the parameters
+//! of a [`SimulationForm`] can be left out of a template.
+//!
+//! A `POST /simulation` with a `template_id` starts from the parameters
+//! of the template; each parameter given in the request replaces the one
+//! from the template. The resolved parameters are stored on the
+//! simulation, along with the id of the template, so the simulation
+//! still shows what it was run with after the template has changed.
+//!
+
+use std::collections::BTreeMap;
+use serde::{ Serialize, Deserialize };
+use serde_json::Value;
+use schemars::JsonSchema;
+use crate::contingency::Contingency;
+use crate::events::{self, Event};
+use crate::limits::{self, Limits};
+use crate::model::ModelRef;
+use crate::realtime::{self, RealTimeConfig};
+use crate::routes::{DomainType, Simulation, SimulationForm, SimulationType, SolverType};
+use crate::simulation_time::{self, SimulationTime};
+use crate::db;
+use crate::search;
+
+// Parameters of the worker message that are set from the simulation
+// itself, and so cannot be given as worker parameters
+const RESERVED_WORKER_PARAMETERS: [&str; 10] = ["domain", "solver", "timestep", "finaltime", "results_file",
+                                               "events", "duration", "contingency", "all_n_minus_1", "realtime"];
+
+#[doc = "Simulation parameters, any of which can be left out"]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct SimulationParameters {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub simulation_type:   Option<SimulationType>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model_id:          Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model:             Option<ModelRef>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub load_profile_id:   Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub load_profile:      Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain:            Option<DomainType>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub solver:            Option<SolverType>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestep:          Option<SimulationTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finaltime:         Option<SimulationTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contingencies:     Option<Vec<Contingency>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub all_n_minus_1:     Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub events:            Option<Vec<Event>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub realtime:          Option<RealTimeConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limits:            Option<Limits>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub worker_parameters: Option<BTreeMap<String, String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name:              Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description:       Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub labels:            Option<BTreeMap<String, String>>
+}
+
+/// # Request body of `POST /simulation`
+///
+/// ## Parameters:
+/// * template_id
+///   - optional, the id of a [`SimulationTemplate`] to take parameters from
+/// * every parameter of a [`SimulationForm`]
+///   - required unless the template has it
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct SimulationRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template_id: Option<u64>,
+    #[serde(flatten)]
+    pub parameters:  SimulationParameters
+}
+
+/// # Form for creating or replacing a SimulationTemplate
+///
+/// ## Parameters:
+/// * name
+///   - String, must not be empty
+/// * description
+///   - String, optional
+/// * parameters
+///   - [`SimulationParameters`], the parameters of a [`SimulationForm`] to save
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct TemplateForm {
+    pub name:        String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub parameters:  SimulationParameters
+}
+
+#[doc = "A saved set of simulation parameters"]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct SimulationTemplate {
+    pub template_id: u64,
+    pub name:        String,
+    #[serde(default)]
+    pub description: String,
+    pub parameters:  SimulationParameters
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[doc = "Struct for encapsulation a list of SimulationTemplates"]
+pub struct TemplateArray {
+    pub templates: Vec<SimulationTemplate>
+}
+
+impl SimulationParameters {
+    #[doc = "All the parameters a simulation was created with"]
+    pub fn from_simulation(sim: &Simulation) -> SimulationParameters {
+        SimulationParameters {
+            simulation_type:   Some(sim.simulation_type),
+            model_id:          Some(sim.model_id.clone()),
+            model:             sim.model.clone(),
+            load_profile_id:   sim.load_profile_id.clone(),
+            load_profile:      sim.load_profile,
+            domain:            Some(sim.domain),
+            solver:            Some(sim.solver),
+            timestep:          Some(sim.timestep),
+            finaltime:         Some(sim.finaltime),
+            contingencies:     Some(sim.contingencies.clone()),
+            all_n_minus_1:     Some(sim.all_n_minus_1),
+            events:            Some(sim.events.clone()),
+            realtime:          sim.realtime.clone(),
+            limits:            Some(sim.limits.clone()),
+            worker_parameters: Some(sim.worker_parameters.clone()),
+            name:              Some(sim.name.clone()),
+            description:       Some(sim.description.clone()),
+            labels:            Some(sim.labels.clone())
+        }
+    }
+}
+
+impl SimulationTemplate {
+    pub fn from_form(template_id: u64, form: &TemplateForm) -> SimulationTemplate {
+        SimulationTemplate {
+            template_id,
+            name:        form.name.trim().to_string(),
+            description: form.description.clone(),
+            parameters:  form.parameters.clone()
+        }
+    }
+}
+
+#[doc = "Check that worker parameters do not clash with the parameters set from the simulation"]
+pub fn validate_worker_parameters(worker_parameters: &BTreeMap<String, String>) -> Result<(), String> {
+    match worker_parameters.keys().find(|key| RESERVED_WORKER_PARAMETERS.contains(&key.as_str())) {
+        Some(key) => Err(format!("'{}' is set from the simulation and cannot be a worker parameter", key)),
+        None => Ok(())
+    }
+}
+
+#[doc = "Check the parameters of a template that can be checked on their own"]
+pub fn validate_form(form: &TemplateForm) -> Result<(), String> {
+    if form.name.trim().is_empty() {
+        return Err("name must not be empty".into())
+    }
+    let parameters = &form.parameters;
+    if let (Some(timestep), Some(finaltime)) = (parameters.timestep, parameters.finaltime) {
+        simulation_time::validate_time_parameters(timestep, finaltime)?;
+    }
+    if let (Some(events), Some(finaltime)) = (&parameters.events, parameters.finaltime) {
+        events::validate_events(events, finaltime)?;
+    }
+    if let Some(limits) = &parameters.limits {
+        limits::validate_limits(limits)?;
+    }
+    if let Some(config) = &parameters.realtime {
+        realtime::validate_config(config)?;
+    }
+    if let Some(worker_parameters) = &parameters.worker_parameters {
+        validate_worker_parameters(worker_parameters)?;
+    }
+    if let Some(labels) = &parameters.labels {
+        search::validate_labels(labels)?;
+    }
+    Ok(())
+}
+
+fn to_object(parameters: &SimulationParameters) -> serde_json::Map<String, Value> {
+    match serde_json::to_value(parameters) {
+        Ok(Value::Object(object)) => object,
+        _ => serde_json::Map::new()
+    }
+}
+
+#[doc = "The complete form for a simulation: the base parameters, such as a template's, replaced by those of the request"]
+pub fn resolve(base: Option<&SimulationParameters>, request: &SimulationParameters) -> Result<SimulationForm, String> {
+    let mut merged = match base {
+        Some(base) => to_object(base),
+        None => serde_json::Map::new()
+    };
+    merged.extend(to_object(request));
+    serde_json::from_value(Value::Object(merged)).map_err(|e| format!("Incomplete simulation parameters: {}", e))
+}
+
+#[doc = "Apply a JSON merge patch (RFC 7396) to a value: null removes a field, objects are merged and anything else replaces"]
+fn merge_patch(target: &mut Value, patch: &Value) {
+    let patch = match patch {
+        Value::Object(patch) => patch,
+        _ => {
+            *target = patch.clone();
+            return
+        }
+    };
+    if !target.is_object() {
+        *target = Value::Object(serde_json::Map::new());
+    }
+    if let Value::Object(target) = target {
+        for (key, value) in patch {
+            if value.is_null() {
+                target.remove(key);
+            } else {
+                merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+            }
+        }
+    }
+}
+
+#[doc = "The complete form for a rerun: the parameters of the original simulation with a JSON merge patch applied"]
+pub fn resolve_rerun(original: &SimulationParameters, overrides: &Value) -> Result<SimulationForm, String> {
+    if !overrides.is_object() {
+        return Err("Overrides must be a JSON object".into())
+    }
+    let mut merged = Value::Object(to_object(original));
+    merge_patch(&mut merged, overrides);
+    serde_json::from_value(merged).map_err(|e| format!("Incomplete simulation parameters: {}", e))
+}
+
+#[doc = "Read all saved templates"]
+pub fn read_templates() -> redis::RedisResult<Vec<SimulationTemplate>> {
+    let mut templates = Vec::new();
+    for id in 1..=db::get_number_of_templates()? {
+        if let Some(template) = db::read_template(id)? {
+            templates.push(template);
+        }
+    }
+    Ok(templates)
+}
@@ -1,12 +1,46 @@
 use rocket::local::blocking::Client;
 use rocket::Build;
 use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use crate::idempotency;
+use crate::results::ResultsSeries;
+use parquet::file::reader::{FileReader, SerializedFileReader};
 use crate::routes::{Simulation, SimulationArray, SimulationSummary, SimulationType, DomainType, SolverType, get_routes, incomplete_form};
-use rocket::http::ContentType;
+use rocket::http::{ContentType, Header, Status};
 use serde_json::json;
 use assert_json_diff::assert_json_eq;
 use crate::Template;
 use crate::routes::{SimulationForm};
+use crate::compare::{self, SimulationComparison};
+use crate::contingency::{self, Contingency, ContingencyJob, ElementType};
+use crate::limits::{find_violations, ComponentLimit, Limits, ViolationKind};
+use crate::report::SimulationReport;
+use crate::events::{Event, EventKind};
+use crate::realtime::InterfaceType;
+use crate::results;
+use crate::simulation_time::SimulationTime;
+use crate::status::{self, SimulationStatus};
+use crate::webhook::{self, Delivery, Webhook};
+use crate::creation::CreationSaga;
+use crate::file_service;
+use crate::search;
+use crate::template;
+use crate::metrics;
+use crate::telemetry;
+use crate::audit;
+use crate::audit::{AuditArray, AuditContext, AuditEntry, AuditOperation};
+use crate::model::{Model, ModelRef};
+use crate::amqp::{self, AMQPSimulation};
+use crate::provenance::{self, InputRole, Provenance};
+use sha2::{Digest, Sha256};
+use crate::load_profile::{self, LoadProfile, LoadProfileCheck, LoadProfileFormat, LoadProfilePreview, TimeFormat};
+use crate::cim::{self, CimCounts, CimProfile, ModelProblem, ModelValidation, Severity};
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry::trace::SpanKind;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use crate::logging::{self, LogContext, LogFormat};
+use crate::health::{Health, Readiness};
+use crate::routes::SimulationError;
 
 #[launch]
 fn rocket() -> rocket::Rocket<Build> {
@@ -14,6 +48,8 @@ fn rocket() -> rocket::Rocket<Build> {
         .register("/", catchers![incomplete_form])
         .mount("/", get_routes())
         .attach(Template::fairing())
+        .attach(metrics::RequestMetrics)
+        .attach(logging::RequestIds)
 }
 
 #[test]
@@ -31,6 +67,8 @@ fn test_get_simulations() {
         simulation_id:   1,
         model_id:        "1".to_string(),
         simulation_type: SimulationType::Powerflow,
+        name:            "".to_string(),
+        labels:          Default::default(),
     };
     assert_json_eq!(received_simulation_summary, expected_simulation_summary)
 }
@@ -47,13 +85,18 @@ fn test_get_simulation_by_id() {
     let received_json: Simulation = serde_json::from_str( reply.as_str() ).unwrap();
     let expected_json = Simulation {
         error:           "".to_string(),
-        load_profile_id: "".to_string(),
+        status:          SimulationStatus::Queued,
+        progress:        0,
+        load_profile_id: None,
         model_id:        "1".to_string(),
+        model:           None,
+        model_files:     vec!["1".to_string()],
+        load_profile:    None,
         results_id:      "1".to_string(),
         domain:          DomainType::SP,
         solver:          SolverType::NRP,
-        timestep:        1,
-        finaltime:       360,
+        timestep:        SimulationTime::from_secs(1),
+        finaltime:       SimulationTime::from_secs(360),
         results_data:    r#"{
   "data": {
     "fileID": "d297cb7c-b578-4da8-9d79-76432e8986e9",
@@ -64,6 +107,19 @@ fn test_get_simulation_by_id() {
 "#.to_string(),
         simulation_id:   1,
         simulation_type: SimulationType::Powerflow,
+        contingencies:   vec![],
+        all_n_minus_1:   false,
+        contingency_jobs: vec![],
+        events:          vec![],
+        realtime:        None,
+        limits:          Limits::default(),
+        report:          None,
+        worker_parameters: Default::default(),
+        template_id:     None,
+        rerun_of:        None,
+        name:            "".to_string(),
+        description:     "".to_string(),
+        labels:          Default::default(),
     };
     assert_json_eq!(received_json, expected_json)
 }
@@ -99,12 +155,23 @@ fn test_post_simulation() {
    
     let form = SimulationForm {
         model_id: "1".to_string(),
-        load_profile_id: "1".to_string(),
+        model: None,
+        load_profile: None,
+        load_profile_id: Some("1".to_string()),
         simulation_type: SimulationType::Powerflow.into(),
         domain:          DomainType::SP,
         solver:          SolverType::NRP,
-        timestep:        1,
-        finaltime:       360,
+        timestep:        SimulationTime::from_secs(1),
+        finaltime:       SimulationTime::from_secs(360),
+        contingencies:   vec![],
+        all_n_minus_1:   false,
+        events:          vec![],
+        realtime:        None,
+        limits:          Limits::default(),
+        worker_parameters: Default::default(),
+        name:            "".to_string(),
+        description:     "".to_string(),
+        labels:          Default::default(),
     };
     let body = serde_json::to_string(&form).unwrap();
     let response = client.post("/simulation")
@@ -117,17 +184,1296 @@ fn test_post_simulation() {
     println!("REPLY: {:?}", reply);
     let expected_simulation = json!(Simulation {
         error:             "".to_string(),
-        load_profile_id:   "1".to_string(),
+        status:            SimulationStatus::Queued,
+        progress:          0,
+        load_profile_id:   Some("1".to_string()),
         model_id:          "1".to_string(),
+        model:             None,
+        model_files:       vec!["1".to_string()],
+        load_profile:      None,
         results_id:        "100".to_string(),
         results_data:      "".to_string(),
         simulation_id:     1,
         simulation_type:   SimulationType::Powerflow,
         domain:            DomainType::SP,
         solver:            SolverType::NRP,
-        timestep:          1,
-        finaltime:         360,
+        timestep:          SimulationTime::from_secs(1),
+        finaltime:         SimulationTime::from_secs(360),
+        contingencies:     vec![],
+        all_n_minus_1:     false,
+        contingency_jobs:  vec![],
+        events:            vec![],
+        realtime:          None,
+        limits:            Limits::default(),
+        report:            None,
+        worker_parameters: Default::default(),
+        template_id:       None,
+        rerun_of:          None,
+        name:              "".to_string(),
+        description:       "".to_string(),
+        labels:            Default::default(),
     });
     let received_json: Simulation = serde_json::from_str( reply.as_str() ).unwrap();
     assert_json_eq!(expected_simulation, received_json)
 }
+
+#[test]
+fn test_post_outage_simulation() {
+    let client = Client::untracked(rocket()).expect("valid rocket instance");
+
+    let ct = "application/json"
+        .parse::<ContentType>()
+        .unwrap();
+
+    let mut form = SimulationForm {
+        model_id: "1".to_string(),
+        model: None,
+        load_profile: None,
+        load_profile_id: Some("1".to_string()),
+        simulation_type: SimulationType::Outage,
+        domain:          DomainType::SP,
+        solver:          SolverType::NRP,
+        timestep:        SimulationTime::from_secs(1),
+        finaltime:       SimulationTime::from_secs(360),
+        contingencies:   vec![],
+        all_n_minus_1:   false,
+        events:          vec![],
+        realtime:        None,
+        limits:          Limits::default(),
+        worker_parameters: Default::default(),
+        name:            "".to_string(),
+        description:     "".to_string(),
+        labels:          Default::default(),
+    };
+    let body = serde_json::to_string(&form).unwrap();
+    let response = client.post("/simulation")
+        .header(ct.clone())
+        .body(&body)
+        .dispatch();
+    assert_eq!(response.status().code, 400);
+
+    form.contingencies = vec![
+        Contingency { element_type: ElementType::Line, mrid: "line1".to_string() },
+        Contingency { element_type: ElementType::Generator, mrid: "gen1".to_string() },
+    ];
+    let body = serde_json::to_string(&form).unwrap();
+    let response = client.post("/simulation")
+        .header(ct.clone())
+        .body(&body)
+        .dispatch();
+    assert_eq!(response.status().code, 200);
+    let received_json: Simulation = serde_json::from_str( response.into_string().unwrap().as_str() ).unwrap();
+    let expected_jobs = vec![
+        ContingencyJob::new(form.contingencies[0].clone(), "100".to_string()),
+        ContingencyJob::new(form.contingencies[1].clone(), "100".to_string()),
+    ];
+    assert_eq!(received_json.contingency_jobs, expected_jobs);
+
+    // With all_n_minus_1, a job is queued for every line, transformer and generator of the model
+    form.contingencies = vec![];
+    form.all_n_minus_1 = true;
+    let response = client.post("/simulation")
+        .header(ct.clone())
+        .body(serde_json::to_string(&form).unwrap())
+        .dispatch();
+    assert_eq!(response.status().code, 400);
+    form.model_id = "cigre-mv-EQ".to_string();
+    let response = client.post("/simulation")
+        .header(ct)
+        .body(serde_json::to_string(&form).unwrap())
+        .dispatch();
+    assert_eq!(response.status().code, 200);
+    let received_json: Simulation = serde_json::from_str( response.into_string().unwrap().as_str() ).unwrap();
+    let outages: Vec<Contingency> = received_json.contingency_jobs.into_iter().map(|job| job.contingency).collect();
+    assert_eq!(outages, vec![
+        Contingency { element_type: ElementType::Transformer, mrid: "_TR1".to_string() },
+        Contingency { element_type: ElementType::Line, mrid: "_L1".to_string() },
+    ]);
+
+    let generator = r##"<rdf:RDF xmlns:cim="http://iec.ch/TC57/2013/CIM-schema-cim16#" xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+        <cim:SynchronousMachine rdf:ID="_G1"/><cim:SynchronousMachine rdf:about="#_G1"/></rdf:RDF>"##;
+    assert_eq!(cim::outages(&[("gen".to_string(), generator.as_bytes().to_vec())]),
+               vec![Contingency { element_type: ElementType::Generator, mrid: "_G1".to_string() }]);
+}
+
+#[test]
+fn test_contingency_ranking() {
+    let slight = results::parse_csv("time,n1.v,line1.loading\n0.0,1.0,0.5\n1.0,0.88,0.9\n").unwrap();
+    let severe = results::parse_csv("time,n1.v,line1.loading\n0.0,1.15,1.2\n1.0,1.0,1.5\n").unwrap();
+    let limits = Limits::default();
+    let mut outages = vec![
+        contingency::outage_report("line7", Some(ElementType::Line), "1", &slight, &limits),
+        contingency::outage_report("gen2", Some(ElementType::Generator), "2", &severe, &limits),
+    ];
+    contingency::rank_outages(&mut outages);
+
+    assert_eq!(outages[0].mrid, "gen2");
+    assert_eq!(outages[0].violations.len(), 2);
+    assert_eq!(outages[0].violations[1].kind, ViolationKind::Overload);
+    assert_eq!(outages[0].violations[1].value, 1.5);
+    assert_eq!(outages[0].violations[1].time, 1.0);
+    assert_eq!(outages[1].violations.len(), 1);
+    assert_eq!(outages[1].violations[0].kind, ViolationKind::Undervoltage);
+}
+
+#[test]
+fn test_post_simulation_with_events() {
+    let client = Client::untracked(rocket()).expect("valid rocket instance");
+
+    let ct = "application/json"
+        .parse::<ContentType>()
+        .unwrap();
+
+    let switch_open = Event {
+        kind:           EventKind::SwitchOpen,
+        time:           SimulationTime::from_nanos(500_000_000),
+        component:      "breaker3".to_string(),
+        clear_time:     None,
+        resistance:     None,
+        active_power:   None,
+        reactive_power: None,
+    };
+    let fault = Event {
+        kind:           EventKind::Fault,
+        time:           SimulationTime::from_nanos(100_000_000),
+        component:      "bus5".to_string(),
+        clear_time:     Some(SimulationTime::from_nanos(200_000_000)),
+        resistance:     Some(0.01),
+        active_power:   None,
+        reactive_power: None,
+    };
+    let mut form = SimulationForm {
+        model_id: "1".to_string(),
+        model: None,
+        load_profile: None,
+        load_profile_id: Some("1".to_string()),
+        simulation_type: SimulationType::Powerflow,
+        domain:          DomainType::EMT,
+        solver:          SolverType::MNA,
+        timestep:        SimulationTime::from_secs(1),
+        finaltime:       SimulationTime::from_secs(1),
+        contingencies:   vec![],
+        all_n_minus_1:   false,
+        events:          vec![switch_open.clone(), fault.clone()],
+        realtime:        None,
+        limits:          Limits::default(),
+        worker_parameters: Default::default(),
+        name:            "".to_string(),
+        description:     "".to_string(),
+        labels:          Default::default(),
+    };
+    let body = serde_json::to_string(&form).unwrap();
+    let response = client.post("/simulation")
+        .header(ct.clone())
+        .body(&body)
+        .dispatch();
+    assert_eq!(response.status().code, 200);
+    let received_json: Simulation = serde_json::from_str( response.into_string().unwrap().as_str() ).unwrap();
+    assert_eq!(received_json.events, vec![fault, switch_open]);
+
+    form.events[0].time = SimulationTime::from_secs(2);
+    let body = serde_json::to_string(&form).unwrap();
+    let response = client.post("/simulation")
+        .header(ct)
+        .body(&body)
+        .dispatch();
+    assert_eq!(response.status().code, 400);
+}
+
+#[test]
+fn test_simulation_time() {
+    assert_eq!("50us".parse::<SimulationTime>(), Ok(SimulationTime::from_nanos(50_000)));
+    assert_eq!("50 µs".parse::<SimulationTime>(), Ok(SimulationTime::from_nanos(50_000)));
+    assert_eq!("0.1".parse::<SimulationTime>(), Ok(SimulationTime::from_nanos(100_000_000)));
+    assert_eq!("1.5s".parse::<SimulationTime>(), Ok(SimulationTime::from_nanos(1_500_000_000)));
+    assert_eq!("2e-3".parse::<SimulationTime>(), Ok(SimulationTime::from_nanos(2_000_000)));
+    assert!("0.1ns".parse::<SimulationTime>().is_err());
+    assert!("-1".parse::<SimulationTime>().is_err());
+    assert!("10 minutes".parse::<SimulationTime>().is_err());
+
+    // Records stored before times became decimal hold whole seconds
+    let old: SimulationTime = serde_json::from_str("30").unwrap();
+    assert_eq!(old, SimulationTime::from_secs(30));
+    let number: SimulationTime = serde_json::from_str("0.00005").unwrap();
+    assert_eq!(number, SimulationTime::from_nanos(50_000));
+    assert_eq!(serde_json::to_string(&number).unwrap(), "0.00005");
+    assert_eq!(number.to_string(), "0.00005s");
+}
+
+#[test]
+fn test_post_simulation_with_units() {
+    let client = Client::untracked(rocket()).expect("valid rocket instance");
+
+    let ct = "application/json"
+        .parse::<ContentType>()
+        .unwrap();
+
+    let body = r#"{ "simulation_type": "Powerflow", "model_id": "1", "load_profile_id": "1",
+                    "domain": "EMT", "solver": "MNA", "timestep": "50us", "finaltime": 0.5 }"#;
+    let response = client.post("/simulation")
+        .header(ct.clone())
+        .body(body)
+        .dispatch();
+    assert_eq!(response.status().code, 200);
+    let received_json: Simulation = serde_json::from_str( response.into_string().unwrap().as_str() ).unwrap();
+    assert_eq!(received_json.timestep, SimulationTime::from_nanos(50_000));
+    assert_eq!(received_json.finaltime, SimulationTime::from_nanos(500_000_000));
+
+    let body = r#"{ "simulation_type": "Powerflow", "model_id": "1", "load_profile_id": "1",
+                    "domain": "EMT", "solver": "MNA", "timestep": "2s", "finaltime": "1s" }"#;
+    let response = client.post("/simulation")
+        .header(ct)
+        .body(body)
+        .dispatch();
+    assert_eq!(response.status().code, 400);
+}
+
+#[test]
+fn test_post_realtime_simulation() {
+    let client = Client::untracked(rocket()).expect("valid rocket instance");
+
+    let ct = "application/json"
+        .parse::<ContentType>()
+        .unwrap();
+
+    let body = r#"{ "simulation_type": "Powerflow", "model_id": "1", "load_profile_id": "1",
+                    "domain": "DP", "solver": "MNA", "timestep": "1ms", "finaltime": 10,
+                    "realtime": { "interface_type": "Villas", "pace": true,
+                                  "exports": [ { "attribute": "n1.v", "index": 0 } ],
+                                  "imports": [ { "attribute": "load1.P_ref", "index": 0 } ],
+                                  "villas_config": "{ \"type\": \"mqtt\", \"host\": \"broker\" }" } }"#;
+    let response = client.post("/simulation")
+        .header(ct.clone())
+        .body(body)
+        .dispatch();
+    assert_eq!(response.status().code, 200);
+    let received_json: Simulation = serde_json::from_str( response.into_string().unwrap().as_str() ).unwrap();
+    let config = received_json.realtime.unwrap();
+    assert_eq!(config.interface_type, InterfaceType::Villas);
+    assert!(config.pace);
+    assert_eq!(config.exports[0].attribute, "n1.v");
+
+    let body = r#"{ "simulation_type": "Powerflow", "model_id": "1", "load_profile_id": "1",
+                    "domain": "DP", "solver": "MNA", "timestep": "1ms", "finaltime": 10,
+                    "realtime": { "interface_type": "Shmem", "shmem_out": "/dpsim-out" } }"#;
+    let response = client.post("/simulation")
+        .header(ct)
+        .body(body)
+        .dispatch();
+    assert_eq!(response.status().code, 400);
+}
+
+#[test]
+fn test_lttb() {
+    let time: Vec<f64> = (0..100).map(|t| t as f64).collect();
+    let values: Vec<f64> = time.iter().map(|t| if *t == 42.0 { 10.0 } else { 0.0 }).collect();
+    let (sampled_time, sampled_values) = results::lttb(&time, &values, 10);
+    assert_eq!(sampled_time.len(), 10);
+    assert_eq!(sampled_time[0], 0.0);
+    assert_eq!(sampled_time[9], 99.0);
+    // The spike is the most significant point and must survive downsampling
+    assert!(sampled_values.contains(&10.0));
+}
+
+// The test file service does not hold a CSV file, so the parsed
+// results for the test simulation are placed in the cache
+const TEST_RESULTS_CSV: &str = "time,n1.v,n2.v\n0.0,1.0,0.9\n0.1,1.01,0.91\n0.2,1.02,0.92\n0.3,1.03,0.93\n";
+
+fn cache_test_results() {
+    results::cache_table("1", results::parse_csv(TEST_RESULTS_CSV).unwrap());
+}
+
+#[test]
+fn test_get_simulation_results_series() {
+    let client = Client::untracked(rocket()).expect("valid rocket instance");
+
+    cache_test_results();
+
+    let response = client.get("/simulation/1/results/series?signals=n2.v&from=100ms&to=0.2").dispatch();
+    assert_eq!(response.status().code, 200);
+    let received_json: ResultsSeries = serde_json::from_str( response.into_string().unwrap().as_str() ).unwrap();
+    assert_eq!(received_json.series.len(), 1);
+    assert_eq!(received_json.series[0].name, "n2.v");
+    assert_eq!(received_json.series[0].time, vec![0.1, 0.2]);
+    assert_eq!(received_json.series[0].values, vec![0.91, 0.92]);
+
+    let response = client.get("/simulation/1/results/series?signals=n3.v").dispatch();
+    assert_eq!(response.status().code, 400);
+
+    // Tables over the size limit are returned but not kept
+    let samples = results::CACHE_MAX_TABLE_BYTES / std::mem::size_of::<f64>() + 1;
+    let large = results::ResultsTable { time: vec![0.0; samples], signals: vec![] };
+    assert!(results::table_bytes(&large) > results::CACHE_MAX_TABLE_BYTES);
+    assert_eq!(results::cache_table("large", large).time.len(), samples);
+    assert!(results::cached_table("large").is_none());
+    assert!(results::cached_table("1").is_some());
+}
+
+#[test]
+fn test_get_simulation_results() {
+    let client = Client::untracked(rocket()).expect("valid rocket instance");
+    cache_test_results();
+
+    let response = client.get("/simulation/1/results?format=Csv&columns=n2.v").dispatch();
+    assert_eq!(response.status().code, 200);
+    assert_eq!(response.headers().get_one("X-Simulation-Id"), Some("1"));
+    assert_eq!(response.headers().get_one("X-Domain"), Some("SP"));
+    assert_eq!(response.into_string().unwrap(), "time,n2.v\n0,0.9\n0.1,0.91\n0.2,0.92\n0.3,0.93\n");
+
+    let response = client.get("/simulation/1/results?format=Json").dispatch();
+    assert_eq!(response.status().code, 200);
+    let received_json: serde_json::Value = serde_json::from_str( response.into_string().unwrap().as_str() ).unwrap();
+    assert_eq!(received_json["metadata"]["solver"], "NRP");
+    assert_eq!(received_json["signals"][1]["name"], "n2.v");
+
+    let response = client.get("/simulation/1/results?format=Ndjson").dispatch();
+    let reply = response.into_string().unwrap();
+    assert_eq!(reply.lines().count(), 5);
+
+    let response = client.get("/simulation/1/results?format=Parquet").dispatch();
+    assert_eq!(response.status().code, 200);
+    let bytes = bytes::Bytes::from(response.into_bytes().unwrap());
+    let reader = SerializedFileReader::new(bytes).unwrap();
+    let metadata = reader.metadata().file_metadata();
+    assert_eq!(metadata.num_rows(), 4);
+    assert_eq!(metadata.schema_descr().num_columns(), 3);
+}
+
+#[test]
+fn test_get_simulation_report() {
+    let client = Client::untracked(rocket()).expect("valid rocket instance");
+    cache_test_results();
+
+    let response = client.get("/simulation/1/report").dispatch();
+    assert_eq!(response.status().code, 200);
+    let received_json: SimulationReport = serde_json::from_str( response.into_string().unwrap().as_str() ).unwrap();
+    let n2 = &received_json.statistics[1];
+    assert_eq!(n2.name, "n2.v");
+    assert_eq!(n2.min, 0.9);
+    assert_eq!(n2.time_of_max, 0.3);
+    assert!((n2.mean - 0.915).abs() < 1e-9);
+    // n1.v stays inside the default band, n2.v is at 0.9 pu and is never below it
+    assert!(received_json.violations.is_empty());
+}
+
+#[test]
+fn test_component_limits() {
+    let table = results::parse_csv("time,n1.v,line1.i,line1.loading\n0.0,0.95,120.0,0.8\n1.0,0.97,180.0,0.95\n").unwrap();
+    let limits = Limits {
+        components: vec![ComponentLimit {
+            component:   "line1".to_string(),
+            voltage_min: None,
+            voltage_max: None,
+            current_max: Some(150.0),
+            loading_max: Some(0.9),
+        }],
+        ..Limits::default()
+    };
+    let violations = find_violations(&table, &limits);
+    assert_eq!(violations.len(), 2);
+    assert_eq!(violations[0].kind, ViolationKind::Overcurrent);
+    assert_eq!(violations[0].value, 180.0);
+    assert_eq!(violations[1].kind, ViolationKind::Overload);
+    assert_eq!(violations[1].limit, 0.9);
+}
+
+#[test]
+fn test_get_simulation_comparison() {
+    let client = Client::untracked(rocket()).expect("valid rocket instance");
+    cache_test_results();
+
+    // The test database returns the same simulation for every id
+    let response = client.get("/simulation/compare?ids=1,2&tolerance=0.001").dispatch();
+    assert_eq!(response.status().code, 200);
+    let received_json: SimulationComparison = serde_json::from_str( response.into_string().unwrap().as_str() ).unwrap();
+    assert_eq!(received_json.base_id, 1);
+    assert!(received_json.parameter_differences.is_empty());
+    assert_eq!(received_json.results[0].common_points, 4);
+    assert_eq!(received_json.results[0].passed, Some(true));
+
+    let response = client.get("/simulation/compare?ids=1").dispatch();
+    assert_eq!(response.status().code, 400);
+
+    // Two runs of the same parameters differ in much more than their ids
+    let base = crate::db::read_simulation(1).unwrap();
+    let mut rerun = base.clone();
+    rerun.simulation_id = 2;
+    rerun.results_id    = "2".into();
+    rerun.status        = SimulationStatus::Running;
+    rerun.progress      = 40;
+    rerun.template_id   = Some(1);
+    rerun.rerun_of      = Some(1);
+    rerun.name          = "rerun".into();
+    rerun.description   = "the same again".into();
+    rerun.labels        = [("study".to_string(), "aachen-feeder".to_string())].into();
+    assert!(compare::compare_parameters(&[base.clone(), rerun.clone()]).is_empty());
+
+    rerun.finaltime = SimulationTime::from_secs(20);
+    let differences = compare::compare_parameters(&[base, rerun]);
+    assert_eq!(differences.len(), 1);
+    assert_eq!(differences[0].parameter, "finaltime");
+}
+
+#[test]
+fn test_compare_results() {
+    let base = results::parse_csv("time,n1.v,n2.v\n0.0,1.0,1.0\n0.1,1.0,1.0\n0.2,1.0,1.0\n").unwrap();
+    let other = results::parse_csv("time,n1.v,n3.v\n0.1,1.3,1.0\n0.2,1.0,1.0\n0.3,1.0,1.0\n").unwrap();
+    let comparison = compare::compare_results(2, &base, &other, Some(0.1));
+    assert_eq!(comparison.common_points, 2);
+    assert_eq!(comparison.signals.len(), 1);
+    assert!((comparison.signals[0].max_abs_error - 0.3).abs() < 1e-9);
+    assert!((comparison.signals[0].rmse - (0.09f64 / 2.0).sqrt()).abs() < 1e-9);
+    assert_eq!(comparison.missing_signals, vec!["n2.v".to_string(), "n3.v".to_string()]);
+    assert_eq!(comparison.passed, Some(false));
+}
+
+#[test]
+fn test_contingency_job_status() {
+    let update = |status: &str, results_id: Option<&str>, error: Option<&str>| -> status::StatusUpdate {
+        serde_json::from_value(json!({ "status": status, "results_id": results_id, "error": error, "progress": 50 })).unwrap()
+    };
+    let mut sim = crate::db::read_simulation(1).unwrap();
+    sim.simulation_type = SimulationType::Outage;
+    sim.contingency_jobs = ["line1", "line2", "line3"].iter()
+        .map(|mrid| ContingencyJob::new(Contingency { element_type: ElementType::Line, mrid: mrid.to_string() },
+                                        format!("results-{}", mrid)))
+        .collect();
+
+    status::apply_update(&mut sim, &update("Running", Some("results-line1"), None)).unwrap();
+    assert_eq!((sim.status, sim.progress), (SimulationStatus::Running, 16));
+
+    // The first job to finish does not finish the simulation, nor does a failed one
+    status::apply_update(&mut sim, &update("Succeeded", Some("results-line1"), None)).unwrap();
+    status::apply_update(&mut sim, &update("Failed", Some("results-line2"), Some("diverged"))).unwrap();
+    assert_eq!((sim.status, sim.progress), (SimulationStatus::Running, 66));
+    assert!(status::apply_update(&mut sim, &update("Running", Some("results-line2"), None)).unwrap_err().contains("already Failed"));
+    assert!(status::apply_update(&mut sim, &update("Running", None, None)).is_err());
+    assert!(status::apply_update(&mut sim, &update("Running", Some("results-line9"), None)).is_err());
+
+    // Once every job has finished, the simulation has failed if any job has
+    status::apply_update(&mut sim, &update("Succeeded", Some("results-line3"), None)).unwrap();
+    assert_eq!((sim.status, sim.progress), (SimulationStatus::Failed, 100));
+    assert_eq!(sim.error, "line2: diverged");
+    assert_eq!(sim.contingency_jobs[2].status, SimulationStatus::Succeeded);
+    assert!(status::apply_update(&mut sim, &update("Succeeded", Some("results-line3"), None)).is_err());
+
+    // Cancelling the simulation cancels the jobs that have not finished
+    for job in &mut sim.contingency_jobs {
+        *job = ContingencyJob::new(job.contingency.clone(), job.results_id.clone());
+    }
+    sim.status = SimulationStatus::Running;
+    status::apply_update(&mut sim, &update("Succeeded", Some("results-line1"), None)).unwrap();
+    status::apply_update(&mut sim, &update("Cancelled", None, Some("stopped by user"))).unwrap();
+    assert_eq!(sim.status, SimulationStatus::Cancelled);
+    assert_eq!(sim.error, "stopped by user");
+    assert_eq!(sim.contingency_jobs[0].status, SimulationStatus::Succeeded);
+    assert_eq!(sim.contingency_jobs[1].status, SimulationStatus::Cancelled);
+}
+
+#[test]
+fn test_put_simulation_status() {
+    let client = Client::untracked(rocket()).expect("valid rocket instance");
+    let mut receiver = status::subscribe();
+
+    let ct = "application/json"
+        .parse::<ContentType>()
+        .unwrap();
+
+    let response = client.put("/simulation/1/status")
+        .header(ct.clone())
+        .body(r#"{ "status": "Running", "progress": 50 }"#)
+        .dispatch();
+    assert_eq!(response.status().code, 200);
+    let received_json: Simulation = serde_json::from_str( response.into_string().unwrap().as_str() ).unwrap();
+    assert_eq!(received_json.status, SimulationStatus::Running);
+    assert_eq!(received_json.progress, 50);
+
+    // Other tests create simulations too, so look for this change among their events
+    let mut published = false;
+    while let Ok(event) = receiver.try_recv() {
+        if event.status == SimulationStatus::Running && event.progress == 50 {
+            published = true;
+        }
+    }
+    assert!(published);
+
+    // An invalid report is a bad request, an illegal transition a conflict
+    let response = client.put("/simulation/1/status")
+        .header(ct)
+        .body(r#"{ "status": "Running", "progress": 101 }"#)
+        .dispatch();
+    assert_eq!(response.status().code, 400);
+    let mut finished: Simulation = serde_json::from_value(json!({
+        "error": "", "load_profile_id": "", "model_id": "1", "results_id": "1", "results_data": "",
+        "simulation_id": 1, "simulation_type": "Powerflow", "domain": "SP", "solver": "NRP",
+        "timestep": 1, "finaltime": 360, "status": "Succeeded"
+    })).unwrap();
+    let update: status::StatusUpdate = serde_json::from_value(json!({ "status": "Running", "progress": 10 })).unwrap();
+    assert!(status::validate_update(&update).is_ok());
+    assert!(status::apply_update(&mut finished, &update).unwrap_err().contains("already Succeeded"));
+}
+
+#[test]
+fn test_post_webhook() {
+    let client = Client::untracked(rocket()).expect("valid rocket instance");
+
+    let ct = "application/json"
+        .parse::<ContentType>()
+        .unwrap();
+
+    // Internal hosts are only called if they are allowed
+    let internal = [
+        "http://redis:6379/", "http://sogno-file-service.default.svc/api/files", "http://localhost:8000/",
+        "http://127.0.0.1/", "http://10.0.0.7/", "http://169.254.169.254/latest/meta-data", "http://[::1]/",
+        "http://[::ffff:192.168.1.1]/", "http://[fd00::1]/", "https://integration-service/hook"
+    ];
+    for url in internal {
+        let body = json!({ "url": url, "events": [ "Succeeded" ], "secret": "secret" }).to_string();
+        let response = client.post("/webhook").header(ct.clone()).body(body).dispatch();
+        assert_eq!(response.status().code, 400, "{}", url);
+    }
+    assert!(!webhook::is_internal_host("hooks.example.com"));
+    assert!(!webhook::is_internal_host("93.184.216.34"));
+    assert!(!webhook::is_internal_host("[2606:2800:220:1:248:1893:25c8:1946]"));
+
+    // Numeric hosts that resolvers read as loopback are rejected, as are reserved ranges
+    for url in ["http://127.1/", "http://0x7f.1/", "http://2130706433/", "http://0.0.0.0/", "http://100.64.0.1/",
+                "http://198.18.0.1/", "http://240.0.0.1/", "http://[::127.0.0.1]/", "http://[64:ff9b::a9fe:a9fe]/"] {
+        let body = json!({ "url": url, "events": [ "Succeeded" ], "secret": "secret" }).to_string();
+        let response = client.post("/webhook").header(ct.clone()).body(body).dispatch();
+        assert_eq!(response.status().code, 400, "{}", url);
+    }
+    assert!(!webhook::is_non_canonical_address("10.0.0.1"));
+    assert!(!webhook::is_non_canonical_address("hooks.example.com"));
+
+    // Deliveries only go to the addresses a name resolves to if none of them is internal
+    let public: SocketAddr = "93.184.216.34:0".parse().unwrap();
+    let metadata: SocketAddr = "169.254.169.254:0".parse().unwrap();
+    assert_eq!(webhook::checked_addresses("hooks.example.com", vec![public]), Ok(vec![public]));
+    assert!(webhook::checked_addresses("rebound.example.com", vec![public, metadata]).is_err());
+    assert!(webhook::checked_addresses("gone.example.com", vec![]).is_err());
+    std::env::set_var("WEBHOOK_ALLOWED_HOSTS", "ci.example.com, integration-service");
+    assert_eq!(webhook::checked_addresses("integration-service", vec![metadata]), Ok(vec![metadata]));
+
+    let body = r#"{ "url": "https://integration-service/hook", "events": [ "Succeeded", "Failed" ], "secret": "secret" }"#;
+    let response = client.post("/webhook")
+        .header(ct.clone())
+        .body(body)
+        .dispatch();
+    assert_eq!(response.status().code, 200);
+    let received_json: Webhook = serde_json::from_str( response.into_string().unwrap().as_str() ).unwrap();
+    assert_eq!(received_json.webhook_id, 1);
+    assert_eq!(received_json.secret, "");
+
+    let body = r#"{ "url": "https://integration-service/hook", "events": [ "Running" ], "secret": "secret" }"#;
+    let response = client.post("/webhook")
+        .header(ct.clone())
+        .body(body)
+        .dispatch();
+    assert_eq!(response.status().code, 400);
+
+    let response = client.post("/webhook/1/test")
+        .header(ct.clone())
+        .dispatch();
+    assert_eq!(response.status().code, 200);
+    let received_json: Delivery = serde_json::from_str( response.into_string().unwrap().as_str() ).unwrap();
+    assert!(received_json.success);
+    assert_eq!(received_json.event, SimulationStatus::Succeeded);
+
+    let response = client.post("/webhook/2/test")
+        .header(ct)
+        .dispatch();
+    assert_eq!(response.status().code, 404);
+}
+
+#[test]
+fn test_webhook_signature() {
+    // Test vector 2 from RFC 4231
+    let signature = webhook::sign("Jefe", b"what do ya want for nothing?");
+    assert_eq!(signature, "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+}
+
+#[test]
+fn test_post_simulation_idempotency_key() {
+    let client = Client::untracked(rocket()).expect("valid rocket instance");
+
+    let ct = "application/json"
+        .parse::<ContentType>()
+        .unwrap();
+
+    let mut form = SimulationForm {
+        model_id: "1".to_string(),
+        model: None,
+        load_profile: None,
+        load_profile_id: Some("1".to_string()),
+        simulation_type: SimulationType::Powerflow,
+        domain:          DomainType::SP,
+        solver:          SolverType::NRP,
+        timestep:        SimulationTime::from_secs(1),
+        finaltime:       SimulationTime::from_secs(360),
+        contingencies:   vec![],
+        all_n_minus_1:   false,
+        events:          vec![],
+        realtime:        None,
+        limits:          Limits::default(),
+        worker_parameters: Default::default(),
+        name:            "".to_string(),
+        description:     "".to_string(),
+        labels:          Default::default(),
+    };
+    let post = |body: &str| client.post("/simulation")
+        .header(ct.clone())
+        .header(Header::new("Idempotency-Key", "test-post-simulation-idempotency-key"))
+        .body(body)
+        .dispatch();
+
+    // The first request creates the simulation and its results file
+    let response = post(&serde_json::to_string(&form).unwrap());
+    assert_eq!(response.status().code, 200);
+    let first_reply = response.into_string().unwrap();
+    let received_json: Simulation = serde_json::from_str( first_reply.as_str() ).unwrap();
+    assert_eq!(received_json.results_id, "100");
+
+    // A retry gets the first response back as it was, not the stored simulation
+    let response = post(&serde_json::to_string(&form).unwrap());
+    assert_eq!(response.status().code, 200);
+    assert_eq!(response.content_type(), Some(ContentType::JSON));
+    assert_eq!(response.into_string().unwrap(), first_reply);
+
+    form.finaltime = SimulationTime::from_secs(20);
+    let response = post(&serde_json::to_string(&form).unwrap());
+    assert_eq!(response.status().code, 409);
+
+    // A claim on a key expires soon, only a stored response is kept for long
+    assert_eq!(crate::db::idempotency_ttl("test-post-simulation-idempotency-key"), Some(idempotency::KEY_TTL_SECONDS));
+    assert_eq!(idempotency::begin("test-idempotency-claim", "hash").unwrap(), None);
+    assert_eq!(crate::db::idempotency_ttl("test-idempotency-claim"), Some(idempotency::CLAIM_TTL_SECONDS));
+    assert_eq!(idempotency::begin("test-idempotency-claim", "hash").unwrap_err().http_status_code, Status::Conflict);
+}
+
+#[test]
+fn test_creation_saga_abort() {
+    let runtime = rocket::tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        let audit_context = AuditContext {
+            actor:      "saga-operator".into(),
+            source_ip:  None,
+            request_id: "saga-abort".into()
+        };
+        let mut saga = CreationSaga::new(audit_context.clone(), AuditOperation::Create);
+        let simulation_id = saga.reserve_id().unwrap();
+        let results_id = saga.create_results_file().await.unwrap();
+        assert_eq!(saga.results_files(), ["100".to_string()]);
+
+        // Nothing has been written yet, so only the results file is removed
+        let error = saga.abort(SimulationError { err: "file service down".into(), http_status_code: Status::BadGateway }).await;
+        assert_eq!(error.http_status_code, Status::BadGateway);
+        assert_eq!(error.err, "file service down");
+        assert!(saga.results_files().is_empty());
+        assert!(saga.simulation().is_none());
+
+        let mut saga = CreationSaga::new(audit_context, AuditOperation::Create);
+        saga.create_results_file().await.unwrap();
+        let simulation: Simulation = serde_json::from_value(json!({
+            "error": "", "load_profile_id": "", "model_id": "1", "results_id": results_id,
+            "results_data": "", "simulation_id": simulation_id, "simulation_type": "Powerflow",
+            "domain": "SP", "solver": "NRP", "timestep": 1, "finaltime": 360
+        })).unwrap();
+        saga.write_record(simulation).unwrap();
+
+        // Once the record is written, it is marked Failed with the reason
+        let error = saga.abort(SimulationError { err: "Could not publish to amqp server".into(), http_status_code: Status::BadGateway }).await;
+        assert_eq!(error.http_status_code, Status::BadGateway);
+        assert!(error.err.contains("has been marked as Failed"));
+        let simulation = saga.simulation().unwrap();
+        assert_eq!(simulation.status, SimulationStatus::Failed);
+        assert_eq!(simulation.error, "Simulation could not be started: Could not publish to amqp server");
+        assert_eq!(simulation.results_id, "");
+        assert!(saga.results_files().is_empty());
+
+        // The creation is audited, with the record as it was marked Failed
+        let entries = audit::query(Some(simulation_id), Some("saga-operator"), None, None).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].operation, AuditOperation::Create);
+        assert_eq!(entries[0].request_id, "saga-abort");
+        assert_eq!(entries[0].after.status, SimulationStatus::Failed);
+    });
+
+    // Replies of the file service without a file are errors, so that the saga is aborted
+    assert_eq!(file_service::reply_field(br#"{ "data": { "fileID": "100" } }"#, "fileID"), Ok("100".to_string()));
+    let error = file_service::reply_field(br#"{ "error": { "message": "bucket not found" } }"#, "fileID").unwrap_err();
+    assert!(error.0.ends_with("bucket not found"));
+    assert!(file_service::reply_field(b"502 Bad Gateway", "url").is_err());
+    assert!(file_service::reply_field(br#"{ "data": {} }"#, "url").is_err());
+}
+
+#[test]
+fn test_simulation_template() {
+    let client = Client::untracked(rocket()).expect("valid rocket instance");
+
+    let ct = "application/json"
+        .parse::<ContentType>()
+        .unwrap();
+
+    // The template gives the type, domain, solver, times and executable
+    let body = r#"{ "template_id": 1, "model_id": "1", "load_profile_id": "1", "finaltime": "20s" }"#;
+    let response = client.post("/simulation")
+        .header(ct.clone())
+        .body(body)
+        .dispatch();
+    assert_eq!(response.status().code, 200);
+    let received_json: Simulation = serde_json::from_str( response.into_string().unwrap().as_str() ).unwrap();
+    assert_eq!(received_json.template_id, Some(1));
+    assert_eq!(received_json.timestep, SimulationTime::from_secs(1));
+    assert_eq!(received_json.finaltime, SimulationTime::from_secs(20));
+    assert_eq!(received_json.worker_parameters["executable"], "SLEW_Shmem_CIGRE_MV_PowerFlow");
+
+    let body = r#"{ "template_id": 2, "model_id": "1", "load_profile_id": "1" }"#;
+    let response = client.post("/simulation")
+        .header(ct.clone())
+        .body(body)
+        .dispatch();
+    assert_eq!(response.status().code, 404);
+
+    let body = r#"{ "model_id": "1", "load_profile_id": "1" }"#;
+    let response = client.post("/simulation")
+        .header(ct.clone())
+        .body(body)
+        .dispatch();
+    assert_eq!(response.status().code, 400);
+
+    let body = r#"{ "name": "Short EMT", "parameters": { "domain": "EMT", "timestep": "50us", "finaltime": "1s" } }"#;
+    let response = client.post("/template")
+        .header(ct.clone())
+        .body(body)
+        .dispatch();
+    assert_eq!(response.status().code, 200);
+
+    let body = r#"{ "name": "Bad", "parameters": { "worker_parameters": { "timestep": "1" } } }"#;
+    let response = client.post("/template")
+        .header(ct)
+        .body(body)
+        .dispatch();
+    assert_eq!(response.status().code, 400);
+}
+
+#[test]
+fn test_post_simulation_rerun() {
+    let client = Client::untracked(rocket()).expect("valid rocket instance");
+
+    let response = client.post("/simulation/1/rerun").dispatch();
+    assert_eq!(response.status().code, 200);
+    let received_json: Simulation = serde_json::from_str( response.into_string().unwrap().as_str() ).unwrap();
+    assert_eq!(received_json.rerun_of, Some(1));
+    assert_eq!(received_json.results_id, "100");
+    assert_eq!(received_json.finaltime, SimulationTime::from_secs(360));
+
+    let ct = "application/json"
+        .parse::<ContentType>()
+        .unwrap();
+    let response = client.post("/simulation/1/rerun")
+        .header(ct.clone())
+        .body(r#"{ "finaltime": "10s", "domain": "DP" }"#)
+        .dispatch();
+    assert_eq!(response.status().code, 200);
+    let received_json: Simulation = serde_json::from_str( response.into_string().unwrap().as_str() ).unwrap();
+    assert_eq!(received_json.finaltime, SimulationTime::from_secs(10));
+    assert_eq!(received_json.timestep, SimulationTime::from_secs(1));
+    assert!(matches!(received_json.domain, DomainType::DP));
+
+    let response = client.post("/simulation/1/rerun")
+        .header(ct.clone())
+        .body(r#"{ "finaltime": "10 minutes" }"#)
+        .dispatch();
+    assert_eq!(response.status().code, 400);
+
+    // null removes a parameter, so a rerun can switch from a model file to a registered model
+    let response = client.post("/simulation/1/rerun")
+        .header(ct)
+        .body(r#"{ "model": { "name": "cigre-mv" }, "model_id": null }"#)
+        .dispatch();
+    assert_eq!(response.status().code, 200);
+    let received_json: Simulation = serde_json::from_str( response.into_string().unwrap().as_str() ).unwrap();
+    assert_eq!(received_json.model, Some(ModelRef { name: "cigre-mv".into(), version: Some(1) }));
+    assert_eq!(received_json.model_id, "");
+
+    // and from a registered load profile to a load profile file, or to none
+    let mut original = crate::db::read_simulation(1).unwrap();
+    original.load_profile    = Some(1);
+    original.load_profile_id = Some("cigre-mv-profile.zip".into());
+    original.realtime        = serde_json::from_value(json!({ "interface_type": "Shmem" })).ok();
+    assert!(original.realtime.is_some());
+    let parameters = template::SimulationParameters::from_simulation(&original);
+    let form = template::resolve_rerun(&parameters, &json!({ "load_profile_id": "2" })).unwrap();
+    assert!(load_profile::resolve(form.load_profile_id.as_deref(), form.load_profile).is_err());
+    let form = template::resolve_rerun(&parameters, &json!({ "load_profile_id": "2", "load_profile": null })).unwrap();
+    assert_eq!((form.load_profile_id.as_deref(), form.load_profile), (Some("2"), None));
+    let form = template::resolve_rerun(&parameters, &json!({ "load_profile_id": null, "load_profile": null, "realtime": null })).unwrap();
+    assert_eq!((form.load_profile_id, form.load_profile), (None, None));
+    assert!(form.realtime.is_none());
+    assert!(template::resolve_rerun(&parameters, &json!([])).is_err());
+}
+
+#[test]
+fn test_simulation_labels_and_search() {
+    let client = Client::untracked(rocket()).expect("valid rocket instance");
+
+    let ct = "application/json"
+        .parse::<ContentType>()
+        .unwrap();
+
+    let body = r#"{ "name": "Aachen feeder, base case", "labels": { "study": "aachen-feeder", "reviewed": "yes" } }"#;
+    let response = client.patch("/simulation/1")
+        .header(ct.clone())
+        .body(body)
+        .dispatch();
+    assert_eq!(response.status().code, 200);
+    let received_json: Simulation = serde_json::from_str( response.into_string().unwrap().as_str() ).unwrap();
+    assert_eq!(received_json.name, "Aachen feeder, base case");
+    assert_eq!(received_json.labels["study"], "aachen-feeder");
+
+    let body = r#"{ "labels": { "study=1": "aachen-feeder" } }"#;
+    let response = client.patch("/simulation/1")
+        .header(ct.clone())
+        .body(body)
+        .dispatch();
+    assert_eq!(response.status().code, 400);
+
+    let response = client.get("/simulation/search?labels=study=aachen-feeder&q=Feeder")
+        .header(ct.clone())
+        .dispatch();
+    assert_eq!(response.status().code, 200);
+    let received_json: SimulationArray = serde_json::from_str( response.into_string().unwrap().as_str() ).unwrap();
+    assert_eq!(received_json.simulations.len(), 1);
+    assert_eq!(received_json.simulations[0].simulation_id, 1);
+
+    let response = client.get("/simulation/search")
+        .header(ct)
+        .dispatch();
+    assert_eq!(response.status().code, 400);
+
+    assert_eq!(search::query_keys(Some("study=aachen-feeder, reviewed"), Some("Base case")).unwrap(),
+               vec!["index:label:reviewed", "index:label:study=aachen-feeder", "index:word:base", "index:word:case"]);
+}
+
+#[test]
+fn test_get_metrics() {
+    let client = Client::untracked(rocket()).expect("valid rocket instance");
+
+    let response = client.get("/simulation/1")
+        .header(ContentType::JSON)
+        .dispatch();
+    assert_eq!(response.status().code, 200);
+
+    let response = client.get("/metrics").dispatch();
+    assert_eq!(response.status().code, 200);
+    assert_eq!(response.content_type().unwrap().media_type(), ContentType::Plain.media_type());
+    let body = response.into_string().unwrap();
+    assert!(body.contains(r#"dpsim_api_http_requests_total{method="GET",route="get_simulation_id",status="200"}"#));
+    assert!(body.contains(r#"dpsim_api_simulations{status="Queued"} 0"#));
+}
+
+#[test]
+fn test_health_probes() {
+    let client = Client::untracked(rocket()).expect("valid rocket instance");
+
+    let response = client.get("/healthz").dispatch();
+    assert_eq!(response.status().code, 200);
+    let health: Health = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    assert_eq!(health.status, "ok");
+
+    let response = client.get("/readyz").dispatch();
+    assert_eq!(response.status().code, 200);
+    let readiness: Readiness = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    assert!(readiness.ready);
+    let names: Vec<&str> = readiness.dependencies.iter().map(|dependency| dependency.name.as_str()).collect();
+    assert_eq!(names, ["redis", "amqp", "file_service"]);
+    assert!(readiness.dependencies.iter().all(|dependency| dependency.ok && dependency.error.is_none()));
+}
+
+#[test]
+fn test_request_ids() {
+    let client = Client::untracked(rocket()).expect("valid rocket instance");
+
+    let response = client.get("/simulation/1")
+        .header(Header::new("X-Request-Id", "trace-42"))
+        .dispatch();
+    assert_eq!(response.headers().get_one("X-Request-Id"), Some("trace-42"));
+
+    let response = client.get("/simulation/1").dispatch();
+    let generated = response.headers().get_one("X-Request-Id").unwrap();
+    assert_eq!(generated.len(), 32);
+    assert!(generated.chars().all(|c| c.is_ascii_hexdigit()));
+
+    let runtime = rocket::tokio::runtime::Runtime::new().unwrap();
+    let (request_id, simulation_id) = runtime.block_on(logging::in_context(LogContext::new("trace-42".into(), None), async {
+        logging::set_simulation_id(7);
+        logging::in_current_context(async { (logging::request_id(), logging::simulation_id()) }).await
+    }));
+    assert_eq!(request_id.as_deref(), Some("trace-42"));
+    assert_eq!(simulation_id, Some(7));
+    assert_eq!(logging::request_id(), None);
+
+    let line = logging::format_line(LogFormat::Json, log::Level::Info, "dpsim_api::routes",
+                                    "\u{1b}[1mCreated\u{1b}[0m simulation", Some("trace-42"), Some(7));
+    let line: serde_json::Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(line["message"], "Created simulation");
+    assert_eq!(line["request_id"], "trace-42");
+    assert_eq!(line["simulation_id"], 7);
+}
+
+#[test]
+fn test_trace_propagation() {
+    let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+    let client = Client::untracked(rocket()).expect("valid rocket instance");
+    let response = client.get("/simulation/1")
+        .header(ContentType::JSON)
+        .header(Header::new("traceparent", traceparent))
+        .dispatch();
+    assert_eq!(response.status().code, 200);
+
+    assert!(!telemetry::trace_headers().contains_key("traceparent"));
+    let mut incoming = std::collections::HashMap::new();
+    incoming.insert("traceparent".to_string(), traceparent.to_string());
+    let _attached = TraceContextPropagator::new().extract(&incoming).attach();
+    let outgoing = telemetry::in_span("file_service get_data_from_url", SpanKind::Client, Vec::new(), || {
+        Ok::<_, String>(telemetry::trace_headers())
+    }).unwrap();
+    assert!(outgoing["traceparent"].starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+    assert!(telemetry::step("simulation.validate", || Err::<(), _>("invalid".to_string())).is_err());
+}
+
+#[test]
+fn test_audit_log() {
+    let client = Client::untracked(rocket()).expect("valid rocket instance");
+    let actor = Header::new("X-Forwarded-User", "audit-operator");
+
+    // Without a trusted proxy, the headers of the client are ignored
+    let response = client.patch("/simulation/1")
+        .header(ContentType::JSON)
+        .header(actor.clone())
+        .header(Header::new("X-Real-IP", "10.1.2.3"))
+        .header(Header::new("X-Request-Id", "audit-untrusted"))
+        .body(r#"{ "description": "Untrusted change" }"#)
+        .dispatch();
+    assert_eq!(response.status().code, 200);
+    let untrusted: Vec<AuditEntry> = audit::query(Some(1), None, None, None).unwrap().into_iter()
+        .filter(|entry| entry.request_id == "audit-untrusted")
+        .collect();
+    assert_eq!(untrusted.len(), 1);
+    assert_eq!(untrusted[0].actor, "anonymous");
+    assert_ne!(untrusted[0].source_ip.as_deref(), Some("10.1.2.3"));
+
+    std::env::set_var("AUDIT_USER_HEADER", "X-Forwarded-User");
+
+    let response = client.patch("/simulation/1")
+        .header(ContentType::JSON)
+        .header(actor.clone())
+        .header(Header::new("X-Request-Id", "audit-patch"))
+        .body(r#"{ "description": "Audited change" }"#)
+        .dispatch();
+    assert_eq!(response.status().code, 200);
+
+    let response = client.put("/simulation/1/status")
+        .header(ContentType::JSON)
+        .header(actor)
+        .body(r#"{ "status": "Cancelled" }"#)
+        .dispatch();
+    assert_eq!(response.status().code, 200);
+
+    let response = client.get("/audit?simulation_id=1&actor=audit-operator")
+        .header(ContentType::JSON)
+        .dispatch();
+    assert_eq!(response.status().code, 200);
+    let received_json: AuditArray = serde_json::from_str( response.into_string().unwrap().as_str() ).unwrap();
+    let operations: Vec<AuditOperation> = received_json.entries.iter().map(|entry| entry.operation).collect();
+    assert_eq!(operations, vec![AuditOperation::Patch, AuditOperation::Cancel]);
+    let patch = &received_json.entries[0];
+    assert_eq!(patch.request_id, "audit-patch");
+    assert_eq!(patch.before.as_ref().unwrap().description, "");
+    assert_eq!(patch.after.description, "Audited change");
+    assert_eq!(received_json.entries[1].after.status, SimulationStatus::Cancelled);
+
+    let response = client.get("/audit?actor=nobody")
+        .header(ContentType::JSON)
+        .dispatch();
+    let received_json: AuditArray = serde_json::from_str( response.into_string().unwrap().as_str() ).unwrap();
+    assert!(received_json.entries.is_empty());
+
+    let response = client.get("/audit?from=200&to=100")
+        .header(ContentType::JSON)
+        .dispatch();
+    assert_eq!(response.status().code, 400);
+}
+
+#[test]
+fn test_model_registry() {
+    let client = Client::untracked(rocket()).expect("valid rocket instance");
+
+    let response = client.post("/model")
+        .header(ContentType::JSON)
+        .body(r#"{ "name": "cigre-mv", "file_ids": ["cigre-mv-EQ", "cigre-mv-TP", "cigre-mv-SV", "cigre-mv-SSH"] }"#)
+        .dispatch();
+    assert_eq!(response.status().code, 200);
+    let received_json: Model = serde_json::from_str( response.into_string().unwrap().as_str() ).unwrap();
+    assert_eq!(received_json.version, 2);
+    assert_eq!(received_json.checksum.len(), 64);
+    assert_eq!(received_json.created_by, "anonymous");
+
+    let response = client.post("/model")
+        .header(ContentType::JSON)
+        .body(r#"{ "name": "cigre-mv", "file_ids": [] }"#)
+        .dispatch();
+    assert_eq!(response.status().code, 400);
+
+    let response = client.get("/model/cigre-mv/3").dispatch();
+    assert_eq!(response.status().code, 404);
+
+    let body = r#"{ "simulation_type": "Powerflow", "model": { "name": "cigre-mv" }, "load_profile_id": "1",
+                    "domain": "SP", "solver": "NRP", "timestep": 1, "finaltime": 360 }"#;
+    let response = client.post("/simulation")
+        .header(ContentType::JSON)
+        .body(body)
+        .dispatch();
+    assert_eq!(response.status().code, 200);
+    let received_json: Simulation = serde_json::from_str( response.into_string().unwrap().as_str() ).unwrap();
+    assert_eq!(received_json.model, Some(ModelRef { name: "cigre-mv".into(), version: Some(1) }));
+    assert_eq!(received_json.model_files.len(), 4);
+
+    let body = r#"{ "simulation_type": "Powerflow", "model_id": "1", "model": { "name": "cigre-mv" },
+                    "load_profile_id": "1", "domain": "SP", "solver": "NRP", "timestep": 1, "finaltime": 360 }"#;
+    let response = client.post("/simulation")
+        .header(ContentType::JSON)
+        .body(body)
+        .dispatch();
+    assert_eq!(response.status().code, 400);
+}
+
+#[test]
+fn test_model_validation() {
+    let client = Client::untracked(rocket()).expect("valid rocket instance");
+
+    let response = client.post("/model/validate")
+        .header(ContentType::JSON)
+        .body(r#"{ "file_ids": ["cigre-mv-EQ", "cigre-mv-TP", "cigre-mv-SV", "cigre-mv-SSH"] }"#)
+        .dispatch();
+    assert_eq!(response.status().code, 200);
+    let received_json: ModelValidation = serde_json::from_str( response.into_string().unwrap().as_str() ).unwrap();
+    assert!(received_json.valid, "{:?}", received_json.problems);
+    assert!(received_json.problems.is_empty());
+    assert_eq!(received_json.profiles, vec![CimProfile::EQ, CimProfile::TP, CimProfile::SV, CimProfile::Ssh]);
+    assert_eq!(received_json.counts, CimCounts { topological_nodes: 3, lines: 1, transformers: 1, loads: 1 });
+
+    // "1" is not XML, and without SV and SSH there are warnings too
+    let response = client.post("/model/validate")
+        .header(ContentType::JSON)
+        .body(r#"{ "file_ids": ["cigre-mv-EQ", "cigre-mv-TP-dangling", "1"] }"#)
+        .dispatch();
+    let received_json: ModelValidation = serde_json::from_str( response.into_string().unwrap().as_str() ).unwrap();
+    assert!(!received_json.valid);
+    assert_eq!(received_json.problems.len(), 1);
+    assert_eq!(received_json.problems[0].file_id.as_deref(), Some("1"));
+
+    let files: Vec<(String, Vec<u8>)> = ["cigre-mv-EQ", "cigre-mv-TP-dangling"].iter()
+        .map(|id| (id.to_string(), std::fs::read(format!("testdata/cim/{}.xml", id)).unwrap()))
+        .collect();
+    let validation = cim::validate(&files);
+    assert!(!validation.valid);
+    let errors: Vec<&ModelProblem> = validation.problems.iter().filter(|p| p.severity == Severity::Error).collect();
+    assert_eq!(errors.len(), 2);
+    assert!(errors.iter().all(|p| p.message.contains("unknown topological node _N9")));
+    assert_eq!(validation.problems.len(), 4);
+    assert!(validation.problems.iter().any(|p| p.message == "No SSH profile in model"));
+    assert_eq!(serde_json::to_string(&CimProfile::Ssh).unwrap(), r#""SSH""#);
+
+    let response = client.post("/model")
+        .header(ContentType::JSON)
+        .body(r#"{ "name": "cigre-mv", "file_ids": ["cigre-mv-EQ", "cigre-mv-TP-dangling"] }"#)
+        .dispatch();
+    assert_eq!(response.status().code, 400);
+}
+
+#[test]
+fn test_load_profile_registry() {
+    let client = Client::untracked(rocket()).expect("valid rocket instance");
+
+    let response = client.post("/load_profile")
+        .header(ContentType::JSON)
+        .body(r#"{ "name": "cigre-mv-10min", "file_id": "cigre-mv-profile.zip" }"#)
+        .dispatch();
+    assert_eq!(response.status().code, 200);
+    let received_json: LoadProfile = serde_json::from_str( response.into_string().unwrap().as_str() ).unwrap();
+    assert_eq!(received_json.load_profile_id, 2);
+    assert_eq!(received_json.analysis.format, LoadProfileFormat::Zip);
+    assert_eq!(received_json.analysis.load_ids, vec!["Load2"]);
+    assert_eq!(received_json.analysis.resolution, SimulationTime::from_secs(60));
+    assert_eq!(received_json.analysis.duration, SimulationTime::from_secs(600));
+    assert_eq!(received_json.analysis.rows, 11);
+
+    let body = "time;Load2_p;Load2_q;Load9_p;Load9_q\n00:00:00;1.0;0.1;2.0;0.2\n00:15:00;1.1;0.1;2.1;0.2\n00:30:00;1.2;0.1;2.2;0.2\n";
+    let response = client.post("/load_profile/upload?name=day")
+        .header(ContentType::CSV)
+        .body(body)
+        .dispatch();
+    assert_eq!(response.status().code, 200);
+    let received_json: LoadProfile = serde_json::from_str( response.into_string().unwrap().as_str() ).unwrap();
+    assert_eq!(received_json.file_id, "101");
+    assert_eq!(received_json.analysis.format, LoadProfileFormat::Csv);
+    assert_eq!(received_json.analysis.time_format, TimeFormat::TimeOfDay);
+    assert_eq!(received_json.analysis.load_ids, vec!["Load2", "Load9"]);
+    assert_eq!(received_json.analysis.resolution, SimulationTime::from_secs(900));
+    assert_eq!((received_json.analysis.start.as_str(), received_json.analysis.end.as_str()), ("00:00:00", "00:30:00"));
+
+    // Load9 is not in the model
+    let check = load_profile::check(&received_json.analysis, &cim::loads(&[("cigre-mv-EQ".to_string(),
+        std::fs::read("testdata/cim/cigre-mv-EQ.xml").unwrap())]), None);
+    assert!(!check.consistent);
+    assert_eq!(check.errors(), "Load Load9 of the profile is not a load of the model");
+
+    // Real profiles are well over Rocket's 8KiB limit for byte bodies
+    let rows: String = (0..1000).map(|minute| format!("{},14.994,3.044\n", minute * 60)).collect();
+    let body = format!("time,Load2_p,Load2_q\n{}", rows);
+    assert!(body.len() > 8 * 1024);
+    let response = client.post("/load_profile/upload?name=year")
+        .header(ContentType::CSV)
+        .body(&body)
+        .dispatch();
+    assert_eq!(response.status().code, 200);
+    let received_json: LoadProfile = serde_json::from_str( response.into_string().unwrap().as_str() ).unwrap();
+    assert_eq!(received_json.analysis.rows, 1000);
+
+    let response = client.post("/load_profile/upload?name=bad")
+        .body("time,Load2\n0,1.0\n0,abc\n")
+        .dispatch();
+    assert_eq!(response.status().code, 400);
+
+    // A zip of a few kilobytes that decompresses to more than the limit
+    let mut bomb = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    bomb.start_file("Load2.csv", zip::write::FileOptions::default()).unwrap();
+    let zeros = vec![b'0'; 1024 * 1024];
+    for _ in 0..=load_profile::MAX_UNCOMPRESSED_FILE / (1024 * 1024) {
+        std::io::Write::write_all(&mut bomb, &zeros).unwrap();
+    }
+    let bomb = bomb.finish().unwrap().into_inner();
+    assert!(bomb.len() < 1024 * 1024);
+    let response = client.post("/load_profile/upload?name=bomb")
+        .body(bomb)
+        .dispatch();
+    assert_eq!(response.status().code, 400);
+    assert!(response.into_string().unwrap().contains("larger than"));
+
+    let response = client.get("/load_profile/1/preview?rows=2").header(ContentType::JSON).dispatch();
+    assert_eq!(response.status().code, 200);
+    let received_json: LoadProfilePreview = serde_json::from_str( response.into_string().unwrap().as_str() ).unwrap();
+    assert_eq!(received_json.tables.len(), 1);
+    assert_eq!(received_json.tables[0].file, "Load2.csv");
+    assert_eq!(received_json.tables[0].columns, vec!["time", "pload", "qload"]);
+    assert_eq!(received_json.tables[0].rows, vec![vec!["0", "14.994", "3.044"], vec!["60", "15.004", "3.044"]]);
+
+    let response = client.get("/load_profile/1/check?model=cigre-mv&finaltime=3600").header(ContentType::JSON).dispatch();
+    assert_eq!(response.status().code, 200);
+    let received_json: LoadProfileCheck = serde_json::from_str( response.into_string().unwrap().as_str() ).unwrap();
+    assert!(!received_json.consistent);
+    assert_eq!(received_json.problems.len(), 1);
+
+    let body = r#"{ "simulation_type": "Powerflow", "model": { "name": "cigre-mv" }, "load_profile": 1,
+                    "domain": "SP", "solver": "NRP", "timestep": 1, "finaltime": 360 }"#;
+    let response = client.post("/simulation")
+        .header(ContentType::JSON)
+        .body(body)
+        .dispatch();
+    assert_eq!(response.status().code, 200);
+    let received_json: Simulation = serde_json::from_str( response.into_string().unwrap().as_str() ).unwrap();
+    assert_eq!(received_json.load_profile, Some(1));
+    assert_eq!(received_json.load_profile_id.as_deref(), Some("cigre-mv-profile.zip"));
+
+    let response = client.post("/simulation")
+        .header(ContentType::JSON)
+        .body(body.replace("360", "3600"))
+        .dispatch();
+    assert_eq!(response.status().code, 400);
+}
+
+#[test]
+fn test_optional_load_profile() {
+    // Records stored before load_profile_id was optional
+    for legacy in ["None", ""] {
+        let simulation: Simulation = serde_json::from_value(json!({
+            "error": "", "load_profile_id": legacy, "model_id": "1", "results_id": "1", "results_data": "",
+            "simulation_id": 1, "simulation_type": "Powerflow", "domain": "SP", "solver": "NRP",
+            "timestep": 1, "finaltime": 360
+        })).unwrap();
+        assert_eq!(simulation.load_profile_id, None);
+        assert_eq!(serde_json::to_value(&simulation).unwrap()["load_profile_id"], serde_json::Value::Null);
+    }
+
+    let client = Client::untracked(rocket()).expect("valid rocket instance");
+    let body = r#"{ "simulation_type": "Powerflow", "model_id": "1", "domain": "SP", "solver": "NRP", "timestep": 1, "finaltime": 360 }"#;
+    let response = client.post("/simulation")
+        .header(ContentType::JSON)
+        .body(body)
+        .dispatch();
+    assert_eq!(response.status().code, 200);
+    let simulation: Simulation = serde_json::from_str( response.into_string().unwrap().as_str() ).unwrap();
+    assert_eq!(simulation.load_profile_id, None);
+
+    let message = amqp::worker_message(&AMQPSimulation::from_simulation(&simulation, vec!["http://model".into()], None));
+    assert!(message.get("load_profile").is_none());
+    assert_eq!(message["model"]["url"], json!(["http://model"]));
+    let message = amqp::worker_message(&AMQPSimulation::from_simulation(&simulation, vec![], Some("http://profile".into())));
+    assert_eq!(message["load_profile"], json!({ "type": "url-list", "url": ["http://profile"] }));
+}
+
+#[test]
+fn test_simulation_provenance() {
+    let client = Client::untracked(rocket()).expect("valid rocket instance");
+    let body = r#"{ "simulation_type": "Powerflow", "model": { "name": "cigre-mv" }, "load_profile": 1,
+                    "domain": "SP", "solver": "NRP", "timestep": 1, "finaltime": 360 }"#;
+    let post = |body: &str| -> Simulation {
+        let response = client.post("/simulation")
+            .header(ContentType::JSON)
+            .body(body)
+            .dispatch();
+        assert_eq!(response.status().code, 200);
+        serde_json::from_str( response.into_string().unwrap().as_str() ).unwrap()
+    };
+
+    // The provenance is not part of the simulation record, only the one stored for it is
+    let recorded_for = |finaltime: f64| -> Provenance {
+        crate::db::written_provenances().into_iter().rev()
+            .find(|provenance| provenance.worker_messages[0]["load_profile"].is_object()
+                               && provenance.worker_messages[0]["parameters"]["finaltime"].as_f64() == Some(finaltime))
+            .unwrap()
+    };
+    let simulation = post(body);
+    let reply = serde_json::to_value(&simulation).unwrap();
+    assert!(reply.get("provenance").is_none());
+    let recorded = recorded_for(360.0);
+    let roles: Vec<InputRole> = recorded.inputs.iter().map(|input| input.role).collect();
+    assert_eq!(roles, vec![InputRole::Model, InputRole::Model, InputRole::Model, InputRole::Model, InputRole::LoadProfile]);
+    let eq_profile = std::fs::read("testdata/cim/cigre-mv-EQ.xml").unwrap();
+    assert_eq!(recorded.inputs[0].sha256, hex::encode(Sha256::digest(&eq_profile)));
+    assert_eq!(recorded.worker_messages.len(), 1);
+    assert_eq!(recorded.worker_messages[0]["load_profile"]["url"], json!(["testdata/load_profiles/cigre-mv-profile.zip"]));
+
+    // The same inputs and parameters give the same hash, whatever the ids
+    let report = provenance::report(&simulation, recorded.clone());
+    assert_eq!(report.reproducibility_hash.len(), 64);
+    assert!(!report.complete);
+    assert_eq!(provenance::report(&post(body), recorded_for(360.0)).reproducibility_hash, report.reproducibility_hash);
+    let shorter = post(&body.replace("360", "300"));
+    assert_ne!(provenance::report(&shorter, recorded_for(300.0)).reproducibility_hash, report.reproducibility_hash);
+
+    let update: status::StatusUpdate = serde_json::from_str(
+        r#"{ "status": "Running", "worker_version": "0.4.2", "dpsim_version": "1.1.1" }"#).unwrap();
+    let mut updated = recorded;
+    provenance::apply_update(&mut updated, &update);
+    let updated = provenance::report(&simulation, updated);
+    assert!(updated.complete);
+    assert_eq!(updated.provenance.dpsim_version.as_deref(), Some("1.1.1"));
+    assert_ne!(updated.reproducibility_hash, report.reproducibility_hash);
+
+    // Simulations are all created with id 1, so 2 has no provenance
+    let response = client.get("/simulation/2/provenance").header(ContentType::JSON).dispatch();
+    assert_eq!(response.status().code, 404);
+}
@@ -0,0 +1,410 @@
+//!
+//! # Webhook notifications
+//!
+//! A webhook is a URL that is sent a POST request when a simulation
+//! reaches one of the finished states it is registered for. A webhook
+//! can be limited to a single simulation, otherwise it is called for
+//! every simulation.
+//!
+//! The body is a json [`WebhookPayload`]. It is signed with HMAC-SHA256
+//! using the secret given at registration, and the signature is sent as
+//! `X-Webhook-Signature: sha256=<hex digest of the body>`.
+//!
+//! A delivery that fails, or gets a response other than 2xx, is retried
+//! with exponential backoff. Every attempt is recorded in the delivery
+//! log of the webhook.
+//!
+//! Webhooks are called from inside the cluster, so the URL must not point
+//! at a loopback, private, link-local or otherwise reserved address, or at
+//! an internal name such as `redis` or `sogno-file-service.default.svc`.
+//! This is checked when a webhook is registered, where numeric hosts that
+//! are not a canonical IP address, such as `127.1`, are rejected too, and
+//! again on every delivery: the host is resolved by the API itself, and
+//! the request only goes to the addresses that passed the check, so that
+//! a name that resolves, or later rebinds, to an internal address is never
+//! called. Hosts that should be called anyway are listed, comma separated,
+//! in the environment variable `WEBHOOK_ALLOWED_HOSTS`.
+//!
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::{ Serialize, Deserialize };
+use schemars::JsonSchema;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use log::info;
+use crate::db;
+use crate::logging;
+use crate::status::{SimulationStatus, StatusEvent};
+
+const MAX_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+#[cfg(not(test))]
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const ALLOWED_HOSTS_VARIABLE: &str = "WEBHOOK_ALLOWED_HOSTS";
+// Names that only resolve inside a cluster or on the local network
+const INTERNAL_SUFFIXES: [&str; 4] = [".local", ".localhost", ".internal", ".svc"];
+
+/// # Form for registering a new Webhook
+///
+/// ## Parameters:
+/// * url
+///   - String
+///   - an http or https URL of a public host, or of one in `WEBHOOK_ALLOWED_HOSTS`
+/// * events
+///   - list of "Succeeded", "Failed", "Cancelled"
+/// * secret
+///   - String
+///   - the key that payloads are signed with
+/// * simulation_id
+///   - optional, only call the webhook for this simulation
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct WebhookForm {
+    pub url:           String,
+    pub events:        Vec<SimulationStatus>,
+    pub secret:        String,
+    #[serde(default)]
+    pub simulation_id: Option<u64>
+}
+
+#[doc = "A registered webhook. The secret is only ever returned empty"]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Webhook {
+    pub webhook_id:    u64,
+    pub url:           String,
+    pub events:        Vec<SimulationStatus>,
+    #[serde(default)]
+    pub secret:        String,
+    #[serde(default)]
+    pub simulation_id: Option<u64>
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[doc = "Struct for encapsulation a list of Webhooks"]
+pub struct WebhookArray {
+    pub webhooks: Vec<Webhook>
+}
+
+#[doc = "The body sent to a webhook"]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct WebhookPayload {
+    pub event:         SimulationStatus,
+    pub simulation_id: u64,
+    pub progress:      u8,
+    pub error:         String,
+    pub timestamp:     u64,
+    pub test:          bool
+}
+
+#[doc = "A record of one attempt to call a webhook"]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Delivery {
+    pub webhook_id:    u64,
+    pub simulation_id: u64,
+    pub event:         SimulationStatus,
+    pub attempt:       u32,
+    pub timestamp:     u64,
+    pub status_code:   Option<u16>,
+    pub success:       bool,
+    pub error:         String
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[doc = "Struct for encapsulation a list of Deliveries"]
+pub struct DeliveryArray {
+    pub deliveries: Vec<Delivery>
+}
+
+impl Webhook {
+    pub fn from_form(webhook_id: u64, form: &WebhookForm) -> Webhook {
+        Webhook {
+            webhook_id,
+            url:           form.url.clone(),
+            events:        form.events.clone(),
+            secret:        form.secret.clone(),
+            simulation_id: form.simulation_id
+        }
+    }
+
+    #[doc = "A copy that is safe to return from the API"]
+    pub fn without_secret(&self) -> Webhook {
+        Webhook { secret: "".into(), ..self.clone() }
+    }
+
+    pub fn wants(&self, event: &StatusEvent) -> bool {
+        self.events.contains(&event.status)
+            && self.simulation_id.is_none_or(|id| id == event.simulation_id)
+    }
+}
+
+fn is_internal_ipv4(ip: &Ipv4Addr) -> bool {
+    let [first, second, third, _] = ip.octets();
+    ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_multicast() || ip.is_documentation()
+        // 0.0.0.0/8, "this network"
+        || first == 0
+        // 100.64.0.0/10, shared by carrier-grade NATs and often used for pods
+        || (first == 100 && (second & 0xc0) == 64)
+        // 192.0.0.0/24, for protocol assignments
+        || (first == 192 && second == 0 && third == 0)
+        // 198.18.0.0/15, for benchmarking
+        || (first == 198 && (second & 0xfe) == 18)
+        // 240.0.0.0/4, reserved, and the broadcast address
+        || first >= 240
+}
+
+fn is_internal_ipv6(ip: &Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    // IPv4-mapped ::ffff:a.b.c.d, IPv4-compatible ::a.b.c.d and NAT64 64:ff9b::a.b.c.d
+    // addresses reach the IPv4 address they embed
+    let embedded = (segments[..5] == [0; 5] && matches!(segments[5], 0 | 0xffff))
+                   || segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0];
+    if embedded {
+        let [a, b] = segments[6].to_be_bytes();
+        let [c, d] = segments[7].to_be_bytes();
+        return is_internal_ipv4(&Ipv4Addr::new(a, b, c, d))
+    }
+    ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() || ip.is_unique_local() || ip.is_unicast_link_local()
+}
+
+#[doc = "Whether an address is inside the cluster, the local network or a reserved range"]
+pub fn is_internal_address(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_internal_ipv4(ip),
+        IpAddr::V6(ip) => is_internal_ipv6(ip)
+    }
+}
+
+fn bare_host(host: &str) -> String {
+    host.trim_start_matches('[').trim_end_matches(']').trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[doc = "Whether a host is an address or a name inside the cluster or the local network"]
+pub fn is_internal_host(host: &str) -> bool {
+    let host = bare_host(host);
+    match host.parse::<IpAddr>() {
+        Ok(ip) => is_internal_address(&ip),
+        // Single label names, such as redis, are those of services next to the API
+        Err(_) => !host.contains('.') || INTERNAL_SUFFIXES.iter().any(|suffix| host.ends_with(suffix))
+    }
+}
+
+#[doc = "Whether a host is a number that resolvers read as an IPv4 address, but not in the canonical \
+         dotted form, such as 127.1, 0x7f.1 or 2130706433"]
+pub fn is_non_canonical_address(host: &str) -> bool {
+    let host = bare_host(host);
+    // No top level domain is numeric, so a numeric last label makes an address
+    let last_label = host.rsplit('.').next().unwrap_or_default();
+    let numeric = !last_label.is_empty()
+        && (last_label.bytes().all(|byte| byte.is_ascii_digit())
+            || (last_label.starts_with("0x") && last_label[2..].bytes().all(|byte| byte.is_ascii_hexdigit())));
+    numeric && host.parse::<Ipv4Addr>().is_err()
+}
+
+#[doc = "The addresses a host resolved to, if none of them is internal or the host is allowed"]
+pub fn checked_addresses(host: &str, addresses: Vec<SocketAddr>) -> Result<Vec<SocketAddr>, String> {
+    if is_allowed_host(&bare_host(host)) {
+        return Ok(addresses)
+    }
+    if addresses.is_empty() {
+        return Err(format!("Host {} has no address", host))
+    }
+    match addresses.iter().find(|address| is_internal_address(&address.ip())) {
+        Some(address) => Err(format!("Host {} resolves to the internal address {}, which is only allowed if it is in {}",
+                                     host, address.ip(), ALLOWED_HOSTS_VARIABLE)),
+        None => Ok(addresses)
+    }
+}
+
+fn is_allowed_host(host: &str) -> bool {
+    std::env::var(ALLOWED_HOSTS_VARIABLE).is_ok_and(|hosts| {
+        hosts.split(',').map(str::trim).any(|allowed| !allowed.is_empty() && allowed.eq_ignore_ascii_case(host))
+    })
+}
+
+#[doc = "Check that a webhook form can be registered"]
+pub fn validate_form(form: &WebhookForm) -> Result<(), String> {
+    let uri = match form.url.parse::<hyper::Uri>() {
+        Ok(uri) => uri,
+        Err(e) => return Err(format!("url '{}' is not valid: {}", form.url, e))
+    };
+    let host = match uri.host() {
+        Some(host) if matches!(uri.scheme_str(), Some("http") | Some("https")) => host,
+        _ => return Err(format!("url '{}' must be an http or https URL", form.url))
+    };
+    if is_non_canonical_address(host) {
+        return Err(format!("url '{}' has a numeric host that is not a canonical IP address", form.url))
+    }
+    if is_internal_host(host) && !is_allowed_host(host) {
+        return Err(format!("url '{}' points at an internal host, which is only allowed if it is in {}",
+                           form.url, ALLOWED_HOSTS_VARIABLE))
+    }
+    if form.events.is_empty() {
+        return Err("events must name at least one of Succeeded, Failed, Cancelled".into())
+    }
+    if let Some(event) = form.events.iter().find(|event| !event.is_finished()) {
+        return Err(format!("{:?} is not an event that webhooks can be registered for", event))
+    }
+    if form.secret.is_empty() {
+        return Err("secret must not be empty".into())
+    }
+    Ok(())
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[doc = "The signature of a payload, as sent in the X-Webhook-Signature header"]
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[doc = "The payload for a status change"]
+pub fn payload(event: &StatusEvent, test: bool) -> WebhookPayload {
+    WebhookPayload {
+        event:         event.status,
+        simulation_id: event.simulation_id,
+        progress:      event.progress,
+        error:         event.error.clone(),
+        timestamp:     now(),
+        test
+    }
+}
+
+#[doc = "Resolves the host of a webhook, and only gives the connector addresses that are not internal"]
+#[cfg(not(test))]
+#[derive(Clone)]
+struct CheckedResolver;
+
+#[cfg(not(test))]
+impl tower_service::Service<hyper::client::connect::dns::Name> for CheckedResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = std::io::Error;
+    type Future = std::pin::Pin<Box<dyn std::future::Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: hyper::client::connect::dns::Name) -> Self::Future {
+        Box::pin(async move {
+            let host = name.as_str();
+            // The connector sets the port of the URL on the addresses
+            let addresses = rocket::tokio::net::lookup_host((host, 0)).await?.collect();
+            match checked_addresses(host, addresses) {
+                Ok(addresses) => Ok(addresses.into_iter()),
+                Err(e) => Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, e))
+            }
+        })
+    }
+}
+
+#[cfg(not(test))]
+async fn send(url: &str, body: Vec<u8>, signature: String, event: SimulationStatus) -> Result<u16, String> {
+    use hyper::{Body, Client, Request};
+    use hyper::client::HttpConnector;
+    // The connector only resolves names, so addresses in the URL are checked here
+    let uri = url.parse::<hyper::Uri>().map_err(|e| e.to_string())?;
+    let host = uri.host().unwrap_or_default();
+    if is_non_canonical_address(host) {
+        return Err(format!("Host {} is not a canonical IP address", host))
+    }
+    if let Ok(ip) = bare_host(host).parse::<IpAddr>() {
+        checked_addresses(host, vec![SocketAddr::new(ip, 0)])?;
+    }
+    let mut http = HttpConnector::new_with_resolver(CheckedResolver);
+    http.enforce_http(false);
+    let connector = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .wrap_connector(http);
+    let client: Client<_, Body> = Client::builder().build(connector);
+    let request = Request::post(url)
+        .header("Content-Type", "application/json")
+        .header("X-Webhook-Signature", signature)
+        .header("X-Webhook-Event", format!("{:?}", event))
+        .body(Body::from(body))
+        .map_err(|e| e.to_string())?;
+    match rocket::tokio::time::timeout(REQUEST_TIMEOUT, client.request(request)).await {
+        Ok(Ok(response)) => Ok(response.status().as_u16()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!("No response within {} seconds", REQUEST_TIMEOUT.as_secs()))
+    }
+}
+
+#[cfg(test)]
+async fn send(url: &str, body: Vec<u8>, signature: String, event: SimulationStatus) -> Result<u16, String> {
+    println!("Webhook {:?} to {}: {} {}", event, url, signature, String::from_utf8_lossy(&body));
+    Ok(200)
+}
+
+#[doc = "Make one attempt to deliver a payload to a webhook, and record it in the delivery log"]
+pub async fn deliver(webhook: &Webhook, payload: &WebhookPayload, attempt: u32) -> Delivery {
+    let body = serde_json::to_vec(payload).unwrap();
+    let signature = sign(&webhook.secret, &body);
+    let result = send(&webhook.url, body, signature, payload.event).await;
+    let delivery = Delivery {
+        webhook_id:    webhook.webhook_id,
+        simulation_id: payload.simulation_id,
+        event:         payload.event,
+        attempt,
+        timestamp:     now(),
+        status_code:   result.as_ref().ok().copied(),
+        success:       matches!(result, Ok(code) if (200..300).contains(&code)),
+        error:         match &result {
+            Ok(code) if !(200..300).contains(code) => format!("Webhook responded with {}", code),
+            Ok(_) => "".into(),
+            Err(e) => e.clone()
+        }
+    };
+    if let Err(e) = db::add_webhook_delivery(&delivery) {
+        info!("Could not record delivery to webhook {}: {}", webhook.webhook_id, e);
+    }
+    delivery
+}
+
+#[doc = "Deliver a payload to a webhook, retrying with exponential backoff"]
+pub async fn deliver_with_retries(webhook: Webhook, payload: WebhookPayload) {
+    let mut backoff = INITIAL_BACKOFF;
+    for attempt in 1..=MAX_ATTEMPTS {
+        if deliver(&webhook, &payload, attempt).await.success {
+            return
+        }
+        if attempt < MAX_ATTEMPTS {
+            rocket::tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+    info!("Giving up on webhook {} after {} attempts", webhook.webhook_id, MAX_ATTEMPTS);
+}
+
+#[doc = "Read all registered webhooks"]
+pub fn read_webhooks() -> redis::RedisResult<Vec<Webhook>> {
+    let mut webhooks = Vec::new();
+    for id in 1..=db::get_number_of_webhooks()? {
+        if let Some(webhook) = db::read_webhook(id)? {
+            webhooks.push(webhook);
+        }
+    }
+    Ok(webhooks)
+}
+
+#[doc = "Start delivering a status change to every webhook that is registered for it"]
+pub fn notify(event: &StatusEvent) {
+    if !event.status.is_finished() {
+        return
+    }
+    let webhooks = match read_webhooks() {
+        Ok(webhooks) => webhooks,
+        Err(e) => {
+            info!("Could not read webhooks for simulation {}: {}", event.simulation_id, e);
+            return
+        }
+    };
+    for webhook in webhooks.into_iter().filter(|webhook| webhook.wants(event)) {
+        rocket::tokio::spawn(logging::in_current_context(deliver_with_retries(webhook, payload(event, false))));
+    }
+}